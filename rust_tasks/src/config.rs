@@ -8,7 +8,13 @@ use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    storage::{api_storage::APIStorage, sqlite_storage::SQLiteStorage, storage::TaskStorage},
+    storage::{
+        api_storage::APIStorage,
+        crdt_storage::CrdtStorage,
+        file_storage::{FileFormat, FileStorage},
+        sqlite_storage::SQLiteStorage,
+        storage::TaskStorage,
+    },
     tasks::summary::SummaryConfig,
 };
 
@@ -23,27 +29,48 @@ pub struct Config {
 enum BackendStrains {
     Api,
     SQLite,
+    File,
+    Crdt,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-struct Backend {
+pub struct Backend {
     strain: BackendStrains,
     uri: String,
+    name: Option<String>,
+    /// Layout of the File strain's directory
+    format: Option<FileFormat>,
 }
 
 impl Backend {
+    /// Name used to select a sync target and to key its sync state, defaults to the uri
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.uri.clone())
+    }
+
     pub fn get_storage_engine(&self) -> Result<Box<dyn TaskStorage>> {
         match self.strain {
             BackendStrains::Api => Ok(Box::new(APIStorage::new(self.uri.clone()))),
-            BackendStrains::SQLite => {
-                let path = self.uri.clone();
-                if !path.starts_with("file://") {
-                    bail!("Expected path to start with file:// but found {path}")
-                }
-                let stripper = "file://".len();
-                let absolute_path = &path[stripper..];
-                Ok(Box::new(SQLiteStorage::new(absolute_path)))
-            }
+            BackendStrains::SQLite => Ok(Box::new(SQLiteStorage::open(self.sqlite_path()?)?)),
+            BackendStrains::File => Ok(Box::new(FileStorage::open(
+                self.file_path()?,
+                self.format.unwrap_or_default(),
+            )?)),
+            BackendStrains::Crdt => Ok(Box::new(CrdtStorage::open(self.file_path()?)?)),
+        }
+    }
+
+    fn sqlite_path(&self) -> Result<&str> {
+        if !matches!(self.strain, BackendStrains::SQLite) {
+            bail!("Expected the SQLite strain but found {:?}", self.strain)
+        }
+        self.file_path()
+    }
+
+    fn file_path(&self) -> Result<&str> {
+        match self.uri.strip_prefix("file://") {
+            None => bail!("Expected path to start with file:// but found {}", self.uri),
+            Some(absolute_path) => Ok(absolute_path),
         }
     }
 }
@@ -53,7 +80,19 @@ impl Config {
         let config_file = path.map_or(config_path()?, |x| Path::new(&x).to_path_buf());
         let mut content = String::new();
         File::open(config_file)?.read_to_string(&mut content)?;
-        let config: Config = toml::from_str(&content)?;
+        Config::from_toml(&content)
+    }
+
+    fn from_toml(content: &str) -> Result<Config> {
+        let config: Config = toml::from_str(content)?;
+        // targets with the same name would share one sync state
+        let syncs = config.sync.as_deref().unwrap_or_default();
+        for (index, target) in syncs.iter().enumerate() {
+            let name = target.name();
+            if syncs[..index].iter().any(|x| x.name() == name) {
+                bail!("Sync target {name} is configured more than once, give each a unique name");
+            }
+        }
         Ok(config)
     }
 
@@ -62,15 +101,27 @@ impl Config {
         backend.get_storage_engine()
     }
 
-    pub fn get_sync_engine(&self) -> Result<Vec<Box<dyn TaskStorage>>> {
-        let res = self
-            .sync
-            .clone()
-            .expect("No syncs defined")
+    /// Opens the SQLite backend without applying migrations, used to inspect and migrate the schema
+    pub fn get_unmigrated_sqlite_storage(&self) -> Result<SQLiteStorage> {
+        SQLiteStorage::open_unmigrated(self.backend.sqlite_path()?)
+    }
+
+    /// Sync targets in the order they are configured, limited to `names` if any are given
+    pub fn get_sync_targets(&self, names: &[String]) -> Result<Vec<&Backend>> {
+        let syncs = match &self.sync {
+            None => bail!("No syncs defined"),
+            Some(syncs) => syncs,
+        };
+        if let Some(unknown) = names
             .iter()
-            .map(|x| x.get_storage_engine().expect("invalid sync type"))
-            .collect();
-        Ok(res)
+            .find(|name| !syncs.iter().any(|x| &x.name() == *name))
+        {
+            bail!("No sync target named {unknown}");
+        }
+        Ok(syncs
+            .iter()
+            .filter(|x| names.is_empty() || names.contains(&x.name()))
+            .collect())
     }
 
     pub fn get_summary_config(&self) -> SummaryConfig {
@@ -88,3 +139,65 @@ fn config_path() -> Result<PathBuf> {
         Some(x) => Ok(x),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_targets_are_selected_by_name() {
+        let config = Config::from_toml(
+            r#"
+        [backend]
+        strain = "SQLite"
+        uri = "file:///tmp/main.db"
+
+        [[sync]]
+        strain = "Api"
+        uri = "http://abc.co"
+        name = "server"
+
+        [[sync]]
+        strain = "SQLite"
+        uri = "file:///tmp/sync.db"
+        "#,
+        )
+        .unwrap();
+        let all: Vec<String> = config
+            .get_sync_targets(&[])
+            .unwrap()
+            .iter()
+            .map(|x| x.name())
+            .collect();
+        assert_eq!(all, vec!["server", "file:///tmp/sync.db"]);
+        let selected = config.get_sync_targets(&["server".to_string()]).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].name(), "server");
+        assert!(config.get_sync_targets(&["phone".to_string()]).is_err());
+    }
+
+    #[test]
+    fn sync_target_names_are_unique() {
+        let error = Config::from_toml(
+            r#"
+        [backend]
+        strain = "SQLite"
+        uri = "file:///tmp/main.db"
+
+        [[sync]]
+        strain = "Api"
+        uri = "http://abc.co"
+        name = "file:///tmp/sync.db"
+
+        [[sync]]
+        strain = "SQLite"
+        uri = "file:///tmp/sync.db"
+        "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Sync target file:///tmp/sync.db is configured more than once, give each a unique name"
+        );
+    }
+}
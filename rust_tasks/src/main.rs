@@ -1,8 +1,13 @@
 use std::error::Error;
 
+use chrono::NaiveDate;
 use clap::Parser;
 use clap::Subcommand;
 use rust_tasks::config::Config;
+use rust_tasks::storage::filter::{DateRange, TaskFilter, TaskOrder, TaskState};
+use rust_tasks::storage::text_search;
+use rust_tasks::tasks::conflict_utils::Take;
+use rust_tasks::tasks::OutputFormat;
 
 #[derive(Parser, Debug)]
 #[command(version, about, verbatim_doc_comment)]
@@ -38,6 +43,12 @@ enum Commands {
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         task_ulids: Vec<String>,
     },
+    /// Raise or, with a negative amount, lower a task's priority
+    Bump {
+        task_ulid: String,
+        #[arg(allow_negative_numbers = true, default_value_t = 1.0)]
+        amount: f64,
+    },
     /// Create a new task
     Add {
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
@@ -45,25 +56,125 @@ enum Commands {
     },
     /// Transfer tasks to today or next recurring period after today
     QuickClean { date: String },
-    /// Direct query into the DB
-    Query { clause: String },
+    /// Find tasks matching a filter
+    Query {
+        /// Only tasks with this tag, can be repeated
+        #[arg(short, long)]
+        tag: Vec<String>,
+        /// Only open tasks
+        #[arg(long, conflicts_with = "closed")]
+        open: bool,
+        /// Only closed tasks
+        #[arg(long)]
+        closed: bool,
+        /// Only tasks whose body contains this text
+        #[arg(short, long)]
+        body: Option<String>,
+        /// Only tasks due on or after this date e.g. 2024-10-23
+        #[arg(long)]
+        due_from: Option<NaiveDate>,
+        /// Only tasks due on or before this date e.g. 2024-10-23
+        #[arg(long)]
+        due_to: Option<NaiveDate>,
+        #[arg(short, long)]
+        limit: Option<usize>,
+    },
+    /// Find tasks whose body, tags or metadata contain every word, best match first
+    Search {
+        #[arg(required = true)]
+        terms: Vec<String>,
+        #[arg(short, long, default_value_t = text_search::DEFAULT_LIMIT)]
+        limit: usize,
+    },
+    /// Revert the last add, do, edit, bump, delete or quick-clean
+    Undo {},
+    /// Show every recorded change to a task, including deleted ones
+    History { task_ulid: String },
+    /// List, restore and purge deleted tasks
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommands,
+    },
     /// Statistics about how my day is going
     Summary {},
-    /// Sync with other storages.
-    /// We have a Bug where deleted tasks are recreated. Work around this by marking the tasks as
-    /// done
+    /// Sync with other storages
     Sync {
-        #[arg(default_value_t = 3)]
-        n_days: usize,
+        /// Only sync with the [[sync]] entry with this name, can be repeated
+        #[arg(short, long)]
+        target: Vec<String>,
+        /// Show when each target last synced instead of syncing
+        #[arg(long, conflicts_with = "dry_run")]
+        status: bool,
+        /// Show what would be exchanged without writing anything
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Inspect and resolve conflicting edits found while syncing
+    Conflicts {
+        #[command(subcommand)]
+        command: ConflictsCommands,
+    },
+    /// Check that the storage is healthy
+    Health {},
+    /// Inspect and migrate the SQLite schema
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
     },
     /// Running tests I'm trying out
     Experiment {},
 }
 
+#[derive(Debug, Subcommand)]
+enum DbCommands {
+    /// Apply pending schema migrations
+    Migrate {},
+    /// Show the schema version and pending migrations
+    Status {},
+}
+
+#[derive(Debug, Subcommand)]
+enum TrashCommands {
+    /// Show deleted tasks, most recently deleted first
+    List {},
+    /// Bring a deleted task back
+    Restore { task_ulid: String },
+    /// Drop deleted tasks for good
+    Purge {
+        /// Only tasks deleted longer ago than this iso8601 duration e.g. P30D
+        #[arg(long)]
+        older_than: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConflictsCommands {
+    /// Show both versions of each conflicting field
+    List {},
+    /// Keep one version of the conflicting fields and drop the conflict
+    Resolve {
+        task_ulid: String,
+        #[arg(long, value_enum)]
+        take: Take,
+    },
+}
+
 fn main() -> anyhow::Result<(), Box<dyn Error>> {
     color_eyre::install()?;
     let args = Args::parse();
     let task_config = Config::load(args.config)?;
+
+    // Opening the storage engine migrates the schema so db commands work on the raw database
+    if let Some(Commands::Db { command }) = &args.command {
+        let sqlite_storage = task_config.get_unmigrated_sqlite_storage()?;
+        match command {
+            DbCommands::Migrate {} => rust_tasks::tasks::migrate_db(&sqlite_storage)?,
+            DbCommands::Status {} => rust_tasks::tasks::show_db_status(&sqlite_storage)?,
+        }
+        return Ok(());
+    }
     let task_storage_box = task_config.get_storage_engine()?;
 
     // You can check for the existence of subcommands, and if found use their
@@ -81,10 +192,29 @@ fn main() -> anyhow::Result<(), Box<dyn Error>> {
             rust_tasks::tasks::edit_utils::edit_task(task_storage_box.as_ref(), task_ulid)?
         }
 
-        Some(Commands::Delete { task_ulids }) => task_ulids.iter().for_each(|task_ulid| {
-            rust_tasks::tasks::edit_utils::delete_task(task_storage_box.as_ref(), task_ulid)
-                .unwrap()
-        }),
+        Some(Commands::Delete { task_ulids }) => {
+            for task_ulid in task_ulids {
+                rust_tasks::tasks::edit_utils::delete_task(task_storage_box.as_ref(), task_ulid)?
+            }
+        }
+        Some(Commands::Bump { task_ulid, amount }) => {
+            rust_tasks::tasks::edit_utils::bump_task(task_storage_box.as_ref(), task_ulid, *amount)?
+        }
+        Some(Commands::Undo {}) => rust_tasks::tasks::undo_utils::undo(task_storage_box.as_ref())?,
+        Some(Commands::History { task_ulid }) => {
+            rust_tasks::tasks::history_utils::show_history(task_storage_box.as_ref(), task_ulid)?
+        }
+        Some(Commands::Trash { command }) => match command {
+            TrashCommands::List {} => {
+                rust_tasks::tasks::trash_utils::list_trash(task_storage_box.as_ref())?
+            }
+            TrashCommands::Restore { task_ulid } => {
+                rust_tasks::tasks::trash_utils::restore_task(task_storage_box.as_ref(), task_ulid)?
+            }
+            TrashCommands::Purge { older_than } => {
+                rust_tasks::tasks::trash_utils::purge_trash(task_storage_box.as_ref(), older_than)?
+            }
+        },
         Some(Commands::Summary {}) => rust_tasks::tasks::get_summary_stats(
             task_storage_box.as_ref(),
             &task_config.get_summary_config(),
@@ -93,21 +223,75 @@ fn main() -> anyhow::Result<(), Box<dyn Error>> {
             let task_params_string = task_params.join(" ");
             rust_tasks::tasks::add_utils::add_task(task_storage_box.as_ref(), &task_params_string)?
         }
-        Some(Commands::Query { clause }) => {
-            rust_tasks::tasks::query(task_storage_box.as_ref(), clause)?
+        Some(Commands::Query {
+            tag,
+            open,
+            closed,
+            body,
+            due_from,
+            due_to,
+            limit,
+        }) => {
+            let state = match (open, closed) {
+                (true, _) => Some(TaskState::Open),
+                (_, true) => Some(TaskState::Closed),
+                _ => None,
+            };
+            let filter = TaskFilter {
+                tags: tag.clone(),
+                state,
+                body_contains: body.clone(),
+                due: (due_from.is_some() || due_to.is_some())
+                    .then(|| DateRange::from_dates(*due_from, *due_to)),
+                order_by: Some(TaskOrder::DueAsc),
+                limit: *limit,
+                ..Default::default()
+            };
+            rust_tasks::tasks::query(task_storage_box.as_ref(), &filter)?
+        }
+        Some(Commands::Search { terms, limit }) => {
+            rust_tasks::tasks::search(task_storage_box.as_ref(), &terms.join(" "), *limit)?
         }
         Some(Commands::QuickClean { date }) => {
             rust_tasks::tasks::quick_clean(task_storage_box.as_ref(), date)?
         }
-        Some(Commands::Sync { n_days }) => {
-            let syncs = &task_config.get_sync_engine()?;
-            if syncs.len() > 1 {
-                println!("I don't currently support multiple syncs");
+        Some(Commands::Sync {
+            target,
+            status,
+            dry_run,
+            format,
+        }) => {
+            if *status {
+                rust_tasks::tasks::show_sync_status(
+                    task_storage_box.as_ref(),
+                    &task_config,
+                    target,
+                )?
+            } else {
+                rust_tasks::tasks::sync(
+                    task_storage_box.as_ref(),
+                    &task_config,
+                    target,
+                    *dry_run,
+                    *format,
+                )?
             }
-            task_storage_box.sync(syncs[0].as_ref(), *n_days)?;
         }
+        Some(Commands::Conflicts { command }) => match command {
+            ConflictsCommands::List {} => {
+                rust_tasks::tasks::conflict_utils::list_conflicts(task_storage_box.as_ref())?
+            }
+            ConflictsCommands::Resolve { task_ulid, take } => {
+                rust_tasks::tasks::conflict_utils::resolve_conflict(
+                    task_storage_box.as_ref(),
+                    task_ulid,
+                    *take,
+                )?
+            }
+        },
+        Some(Commands::Health {}) => rust_tasks::tasks::show_health(task_storage_box.as_ref())?,
         Some(Commands::Experiment {}) => rust_tasks::tasks::experiment()?,
-        None => {}
+        Some(Commands::Db { .. }) | None => {}
     }
 
     Ok(())
@@ -1,11 +1,18 @@
 use anyhow::anyhow;
+use chrono::{DateTime, Utc};
 use ureq::Error;
 
-use std::collections::HashSet;
+use std::time::{Duration, Instant};
 
 use crate::tasks::summary::SummaryConfig;
 
-use super::storage::{DaySummaryResult, TaskStorage};
+use super::filter::TaskFilter;
+use super::storage::{
+    ChangeSet, DaySummaryResult, HealthCheck, HealthReport, Operation, SyncConflict, SyncState,
+    TaskBatch, TaskEvent, TaskStorage, Tombstone, TrashedTask,
+};
+
+const SLOW_RESPONSE: Duration = Duration::from_secs(1);
 
 pub struct APIStorage {
     pub uri: String,
@@ -22,12 +29,6 @@ impl TaskStorage for APIStorage {
     }
 
     fn delete(&self, task: &crate::tasks::Task) -> anyhow::Result<()> {
-        // FIXME: temporary soln that ensures syncs also works with delete and remove APIs
-        let remote_task = self.search_using_ulid(&task.ulid)?;
-        if remote_task.len() == 0 {
-            self.save(task)?;
-        }
-
         let end_point = format!("{}/tasks/{}", self.uri, task.ulid);
         ureq::delete(&end_point)
             .call()
@@ -54,6 +55,17 @@ impl TaskStorage for APIStorage {
         Ok(res)
     }
 
+    fn search_text(&self, query: &str, limit: usize) -> anyhow::Result<Vec<crate::tasks::Task>> {
+        let end_point = format!("{}/tasks/search", self.uri);
+        let res = ureq::get(&end_point)
+            .query("q", query)
+            .query("limit", &limit.to_string())
+            .call()
+            .map_err(api_error_report)?
+            .into_json()?;
+        Ok(res)
+    }
+
     fn next_tasks(&self, count: usize) -> anyhow::Result<Vec<crate::tasks::Task>> {
         let end_point = format!("{}/tasks/next/{}", self.uri, count);
         let response = ureq::get(&end_point).call().map_err(api_error_report)?;
@@ -72,22 +84,198 @@ impl TaskStorage for APIStorage {
         Ok(res)
     }
 
-    fn unsafe_query(&self, clause: &str) -> anyhow::Result<Vec<crate::tasks::Task>> {
-        let end_point = format!("{}/tasks/unsafe_query/", self.uri);
+    fn query(&self, filter: &TaskFilter) -> anyhow::Result<Vec<crate::tasks::Task>> {
+        let end_point = format!("{}/tasks/query/", self.uri);
+        let json_filter = serde_json::to_string(filter)?;
+        let res = ureq::get(&end_point)
+            .query("filter", &json_filter)
+            .call()
+            .map_err(api_error_report)?
+            .into_json()?;
+        Ok(res)
+    }
+
+    fn health(&self) -> anyhow::Result<HealthReport> {
+        let end_point = format!("{}/health", self.uri);
+        let start = Instant::now();
+        // A degraded server responds with an error status but still sends its report
+        let response = match ureq::get(&end_point).call() {
+            Ok(response) => response,
+            Err(Error::Status(_, response)) => response,
+            Err(Error::Transport(t)) => {
+                return Ok(HealthReport::new(vec![HealthCheck::degraded(
+                    "reachable",
+                    t.to_string(),
+                )]))
+            }
+        };
+        let latency = start.elapsed();
+        let server_report: HealthReport = response.into_json()?;
+
+        let latency_detail = format!("{}ms", latency.as_millis());
+        let mut checks = vec![
+            HealthCheck::ok("reachable", &self.uri),
+            match latency > SLOW_RESPONSE {
+                true => HealthCheck::degraded("latency", latency_detail),
+                false => HealthCheck::ok("latency", latency_detail),
+            },
+        ];
+        checks.extend(server_report.checks);
+        Ok(HealthReport::new(checks))
+    }
+
+    fn sync_state(&self, peer: &str) -> anyhow::Result<SyncState> {
+        let end_point = format!("{}/sync_state/", self.uri);
         let res = ureq::get(&end_point)
-            .query("clause", clause)
+            .query("peer", peer)
             .call()
             .map_err(api_error_report)?
             .into_json()?;
         Ok(res)
     }
 
-    fn sync(&self, _task_storage: &dyn TaskStorage, _n_days: usize) -> anyhow::Result<()> {
-        todo!()
+    fn save_sync_state(&self, peer: &str, state: &SyncState) -> anyhow::Result<()> {
+        let end_point = format!("{}/sync_state/", self.uri);
+        ureq::put(&end_point)
+            .query("peer", peer)
+            .send_json(state)
+            .map_err(api_error_report)?;
+        Ok(())
+    }
+
+    fn sync_bases(&self, peer: &str) -> anyhow::Result<Vec<crate::tasks::Task>> {
+        let end_point = format!("{}/sync_bases/", self.uri);
+        let res = ureq::get(&end_point)
+            .query("peer", peer)
+            .call()
+            .map_err(api_error_report)?
+            .into_json()?;
+        Ok(res)
+    }
+
+    fn save_sync_bases(&self, peer: &str, tasks: &[crate::tasks::Task]) -> anyhow::Result<()> {
+        let end_point = format!("{}/sync_bases/", self.uri);
+        ureq::put(&end_point)
+            .query("peer", peer)
+            .send_json(tasks)
+            .map_err(api_error_report)?;
+        Ok(())
+    }
+
+    fn conflicts(&self) -> anyhow::Result<Vec<SyncConflict>> {
+        let end_point = format!("{}/sync_conflicts/", self.uri);
+        let res = ureq::get(&end_point)
+            .call()
+            .map_err(api_error_report)?
+            .into_json()?;
+        Ok(res)
+    }
+
+    fn save_conflict(&self, conflict: &SyncConflict) -> anyhow::Result<()> {
+        let end_point = format!("{}/sync_conflicts/", self.uri);
+        ureq::post(&end_point)
+            .send_json(conflict)
+            .map_err(api_error_report)?;
+        Ok(())
+    }
+
+    fn remove_conflict(&self, ulid: &str) -> anyhow::Result<()> {
+        let end_point = format!("{}/sync_conflicts/{}", self.uri, ulid);
+        ureq::delete(&end_point).call().map_err(api_error_report)?;
+        Ok(())
+    }
+
+    fn import(&self, task: &crate::tasks::Task) -> anyhow::Result<()> {
+        let end_point = format!("{}/tasks/{}", self.uri, task.ulid);
+        ureq::put(&end_point)
+            .send_json(task)
+            .map_err(api_error_report)?;
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: &TaskBatch) -> anyhow::Result<()> {
+        let end_point = format!("{}/tasks/batch", self.uri);
+        ureq::post(&end_point)
+            .send_json(batch)
+            .map_err(api_error_report)?;
+        Ok(())
+    }
+
+    fn tombstones(&self) -> anyhow::Result<Vec<Tombstone>> {
+        let end_point = format!("{}/tombstones/", self.uri);
+        let res = ureq::get(&end_point)
+            .call()
+            .map_err(api_error_report)?
+            .into_json()?;
+        Ok(res)
+    }
+
+    fn apply_tombstone(&self, tombstone: &Tombstone) -> anyhow::Result<()> {
+        let end_point = format!("{}/tombstones/", self.uri);
+        ureq::post(&end_point)
+            .send_json(tombstone)
+            .map_err(api_error_report)?;
+        Ok(())
+    }
+
+    fn changes_since(&self, cursor: u64) -> anyhow::Result<ChangeSet> {
+        let end_point = format!("{}/tasks/changes", self.uri);
+        let res = ureq::get(&end_point)
+            .query("since", &cursor.to_string())
+            .call()
+            .map_err(api_error_report)?
+            .into_json()?;
+        Ok(res)
+    }
+
+    fn purge_tombstones(&self, cursor: u64) -> anyhow::Result<usize> {
+        let end_point = format!("{}/tombstones/", self.uri);
+        let res = ureq::delete(&end_point)
+            .query("cursor", &cursor.to_string())
+            .call()
+            .map_err(api_error_report)?
+            .into_json()?;
+        Ok(res)
+    }
+
+    fn trash(&self) -> anyhow::Result<Vec<TrashedTask>> {
+        let end_point = format!("{}/trash/", self.uri);
+        let res = ureq::get(&end_point)
+            .call()
+            .map_err(api_error_report)?
+            .into_json()?;
+        Ok(res)
+    }
+
+    fn purge_trash(&self, before: &DateTime<Utc>) -> anyhow::Result<usize> {
+        let end_point = format!("{}/trash/", self.uri);
+        let res = ureq::delete(&end_point)
+            .query("before", &before.to_rfc3339())
+            .call()
+            .map_err(api_error_report)?
+            .into_json()?;
+        Ok(res)
+    }
+
+    fn push_operation(&self, operation: &Operation) -> anyhow::Result<()> {
+        let end_point = format!("{}/operations/", self.uri);
+        ureq::post(&end_point)
+            .send_json(operation)
+            .map_err(api_error_report)?;
+        Ok(())
+    }
+
+    fn pop_operation(&self) -> anyhow::Result<Option<Operation>> {
+        let end_point = format!("{}/operations/last", self.uri);
+        let res = ureq::delete(&end_point)
+            .call()
+            .map_err(api_error_report)?
+            .into_json()?;
+        Ok(res)
     }
 
-    fn deleted_ulids(&self, n_days: &usize) -> anyhow::Result<HashSet<String>> {
-        let end_point = format!("{}/tasks/deleted_ulids/{}", self.uri, n_days);
+    fn history(&self, ulid: &str) -> anyhow::Result<Vec<TaskEvent>> {
+        let end_point = format!("{}/tasks/{}/history", self.uri, ulid);
         let res = ureq::get(&end_point)
             .call()
             .map_err(api_error_report)?
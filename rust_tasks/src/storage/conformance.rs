@@ -0,0 +1,763 @@
+//! Behaviour every `TaskStorage` must share. Each case gets an empty storage and panics on the
+//! first difference, so a backend only has to call `run_all` from its tests.
+use std::panic::{self, AssertUnwindSafe};
+
+use chrono::{DateTime, Duration, NaiveDate, SubsecRound, Utc};
+
+use crate::tasks::{recurrence::RecurrenceMode, summary::SummaryConfig, Task};
+
+use super::filter::{DateRange, TaskFilter, TaskOrder, TaskState};
+use super::storage::{
+    HealthStatus, LookupError, Operation, SyncConflict, SyncState, TaskBatch, TaskEvent,
+    TaskEventKind, TaskStorage, Tombstone, JOURNAL_LENGTH,
+};
+
+type Case = fn(&dyn TaskStorage);
+
+const CASES: &[(&str, Case)] = &[
+    ("save_and_search", save_and_search),
+    ("find_by_ulid", find_by_ulid),
+    (
+        "update_replaces_fields_and_tags",
+        update_replaces_fields_and_tags,
+    ),
+    ("recurrence_round_trips", recurrence_round_trips),
+    ("recurrence_can_be_cleared", recurrence_can_be_cleared),
+    ("delete_leaves_a_tombstone", delete_leaves_a_tombstone),
+    ("import_keeps_modified_utc", import_keeps_modified_utc),
+    (
+        "tombstone_beats_older_edits_only",
+        tombstone_beats_older_edits_only,
+    ),
+    ("purge_tombstones", purge_tombstones),
+    ("trash_keeps_deleted_tasks", trash_keeps_deleted_tasks),
+    ("changes_since", changes_since),
+    ("query_filters", query_filters),
+    ("search_text", search_text),
+    ("next_tasks", next_tasks),
+    ("summarize_day", summarize_day),
+    ("sync_metadata", sync_metadata),
+    ("failed_batch_writes_nothing", failed_batch_writes_nothing),
+    ("health", health),
+    ("history_records_writes", history_records_writes),
+    ("operation_journal", operation_journal),
+];
+
+/// Runs every case against a fresh storage from `new_storage`, a failing case panics again with
+/// its name
+pub fn run_all(new_storage: &dyn Fn() -> Box<dyn TaskStorage>) {
+    for (name, case) in CASES {
+        let storage = new_storage();
+        if panic::catch_unwind(AssertUnwindSafe(|| case(storage.as_ref()))).is_err() {
+            panic!("conformance case {name} failed");
+        }
+    }
+}
+
+fn utc(datetime: &str) -> DateTime<Utc> {
+    datetime.parse().unwrap()
+}
+
+fn now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(0)
+}
+
+fn tags(tags: &[&str]) -> Option<Vec<String>> {
+    Some(tags.iter().map(|x| x.to_string()).collect())
+}
+
+fn get(storage: &dyn TaskStorage, ulid: &str) -> Option<Task> {
+    storage.search_using_ulid(ulid).unwrap().pop()
+}
+
+fn ulids(tasks: &[Task]) -> Vec<&str> {
+    tasks.iter().map(|x| x.ulid.as_str()).collect()
+}
+
+fn save_and_search(storage: &dyn TaskStorage) {
+    let before = now();
+    let task = Task {
+        body: "water the plants".to_string(),
+        due_utc: Some(utc("2024-03-01T09:00:00Z")),
+        tags: tags(&["home", "chores"]),
+        ..Default::default()
+    };
+    storage.save(&task).unwrap();
+
+    let found = storage.search_using_ulid(&task.ulid[20..]).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].body, task.body);
+    assert_eq!(found[0].due_utc, task.due_utc);
+    assert_eq!(found[0].tags, tags(&["chores", "home"]));
+    assert!(found[0].modified_utc >= Some(before));
+    assert!(storage.search_using_ulid("nothing").unwrap().is_empty());
+    assert!(storage.save(&task).is_err(), "saving a ulid twice");
+}
+
+fn find_by_ulid(storage: &dyn TaskStorage) {
+    let first = Task {
+        ulid: "01hq0000000000000000000abc".to_string(),
+        ..Default::default()
+    };
+    let second = Task {
+        ulid: "01hq0000000000000000000xyz".to_string(),
+        ..Default::default()
+    };
+    storage.save(&first).unwrap();
+    storage.save(&second).unwrap();
+
+    assert_eq!(storage.find_by_ulid("ABC").unwrap().ulid, first.ulid);
+    assert_eq!(
+        storage
+            .find_by_ulid("01HQ000000000000000000")
+            .unwrap_err()
+            .to_string(),
+        format!(
+            "Expected 1 task but found 2:\n{}: \n{}: ",
+            first.ulid, second.ulid
+        )
+    );
+    assert_eq!(
+        storage.find_by_ulid(&second.ulid[..24]).unwrap().ulid,
+        second.ulid
+    );
+    for fragment in ["", "%", "_bc", "' OR 1=1 --", "nothing"] {
+        assert!(
+            matches!(
+                storage.find_by_ulid(fragment),
+                Err(LookupError::NotFound(_))
+            ),
+            "{fragment:?} matched"
+        );
+    }
+    match storage.find_by_ulid("01hq") {
+        Err(LookupError::Ambiguous(tasks)) => assert_eq!(tasks.len(), 2),
+        x => panic!("expected an ambiguous ulid, got {x:?}"),
+    }
+}
+
+fn update_replaces_fields_and_tags(storage: &dyn TaskStorage) {
+    let mut task = Task {
+        body: "draft".to_string(),
+        tags: tags(&["a", "b"]),
+        ..Default::default()
+    };
+    storage.save(&task).unwrap();
+    task.body = "final".to_string();
+    task.tags = tags(&["c"]);
+    task.closed_utc = Some(utc("2024-03-01T09:00:00Z"));
+    storage.update(&task).unwrap();
+
+    let found = get(storage, &task.ulid).unwrap();
+    assert_eq!(found.body, "final");
+    assert_eq!(found.tags, tags(&["c"]));
+    assert_eq!(found.closed_utc, task.closed_utc);
+}
+
+fn recurrence_round_trips(storage: &dyn TaskStorage) {
+    let duration = Task {
+        due_utc: Some(utc("2024-03-01T09:00:00Z")),
+        recurrence_duration: "P1W".parse().ok(),
+        ..Default::default()
+    };
+    let mut rule = Task {
+        due_utc: Some(utc("2024-03-01T09:00:00Z")),
+        recurrence_rule: "FREQ=MONTHLY;BYDAY=1MO".parse().ok(),
+        ..Default::default()
+    };
+    storage.save(&duration).unwrap();
+    storage.save(&rule).unwrap();
+    assert_eq!(
+        get(storage, &duration.ulid).unwrap().recurrence_duration,
+        duration.recurrence_duration
+    );
+    assert_eq!(get(storage, &duration.ulid).unwrap().recurrence_rule, None);
+    assert_eq!(
+        get(storage, &rule.ulid).unwrap().recurrence_rule,
+        rule.recurrence_rule
+    );
+
+    rule.recurrence_rule = "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,FR".parse().ok();
+    rule.recurrence_mode = Some(RecurrenceMode::CatchUp);
+    rule.ready_lead = "P3DT12H".parse().ok();
+    rule.recurrence_until = Some(utc("2024-12-31T23:59:59Z"));
+    rule.recurrence_count = Some(10);
+    rule.recurrence_occurrence = Some(3);
+    storage.update(&rule).unwrap();
+    let found = get(storage, &rule.ulid).unwrap();
+    assert_eq!(found.ready_lead, rule.ready_lead);
+    assert_eq!(found.recurrence_until, rule.recurrence_until);
+    assert_eq!(
+        (found.recurrence_count, found.recurrence_occurrence),
+        (Some(10), Some(3))
+    );
+    assert_eq!(found.recurrence_rule, rule.recurrence_rule);
+    assert_eq!(found.recurrence_mode, Some(RecurrenceMode::CatchUp));
+    assert_eq!(get(storage, &duration.ulid).unwrap().recurrence_mode, None);
+}
+
+fn recurrence_can_be_cleared(storage: &dyn TaskStorage) {
+    let task = Task {
+        due_utc: Some(utc("2024-03-01T09:00:00Z")),
+        recurrence_duration: "P1W".parse().ok(),
+        recurrence_rule: "FREQ=WEEKLY;BYDAY=MO".parse().ok(),
+        recurrence_mode: Some(RecurrenceMode::FromCompletion),
+        ready_lead: "P1D".parse().ok(),
+        recurrence_until: Some(utc("2024-12-31T23:59:59Z")),
+        recurrence_count: Some(10),
+        recurrence_occurrence: Some(3),
+        ..Default::default()
+    };
+    storage.save(&task).unwrap();
+    let cleared = Task {
+        recurrence_duration: None,
+        recurrence_rule: None,
+        recurrence_mode: None,
+        ready_lead: None,
+        recurrence_until: None,
+        recurrence_count: None,
+        recurrence_occurrence: None,
+        ..task.clone()
+    };
+    storage.update(&cleared).unwrap();
+    let found = get(storage, &task.ulid).unwrap();
+    assert_eq!(found.recurrence_duration, None);
+    assert_eq!(found.recurrence_rule, None);
+    assert_eq!(found.recurrence_mode, None);
+    assert_eq!(found.ready_lead, None);
+    assert_eq!(found.recurrence_until, None);
+    assert_eq!(found.recurrence_count, None);
+    assert_eq!(found.recurrence_occurrence, None);
+    assert_eq!(found.due_utc, task.due_utc);
+}
+
+fn delete_leaves_a_tombstone(storage: &dyn TaskStorage) {
+    let before = now();
+    let task = Task::default();
+    storage.save(&task).unwrap();
+    storage.delete(&task).unwrap();
+
+    assert!(get(storage, &task.ulid).is_none());
+    let tombstones = storage.tombstones().unwrap();
+    assert_eq!(tombstones.len(), 1);
+    assert_eq!(tombstones[0].ulid, task.ulid);
+    assert!(tombstones[0].deleted_utc >= before);
+    assert!(storage.delete(&task).is_err(), "deleting a missing task");
+}
+
+fn import_keeps_modified_utc(storage: &dyn TaskStorage) {
+    let task = Task {
+        body: "from another storage".to_string(),
+        modified_utc: Some(utc("2020-01-01T00:00:00Z")),
+        tags: tags(&["x"]),
+        ..Default::default()
+    };
+    storage.import(&task).unwrap();
+    assert_eq!(get(storage, &task.ulid).unwrap(), task);
+
+    let cursor = storage.changes_since(0).unwrap().cursor;
+    storage.import(&task).unwrap();
+    let changes = storage.changes_since(cursor).unwrap();
+    assert!(changes.tasks.is_empty(), "importing an identical task");
+
+    storage.delete(&task).unwrap();
+    storage.import(&task).unwrap();
+    assert!(get(storage, &task.ulid).is_some());
+    assert!(storage.tombstones().unwrap().is_empty());
+}
+
+fn tombstone_beats_older_edits_only(storage: &dyn TaskStorage) {
+    let task = Task {
+        modified_utc: Some(utc("2024-01-02T00:00:00Z")),
+        ..Default::default()
+    };
+    storage.import(&task).unwrap();
+    let tombstone = |deleted_utc: &str| Tombstone {
+        ulid: task.ulid.clone(),
+        deleted_utc: utc(deleted_utc),
+    };
+
+    storage
+        .apply_tombstone(&tombstone("2024-01-01T00:00:00Z"))
+        .unwrap();
+    assert!(get(storage, &task.ulid).is_some());
+
+    storage
+        .apply_tombstone(&tombstone("2024-01-03T00:00:00Z"))
+        .unwrap();
+    assert!(get(storage, &task.ulid).is_none());
+    storage
+        .apply_tombstone(&tombstone("2024-01-02T12:00:00Z"))
+        .unwrap();
+    assert_eq!(
+        storage.tombstones().unwrap(),
+        vec![tombstone("2024-01-03T00:00:00Z")]
+    );
+}
+
+fn purge_tombstones(storage: &dyn TaskStorage) {
+    let tombstone = |ulid: &str, deleted_utc| Tombstone {
+        ulid: ulid.to_string(),
+        deleted_utc: utc(deleted_utc),
+    };
+    // what counts is when a tombstone was recorded here, not when the task was deleted
+    storage
+        .apply_tombstone(&tombstone("old", "2024-02-01T00:00:00Z"))
+        .unwrap();
+    let cursor = storage.changes_since(0).unwrap().cursor;
+    storage
+        .apply_tombstone(&tombstone("new", "2024-01-01T00:00:00Z"))
+        .unwrap();
+    assert_eq!(storage.purge_tombstones(0).unwrap(), 0);
+    let purged = storage.purge_tombstones(cursor).unwrap();
+    assert_eq!(purged, 1);
+    let tombstones = storage.tombstones().unwrap();
+    assert_eq!(tombstones.len(), 1);
+    assert_eq!(tombstones[0].ulid, "new");
+}
+
+fn trash_keeps_deleted_tasks(storage: &dyn TaskStorage) {
+    let later = now() + Duration::days(1);
+    let task = Task {
+        body: "water the plants".to_string(),
+        tags: tags(&["home"]),
+        ..Default::default()
+    };
+    storage.save(&task).unwrap();
+    storage.delete(&task).unwrap();
+    let trash = storage.trash().unwrap();
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].task.body, task.body);
+    assert_eq!(trash[0].task.tags, task.tags);
+    let cursor = storage.changes_since(0).unwrap().cursor;
+    assert_eq!(
+        storage.purge_tombstones(cursor).unwrap(),
+        0,
+        "still in the trash"
+    );
+
+    assert_eq!(storage.purge_trash(&later).unwrap(), 1);
+    assert!(storage.trash().unwrap().is_empty());
+    assert_eq!(storage.tombstones().unwrap().len(), 1, "still deleted");
+    let changes = storage.changes_since(cursor).unwrap();
+    assert!(changes.tombstones.is_empty(), "nothing new to sync");
+    assert_eq!(storage.purge_tombstones(cursor).unwrap(), 1);
+
+    // restoring is importing it again
+    let restored = Task::default();
+    storage.save(&restored).unwrap();
+    storage.delete(&restored).unwrap();
+    let trashed = storage.trash().unwrap().pop().unwrap().task;
+    storage
+        .import(&Task {
+            modified_utc: Some(later),
+            ..trashed
+        })
+        .unwrap();
+    assert!(storage.trash().unwrap().is_empty());
+    assert!(storage.tombstones().unwrap().is_empty());
+    assert!(get(storage, &restored.ulid).is_some());
+
+    // deletions from sync land in the trash too
+    storage
+        .apply_tombstone(&Tombstone {
+            ulid: restored.ulid.clone(),
+            deleted_utc: later + Duration::seconds(1),
+        })
+        .unwrap();
+    assert_eq!(storage.trash().unwrap()[0].task.ulid, restored.ulid);
+}
+
+fn changes_since(storage: &dyn TaskStorage) {
+    let mut first = Task::default();
+    storage.save(&first).unwrap();
+    let all = storage.changes_since(0).unwrap();
+    assert_eq!(ulids(&all.tasks), vec![first.ulid.as_str()]);
+    assert!(storage.changes_since(all.cursor).unwrap().tasks.is_empty());
+
+    first.body = "edited".to_string();
+    storage.update(&first).unwrap();
+    let second = Task::default();
+    storage.save(&second).unwrap();
+    let changes = storage.changes_since(all.cursor).unwrap();
+    assert!(changes.cursor > all.cursor);
+    assert_eq!(changes.tasks.len(), 2);
+
+    storage.delete(&second).unwrap();
+    let deleted = storage.changes_since(changes.cursor).unwrap();
+    assert!(deleted.tasks.is_empty());
+    assert_eq!(deleted.tombstones.len(), 1);
+    assert_eq!(deleted.tombstones[0].ulid, second.ulid);
+}
+
+fn query_filters(storage: &dyn TaskStorage) {
+    let milk = Task {
+        body: "Buy milk".to_string(),
+        due_utc: Some(utc("2024-03-01T09:00:00Z")),
+        tags: tags(&["home"]),
+        ..Default::default()
+    };
+    let report = Task {
+        body: "Write report".to_string(),
+        due_utc: Some(utc("2024-03-02T09:00:00Z")),
+        closed_utc: Some(utc("2024-03-02T10:00:00Z")),
+        tags: tags(&["work"]),
+        ..Default::default()
+    };
+    let stamps = Task {
+        body: "buy 100% stamps".to_string(),
+        tags: tags(&["home", "errands"]),
+        ..Default::default()
+    };
+    for task in [&milk, &report, &stamps] {
+        storage.save(task).unwrap();
+    }
+    let query = |filter: TaskFilter| {
+        let mut found: Vec<String> = storage
+            .query(&filter)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.ulid)
+            .collect();
+        if filter.order_by.is_none() {
+            found.sort();
+        }
+        found
+    };
+    let sorted = |tasks: &[&Task]| {
+        let mut ulids: Vec<String> = tasks.iter().map(|x| x.ulid.clone()).collect();
+        ulids.sort();
+        ulids
+    };
+
+    assert_eq!(query(TaskFilter::default()).len(), 3);
+    let filter = TaskFilter {
+        tags: vec!["home".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(query(filter), sorted(&[&milk, &stamps]));
+    let filter = TaskFilter {
+        tags: vec!["home".to_string(), "errands".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(query(filter), sorted(&[&stamps]));
+    let filter = TaskFilter {
+        state: Some(TaskState::Closed),
+        ..Default::default()
+    };
+    assert_eq!(query(filter), sorted(&[&report]));
+    let march_first = NaiveDate::from_ymd_opt(2024, 3, 1);
+    let filter = TaskFilter {
+        due: Some(DateRange::from_dates(march_first, march_first)),
+        ..Default::default()
+    };
+    assert_eq!(query(filter), sorted(&[&milk]));
+    let filter = TaskFilter {
+        body_contains: Some("BUY".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(query(filter), sorted(&[&milk, &stamps]));
+    let filter = TaskFilter {
+        body_contains: Some("0%".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(query(filter), sorted(&[&stamps]));
+    let filter = TaskFilter {
+        modified_since: Some(now() + Duration::days(1)),
+        ..Default::default()
+    };
+    assert!(query(filter).is_empty());
+    let filter = TaskFilter {
+        order_by: Some(TaskOrder::DueAsc),
+        ..Default::default()
+    };
+    assert_eq!(
+        query(filter),
+        vec![stamps.ulid.clone(), milk.ulid.clone(), report.ulid.clone()]
+    );
+    let filter = TaskFilter {
+        order_by: Some(TaskOrder::DueDesc),
+        limit: Some(1),
+        ..Default::default()
+    };
+    assert_eq!(query(filter), vec![report.ulid.clone()]);
+}
+
+fn search_text(storage: &dyn TaskStorage) {
+    let body = Task {
+        body: "Water the plants".to_string(),
+        ..Default::default()
+    };
+    let tagged = Task {
+        body: "water the lawn".to_string(),
+        tags: tags(&["plants"]),
+        ..Default::default()
+    };
+    let metadata = Task {
+        body: "call the garden centre".to_string(),
+        metadata: Some(r#"{"about": "plants"}"#.to_string()),
+        ..Default::default()
+    };
+    for task in [&body, &tagged, &metadata] {
+        storage.save(task).unwrap();
+    }
+    let found = storage.search_text("PLANTS", 10).unwrap();
+    assert_eq!(
+        ulids(&found),
+        vec![
+            body.ulid.as_str(),
+            tagged.ulid.as_str(),
+            metadata.ulid.as_str()
+        ]
+    );
+    let found = storage.search_text("water plants", 10).unwrap();
+    assert_eq!(
+        ulids(&found),
+        vec![body.ulid.as_str(), tagged.ulid.as_str()]
+    );
+    assert_eq!(storage.search_text("plants", 1).unwrap().len(), 1);
+    assert!(storage.search_text("plant", 10).unwrap().is_empty());
+    assert!(storage.search_text("\"", 10).unwrap().is_empty());
+
+    storage
+        .update(&Task {
+            body: "water the lawn".to_string(),
+            tags: tags(&["garden"]),
+            ..body.clone()
+        })
+        .unwrap();
+    storage.delete(&metadata).unwrap();
+    let found = storage.search_text("plants", 10).unwrap();
+    assert_eq!(ulids(&found), vec![tagged.ulid.as_str()]);
+    let found = storage.search_text("garden", 10).unwrap();
+    assert_eq!(ulids(&found), vec![body.ulid.as_str()]);
+}
+
+fn next_tasks(storage: &dyn TaskStorage) {
+    let now = now();
+    let due = |days: i64| Some(now + Duration::days(days));
+    let overdue = Task {
+        due_utc: due(-2),
+        ..Default::default()
+    };
+    let due_yesterday = Task {
+        due_utc: due(-1),
+        ..Default::default()
+    };
+    let future = Task {
+        due_utc: due(2),
+        ..Default::default()
+    };
+    let closed = Task {
+        due_utc: due(-1),
+        closed_utc: Some(now),
+        ..Default::default()
+    };
+    let not_ready = Task {
+        due_utc: due(-1),
+        ready_utc: due(1),
+        ..Default::default()
+    };
+    let bumped = Task {
+        due_utc: due(-1),
+        priority_adjustment: Some(1.5),
+        ..Default::default()
+    };
+    for task in [
+        &due_yesterday,
+        &future,
+        &closed,
+        &not_ready,
+        &overdue,
+        &bumped,
+    ] {
+        storage.save(task).unwrap();
+    }
+    let next = storage.next_tasks(10).unwrap();
+    assert_eq!(
+        ulids(&next),
+        vec![
+            bumped.ulid.as_str(),
+            overdue.ulid.as_str(),
+            due_yesterday.ulid.as_str()
+        ]
+    );
+    assert_eq!(next[0].priority_adjustment, Some(1.5));
+    assert_eq!(storage.next_tasks(1).unwrap().len(), 1);
+}
+
+fn summarize_day(storage: &dyn TaskStorage) {
+    let now = now();
+    let tasks = [
+        Task {
+            due_utc: Some(now),
+            tags: tags(&["work"]),
+            ..Default::default()
+        },
+        Task {
+            due_utc: Some(now - Duration::days(1)),
+            ..Default::default()
+        },
+        Task {
+            due_utc: Some(now),
+            closed_utc: Some(now),
+            tags: tags(&["work"]),
+            ..Default::default()
+        },
+        Task {
+            due_utc: Some(now + Duration::days(1)),
+            ..Default::default()
+        },
+    ];
+    for task in &tasks {
+        storage.save(task).unwrap();
+    }
+    let summary = storage.summarize_day(&SummaryConfig::default()).unwrap();
+    assert_eq!(summary.total_tasks, 3);
+    assert_eq!(summary.done_tasks, 1);
+    let open_tags_count = summary.open_tags_count.unwrap();
+    assert_eq!(open_tags_count["work"], 1);
+    assert_eq!(open_tags_count["meeting"], 0);
+}
+
+fn sync_metadata(storage: &dyn TaskStorage) {
+    assert_eq!(storage.sync_state("laptop").unwrap(), SyncState::default());
+    let state = SyncState {
+        last_attempt_utc: Some(utc("2024-01-04T10:00:00Z")),
+        last_success_utc: None,
+        last_error: Some("timed out".to_string()),
+        local_cursor: 3,
+        remote_cursor: 7,
+    };
+    storage.save_sync_state("laptop", &state).unwrap();
+    assert_eq!(storage.sync_state("laptop").unwrap(), state);
+    assert_eq!(storage.sync_state("phone").unwrap(), SyncState::default());
+
+    let base = Task {
+        modified_utc: Some(utc("2024-01-04T10:00:00Z")),
+        ..Default::default()
+    };
+    storage
+        .save_sync_bases("laptop", std::slice::from_ref(&base))
+        .unwrap();
+    assert_eq!(storage.sync_bases("laptop").unwrap(), vec![base.clone()]);
+    assert!(storage.sync_bases("phone").unwrap().is_empty());
+
+    let conflict = SyncConflict {
+        ulid: base.ulid.clone(),
+        peer: "laptop".to_string(),
+        fields: vec!["body".to_string()],
+        local: base.clone(),
+        remote: base.clone(),
+        detected_utc: utc("2024-01-04T10:00:00Z"),
+    };
+    storage.save_conflict(&conflict).unwrap();
+    let replaced = SyncConflict {
+        fields: vec!["due_utc".to_string()],
+        ..conflict
+    };
+    storage.save_conflict(&replaced).unwrap();
+    assert_eq!(storage.conflicts().unwrap(), vec![replaced]);
+    storage.remove_conflict(&base.ulid).unwrap();
+    assert!(storage.conflicts().unwrap().is_empty());
+}
+
+fn failed_batch_writes_nothing(storage: &dyn TaskStorage) {
+    let task = Task::default();
+    let batch = TaskBatch {
+        save: vec![task.clone()],
+        delete: vec![Task::default()],
+        ..Default::default()
+    };
+    assert!(storage.apply_batch(&batch).is_err());
+    assert!(get(storage, &task.ulid).is_none());
+
+    let imported = Task {
+        modified_utc: Some(utc("2024-01-01T00:00:00Z")),
+        ..Default::default()
+    };
+    let batch = TaskBatch {
+        save: vec![task.clone()],
+        import: vec![imported.clone()],
+        ..Default::default()
+    };
+    storage.apply_batch(&batch).unwrap();
+    assert!(get(storage, &task.ulid).is_some());
+    assert_eq!(get(storage, &imported.ulid).unwrap(), imported);
+}
+
+fn health(storage: &dyn TaskStorage) {
+    storage.save(&Task::default()).unwrap();
+    assert_eq!(storage.health().unwrap().status, HealthStatus::Ok);
+}
+
+fn history_records_writes(storage: &dyn TaskStorage) {
+    let mut task = Task {
+        body: "draft".to_string(),
+        ..Default::default()
+    };
+    storage.save(&task).unwrap();
+    task.body = "final".to_string();
+    storage.update(&task).unwrap();
+    task.closed_utc = Some(now());
+    storage.update(&task).unwrap();
+    storage.import(&get(storage, &task.ulid).unwrap()).unwrap();
+    storage.delete(&task).unwrap();
+    storage.save(&Task::default()).unwrap();
+
+    let history = storage.history(&task.ulid).unwrap();
+    let kinds: Vec<TaskEventKind> = history.iter().map(|x| x.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            TaskEventKind::Save,
+            TaskEventKind::Update,
+            TaskEventKind::Do,
+            TaskEventKind::Delete
+        ]
+    );
+    assert!(history[0].before.is_none());
+    assert_eq!(history[1].before.as_ref().unwrap().body, "draft");
+    assert_eq!(history[1].after.as_ref().unwrap().body, "final");
+    assert_eq!(
+        history[3].before.as_ref().unwrap().closed_utc,
+        task.closed_utc
+    );
+    assert!(history[3].after.is_none());
+    assert!(history.iter().all(|x| x.ulid == task.ulid));
+    assert!(storage.history("nothing").unwrap().is_empty());
+}
+
+fn operation_journal(storage: &dyn TaskStorage) {
+    assert_eq!(storage.pop_operation().unwrap(), None);
+    let task = Task::default();
+    let add = Operation::new(
+        "add",
+        vec![TaskEvent::new(
+            TaskEventKind::Save,
+            None,
+            Some(task.clone()),
+        )],
+    );
+    let delete = Operation::new(
+        "delete",
+        vec![TaskEvent::new(TaskEventKind::Delete, Some(task), None)],
+    );
+    storage.push_operation(&add).unwrap();
+    storage.push_operation(&delete).unwrap();
+    assert_eq!(storage.pop_operation().unwrap(), Some(delete));
+    assert_eq!(storage.pop_operation().unwrap(), Some(add));
+    assert_eq!(storage.pop_operation().unwrap(), None);
+
+    for i in 0..=JOURNAL_LENGTH {
+        storage
+            .push_operation(&Operation::new(&i.to_string(), vec![]))
+            .unwrap();
+    }
+    let mut count = 0;
+    while let Some(operation) = storage.pop_operation().unwrap() {
+        count += 1;
+        assert_ne!(operation.command, "0", "the oldest is dropped");
+    }
+    assert_eq!(count, JOURNAL_LENGTH);
+}
@@ -0,0 +1,1027 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::tasks::{summary::SummaryConfig, Task};
+
+use super::file_storage::write_atomically;
+use super::filter::TaskFilter;
+use super::memory_storage::{stored, MemoryState, MemoryStorage};
+use super::storage::{
+    ChangeSet, DaySummaryResult, HealthCheck, HealthReport, Operation, SyncConflict, SyncState,
+    TaskBatch, TaskEvent, TaskEventKind, TaskStorage, Tombstone, TrashedTask, JOURNAL_LENGTH,
+};
+
+/// Orders writes across replicas with a Lamport clock, ties broken by replica id, so every
+/// replica picks the same winner without looking at wall clocks
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct Stamp {
+    clock: u64,
+    replica: String,
+}
+
+/// Last-writer-wins register
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Register<T> {
+    value: T,
+    stamp: Stamp,
+}
+
+impl<T: Clone> Register<T> {
+    fn merge(&mut self, other: &Self) {
+        if other.stamp > self.stamp {
+            *self = other.clone();
+        }
+    }
+}
+
+/// Add-wins set of tags. Removing a tag only cancels the adds that were seen, so a concurrent
+/// add survives the merge.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct TagSet(BTreeMap<String, TagDots>);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct TagDots {
+    added: BTreeSet<Stamp>,
+    removed: BTreeSet<Stamp>,
+}
+
+impl TagDots {
+    fn is_live(&self) -> bool {
+        self.added.difference(&self.removed).next().is_some()
+    }
+}
+
+impl TagSet {
+    fn values(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|(_, dots)| dots.is_live())
+            .map(|(tag, _)| tag.clone())
+            .collect()
+    }
+
+    fn set(&mut self, tags: &BTreeSet<String>, stamp: &Stamp) {
+        for (tag, dots) in self.0.iter_mut() {
+            if dots.is_live() && !tags.contains(tag) {
+                dots.removed.extend(dots.added.iter().cloned());
+            }
+        }
+        for tag in tags {
+            let dots = self.0.entry(tag.clone()).or_default();
+            if !dots.is_live() {
+                dots.added.insert(stamp.clone());
+            }
+        }
+    }
+
+    /// Adds the tags again at `stamp`, so they outlive the older adds being dropped
+    fn renew(&mut self, tags: &BTreeSet<String>, stamp: &Stamp) {
+        for tag in tags {
+            self.0
+                .entry(tag.clone())
+                .or_default()
+                .added
+                .insert(stamp.clone());
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (tag, dots) in &other.0 {
+            let merged = self.0.entry(tag.clone()).or_default();
+            merged.added.extend(dots.added.iter().cloned());
+            merged.removed.extend(dots.removed.iter().cloned());
+        }
+    }
+
+    /// Drops the adds and removes made up to `stamp`
+    fn drop_until(&mut self, stamp: &Stamp) {
+        for dots in self.0.values_mut() {
+            dots.added.retain(|x| x > stamp);
+            dots.removed.retain(|x| x > stamp);
+        }
+        self.0.retain(|_, dots| !dots.added.is_empty());
+    }
+}
+
+/// A task as a CRDT: a register per field, the tags and a register for when it was deleted
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct TaskDocument {
+    fields: BTreeMap<String, Register<Value>>,
+    tags: TagSet,
+    deleted: Option<Register<Option<DateTime<Utc>>>>,
+}
+
+impl TaskDocument {
+    fn deleted_utc(&self) -> Option<DateTime<Utc>> {
+        self.deleted.as_ref().and_then(|x| x.value)
+    }
+
+    fn task(&self, ulid: &str) -> Result<Option<Task>> {
+        if self.deleted_utc().is_some() {
+            return Ok(None);
+        }
+        self.last_task(ulid)
+    }
+
+    /// The task as it was last written, even if it was deleted since
+    fn last_task(&self, ulid: &str) -> Result<Option<Task>> {
+        if self.fields.is_empty() {
+            return Ok(None);
+        }
+        let mut task: serde_json::Map<String, Value> = self
+            .fields
+            .iter()
+            .map(|(field, register)| (field.clone(), register.value.clone()))
+            .collect();
+        task.insert("ulid".to_string(), Value::from(ulid));
+        let tags = self.tags.values();
+        task.insert(
+            "tags".to_string(),
+            if tags.is_empty() {
+                Value::Null
+            } else {
+                Value::from(tags)
+            },
+        );
+        Ok(Some(serde_json::from_value(Value::Object(task))?))
+    }
+
+    /// Whether the document is still deleted by a delete made up to `stamp`
+    fn deleted_until(&self, stamp: &Stamp) -> bool {
+        self.deleted
+            .as_ref()
+            .is_some_and(|x| x.value.is_some() && x.stamp <= *stamp)
+    }
+
+    /// Drops the fields and tags written up to `stamp`, keeping the `deleted` register
+    fn drop_until(&mut self, stamp: &Stamp) {
+        self.fields.retain(|_, x| x.stamp > *stamp);
+        self.tags.drop_until(stamp);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (field, register) in &other.fields {
+            match self.fields.get_mut(field) {
+                Some(x) => x.merge(register),
+                None => {
+                    self.fields.insert(field.clone(), register.clone());
+                }
+            }
+        }
+        self.tags.merge(&other.tags);
+        match (&mut self.deleted, &other.deleted) {
+            (Some(x), Some(other)) => x.merge(other),
+            (None, Some(other)) => self.deleted = Some(other.clone()),
+            _ => {}
+        }
+    }
+}
+
+/// The part of a replica file that other replicas merge
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Replica {
+    replica: String,
+    clock: u64,
+    documents: BTreeMap<String, TaskDocument>,
+}
+
+/// Bookkeeping that stays with one replica
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct LocalState {
+    change_sequence: u64,
+    /// Change sequence of the last local write or merge of each task
+    changes: BTreeMap<String, u64>,
+    sync_states: BTreeMap<String, SyncState>,
+    sync_bases: BTreeMap<String, BTreeMap<String, Task>>,
+    conflicts: BTreeMap<String, SyncConflict>,
+    #[serde(default)]
+    events: Vec<TaskEvent>,
+    #[serde(default)]
+    operations: Vec<Operation>,
+    /// Deleted tasks purged from the trash. Their documents keep the fields for merging.
+    #[serde(default)]
+    purged_trash: BTreeSet<String>,
+    /// Stamp of the delete each purged tombstone was at. The document keeps its `deleted`
+    /// register so a replica that still has the task can't bring it back, and drops whatever
+    /// was written up to the stamp, also when merged in again.
+    #[serde(default)]
+    purged_tombstones: BTreeMap<String, Stamp>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CrdtState {
+    #[serde(flatten)]
+    replica: Replica,
+    #[serde(default)]
+    local: LocalState,
+}
+
+impl CrdtState {
+    fn new() -> Self {
+        Self {
+            replica: Replica {
+                replica: Ulid::new().to_string().to_lowercase(),
+                clock: 0,
+                documents: BTreeMap::new(),
+            },
+            local: LocalState::default(),
+        }
+    }
+
+    fn tick(&mut self) -> Stamp {
+        self.replica.clock += 1;
+        Stamp {
+            clock: self.replica.clock,
+            replica: self.replica.replica.clone(),
+        }
+    }
+
+    fn touch(&mut self, ulid: &str) {
+        self.local.change_sequence += 1;
+        self.local
+            .changes
+            .insert(ulid.to_string(), self.local.change_sequence);
+    }
+
+    fn task(&self, ulid: &str) -> Result<Option<Task>> {
+        match self.replica.documents.get(ulid) {
+            None => Ok(None),
+            Some(x) => x.task(ulid),
+        }
+    }
+
+    fn tasks(&self) -> Result<Vec<Task>> {
+        let mut tasks = vec![];
+        for (ulid, document) in &self.replica.documents {
+            tasks.extend(document.task(ulid)?);
+        }
+        Ok(tasks)
+    }
+
+    fn tombstone_purged(&self, ulid: &str, document: &TaskDocument) -> bool {
+        self.local
+            .purged_tombstones
+            .get(ulid)
+            .is_some_and(|x| document.deleted_until(x))
+    }
+
+    fn deleted_utc(&self, ulid: &str) -> Option<DateTime<Utc>> {
+        self.replica
+            .documents
+            .get(ulid)
+            .and_then(|x| x.deleted_utc())
+    }
+
+    /// Writes the fields that differ from the document, bringing it back if it was deleted.
+    /// Bringing it back writes every field, the older ones may be dropped by a purged tombstone.
+    fn write_task(&mut self, task: &Task) -> Result<()> {
+        let stamp = self.tick();
+        let Value::Object(mut fields) = serde_json::to_value(task)? else {
+            bail!("Task {} didn't serialize to an object", task.ulid);
+        };
+        let tags: BTreeSet<String> = task.tags.iter().flatten().cloned().collect();
+        let document = self.replica.documents.entry(task.ulid.clone()).or_default();
+        // fields that are None aren't serialized but still have to clear the register
+        for field in document.fields.keys() {
+            fields.entry(field.clone()).or_insert(Value::Null);
+        }
+        let restoring = document.deleted_utc().is_some();
+        for (field, value) in fields {
+            if field == "ulid" || field == "tags" {
+                continue;
+            }
+            if restoring || document.fields.get(&field).is_none_or(|x| x.value != value) {
+                let stamp = stamp.clone();
+                document.fields.insert(field, Register { value, stamp });
+            }
+        }
+        document.tags.set(&tags, &stamp);
+        if restoring {
+            document.tags.renew(&tags, &stamp);
+            document.deleted = Some(Register { value: None, stamp });
+        }
+        self.local.purged_trash.remove(&task.ulid);
+        self.touch(&task.ulid);
+        Ok(())
+    }
+
+    fn write_tombstone(&mut self, ulid: &str, deleted_utc: DateTime<Utc>) {
+        let stamp = self.tick();
+        let document = self.replica.documents.entry(ulid.to_string()).or_default();
+        document.deleted = Some(Register {
+            value: Some(deleted_utc),
+            stamp,
+        });
+        self.local.purged_trash.remove(ulid);
+        self.touch(ulid);
+    }
+
+    fn record(&mut self, kind: TaskEventKind, before: Option<Task>, after: Option<Task>) {
+        self.local.events.push(TaskEvent::new(kind, before, after));
+    }
+
+    /// Merges another replica's documents, returns whether anything changed
+    fn merge(&mut self, other: &Replica) -> Result<bool> {
+        self.replica.clock = self.replica.clock.max(other.clock);
+        let mut changed = false;
+        for (ulid, document) in &other.documents {
+            let current = self.replica.documents.get(ulid);
+            let mut merged = current.cloned().unwrap_or_default();
+            merged.merge(document);
+            if let Some(purged) = self.local.purged_tombstones.get(ulid) {
+                merged.drop_until(purged);
+            }
+            if current == Some(&merged) {
+                continue;
+            }
+            let before = self.task(ulid)?;
+            let after = merged.task(ulid)?;
+            self.replica.documents.insert(ulid.clone(), merged);
+            if before.is_some() {
+                self.local.purged_trash.remove(ulid);
+            }
+            self.touch(ulid);
+            changed = true;
+            match (&before, &after) {
+                (Some(_), None) => self.record(TaskEventKind::Delete, before, after),
+                _ if before != after => self.record(TaskEventKind::Import, before, after),
+                _ => {}
+            }
+        }
+        Ok(changed)
+    }
+
+    /// The live tasks in a MemoryStorage, to share its queries
+    fn memory(&self) -> Result<MemoryStorage> {
+        let tasks = self
+            .tasks()?
+            .into_iter()
+            .map(|x| (x.ulid.clone(), (x, 0)))
+            .collect();
+        Ok(MemoryStorage::from_state(MemoryState {
+            tasks,
+            ..Default::default()
+        }))
+    }
+
+    fn save(&mut self, task: &Task) -> Result<()> {
+        if self.task(&task.ulid)?.is_some() {
+            bail!("Task with ulid: {} already exists", task.ulid);
+        }
+        let task = stored(task, Some(Utc::now()));
+        self.write_task(&task)?;
+        self.record(TaskEventKind::Save, None, Some(task));
+        Ok(())
+    }
+
+    fn delete(&mut self, task: &Task) -> Result<()> {
+        let Some(before) = self.task(&task.ulid)? else {
+            bail!("Task with ulid: {} doesn't exist", task.ulid);
+        };
+        self.write_tombstone(&task.ulid, Utc::now().trunc_subsecs(0));
+        self.record(TaskEventKind::Delete, Some(before), None);
+        Ok(())
+    }
+
+    fn update(&mut self, task: &Task) -> Result<()> {
+        if let Some(before) = self.task(&task.ulid)? {
+            let after = stored(task, Some(Utc::now()));
+            self.write_task(&after)?;
+            let kind = TaskEventKind::of_update(&before, &after);
+            self.record(kind, Some(before), Some(after));
+        }
+        Ok(())
+    }
+
+    fn import(&mut self, task: &Task) -> Result<()> {
+        let task = stored(task, task.modified_utc);
+        let before = self.task(&task.ulid)?;
+        if before.as_ref().is_some_and(|x| x.same_as(&task)) {
+            return Ok(());
+        }
+        self.write_task(&task)?;
+        self.record(TaskEventKind::Import, before, Some(task));
+        Ok(())
+    }
+
+    fn apply_tombstone(&mut self, tombstone: &Tombstone) -> Result<()> {
+        if let Some(task) = self.task(&tombstone.ulid)? {
+            if task.modified_utc > Some(tombstone.deleted_utc) {
+                // edited after it was deleted elsewhere so the edit wins
+                return Ok(());
+            }
+            self.record(TaskEventKind::Delete, Some(task), None);
+        } else if self.deleted_utc(&tombstone.ulid) >= Some(tombstone.deleted_utc) {
+            return Ok(());
+        }
+        self.write_tombstone(&tombstone.ulid, tombstone.deleted_utc);
+        Ok(())
+    }
+
+    fn apply_batch(&mut self, batch: &TaskBatch) -> Result<()> {
+        batch.save.iter().try_for_each(|x| self.save(x))?;
+        batch.update.iter().try_for_each(|x| self.update(x))?;
+        batch.import.iter().try_for_each(|x| self.import(x))?;
+        batch.delete.iter().try_for_each(|x| self.delete(x))?;
+        batch
+            .tombstones
+            .iter()
+            .try_for_each(|x| self.apply_tombstone(x))
+    }
+
+    fn tombstones(&self) -> Vec<Tombstone> {
+        self.replica
+            .documents
+            .iter()
+            .filter(|(ulid, document)| !self.tombstone_purged(ulid, document))
+            .filter_map(|(ulid, document)| {
+                document.deleted_utc().map(|deleted_utc| Tombstone {
+                    ulid: ulid.clone(),
+                    deleted_utc,
+                })
+            })
+            .collect()
+    }
+
+    fn purge_tombstones(&mut self, cursor: u64) -> Result<usize> {
+        let trashed: BTreeSet<String> = self.trash()?.into_iter().map(|x| x.task.ulid).collect();
+        let changes = &self.local.changes;
+        let purged: Vec<String> = self
+            .tombstones()
+            .into_iter()
+            .filter(|x| changes.get(&x.ulid).is_none_or(|x| *x <= cursor))
+            .filter(|x| !trashed.contains(&x.ulid))
+            .map(|x| x.ulid)
+            .collect();
+        for ulid in &purged {
+            let Some(document) = self.replica.documents.get_mut(ulid) else {
+                continue;
+            };
+            let Some(stamp) = document.deleted.as_ref().map(|x| x.stamp.clone()) else {
+                continue;
+            };
+            document.drop_until(&stamp);
+            self.local.purged_tombstones.insert(ulid.clone(), stamp);
+            self.local.changes.remove(ulid);
+            self.local.purged_trash.remove(ulid);
+        }
+        let documents = &self.replica.documents;
+        for bases in self.local.sync_bases.values_mut() {
+            bases.retain(|ulid, _| {
+                documents
+                    .get(ulid)
+                    .is_some_and(|x| x.deleted_utc().is_none())
+            });
+        }
+        Ok(purged.len())
+    }
+
+    fn trash(&self) -> Result<Vec<TrashedTask>> {
+        let mut trash = vec![];
+        for (ulid, document) in &self.replica.documents {
+            let Some(deleted_utc) = document.deleted_utc() else {
+                continue;
+            };
+            if self.local.purged_trash.contains(ulid) || self.tombstone_purged(ulid, document) {
+                continue;
+            }
+            if let Some(task) = document.last_task(ulid)? {
+                trash.push(TrashedTask { task, deleted_utc });
+            }
+        }
+        trash.sort_by(|a, b| (b.deleted_utc, &b.task.ulid).cmp(&(a.deleted_utc, &a.task.ulid)));
+        Ok(trash)
+    }
+
+    fn purge_trash(&mut self, before: &DateTime<Utc>) -> Result<usize> {
+        let purged: Vec<String> = self
+            .trash()?
+            .into_iter()
+            .filter(|x| x.deleted_utc < *before)
+            .map(|x| x.task.ulid)
+            .collect();
+        self.local.purged_trash.extend(purged.iter().cloned());
+        Ok(purged.len())
+    }
+
+    fn changes_since(&self, cursor: u64) -> Result<ChangeSet> {
+        let mut changes = ChangeSet {
+            tasks: vec![],
+            tombstones: vec![],
+            cursor: self.local.change_sequence,
+        };
+        for (ulid, change) in &self.local.changes {
+            if *change <= cursor {
+                continue;
+            }
+            let Some(document) = self.replica.documents.get(ulid) else {
+                continue;
+            };
+            if self.tombstone_purged(ulid, document) {
+                continue;
+            }
+            if let Some(deleted_utc) = document.deleted_utc() {
+                changes.tombstones.push(Tombstone {
+                    ulid: ulid.clone(),
+                    deleted_utc,
+                });
+            } else {
+                changes.tasks.extend(document.task(ulid)?);
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// Keeps each task as a CRDT document in a replica file: last-writer-wins registers for the
+/// fields and an add-wins set for the tags. Writes are ordered by a Lamport clock rather than
+/// wall clocks, so replicas converge to the same tasks whichever order they merge in.
+///
+/// Replicas exchange state by putting their files next to each other, say in a synced folder.
+/// Every other file with the same extension in the directory is merged in on each call, and
+/// `merge_replica` merges one from anywhere. Syncing with other strains works as usual.
+pub struct CrdtStorage {
+    path: PathBuf,
+    /// The loaded state while a transaction holds the lock
+    open: RefCell<Option<CrdtState>>,
+}
+
+impl CrdtStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Couldn't create directory {}", dir.display()))?;
+        }
+        Ok(Self {
+            path,
+            open: RefCell::new(None),
+        })
+    }
+
+    /// Merges the replica file at `path` into this one
+    pub fn merge_replica(&self, path: impl AsRef<Path>) -> Result<bool> {
+        let other = read_state(path.as_ref())?;
+        self.write(|x| x.merge(&other.replica))
+    }
+
+    /// Blocks until no other invocation is using the replica, unlocked when dropped
+    fn lock(&self) -> Result<File> {
+        let mut lock_path = self.path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path)?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    /// Runs `f` on the replica and writes it back if `write` is set. Nothing changes if `f` fails.
+    fn with_state<T>(&self, write: bool, f: impl FnOnce(&mut CrdtState) -> Result<T>) -> Result<T> {
+        if let Some(state) = self.open.borrow_mut().as_mut() {
+            let mut scratch = state.clone();
+            let result = f(&mut scratch)?;
+            *state = scratch;
+            return Ok(result);
+        }
+        let _lock = self.lock()?;
+        let (mut state, changed) = self.load()?;
+        let result = f(&mut state)?;
+        if write || changed {
+            self.store(&state)?;
+        }
+        Ok(result)
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&CrdtState) -> Result<T>) -> Result<T> {
+        self.with_state(false, |x| f(x))
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&mut CrdtState) -> Result<T>) -> Result<T> {
+        self.with_state(true, f)
+    }
+
+    /// The replica with its siblings merged in and whether it needs writing back
+    fn load(&self) -> Result<(CrdtState, bool)> {
+        let (mut state, mut changed) = if self.path.exists() {
+            (read_state(&self.path)?, false)
+        } else {
+            (CrdtState::new(), true)
+        };
+        for sibling in self.siblings()? {
+            // not every file next to the replica is one, e.g. a sync tool's conflicted copy,
+            // `health` reports the ones skipped
+            let Ok(other) = read_state(&sibling) else {
+                continue;
+            };
+            changed |= state.merge(&other.replica)?;
+        }
+        Ok((state, changed))
+    }
+
+    fn siblings(&self) -> Result<Vec<PathBuf>> {
+        let Some(dir) = self.path.parent() else {
+            return Ok(vec![]);
+        };
+        let mut siblings = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path != self.path && path.is_file() && path.extension() == self.path.extension() {
+                siblings.push(path);
+            }
+        }
+        siblings.sort();
+        Ok(siblings)
+    }
+
+    fn store(&self, state: &CrdtState) -> Result<()> {
+        write_atomically(&self.path, &serde_json::to_string(state)?)
+    }
+}
+
+fn read_state(path: &Path) -> Result<CrdtState> {
+    let content = fs::read_to_string(path)?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Couldn't parse replica {}", path.display()))
+}
+
+impl TaskStorage for CrdtStorage {
+    fn save(&self, task: &Task) -> Result<()> {
+        self.write(|x| x.save(task))
+    }
+
+    fn delete(&self, task: &Task) -> Result<()> {
+        self.write(|x| x.delete(task))
+    }
+
+    fn update(&self, task: &Task) -> Result<()> {
+        self.write(|x| x.update(task))
+    }
+
+    fn import(&self, task: &Task) -> Result<()> {
+        self.write(|x| x.import(task))
+    }
+
+    fn apply_batch(&self, batch: &TaskBatch) -> Result<()> {
+        self.write(|x| x.apply_batch(batch))
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        if self.open.borrow().is_some() {
+            return f();
+        }
+        let _lock = self.lock()?;
+        let (state, _) = self.load()?;
+        *self.open.borrow_mut() = Some(state);
+        let result = f();
+        let state = self.open.borrow_mut().take();
+        result?;
+        match state {
+            Some(state) => self.store(&state),
+            None => Ok(()),
+        }
+    }
+
+    fn search_using_ulid(&self, ulid: &str) -> Result<Vec<Task>> {
+        self.read(|x| x.memory()?.search_using_ulid(ulid))
+    }
+
+    fn next_tasks(&self, count: usize) -> Result<Vec<Task>> {
+        self.read(|x| x.memory()?.next_tasks(count))
+    }
+
+    fn summarize_day(&self, summary: &SummaryConfig) -> Result<DaySummaryResult> {
+        self.read(|x| x.memory()?.summarize_day(summary))
+    }
+
+    fn tombstones(&self) -> Result<Vec<Tombstone>> {
+        self.read(|x| Ok(x.tombstones()))
+    }
+
+    fn apply_tombstone(&self, tombstone: &Tombstone) -> Result<()> {
+        self.write(|x| x.apply_tombstone(tombstone))
+    }
+
+    fn purge_tombstones(&self, cursor: u64) -> Result<usize> {
+        self.write(|x| x.purge_tombstones(cursor))
+    }
+
+    fn trash(&self) -> Result<Vec<TrashedTask>> {
+        self.read(|x| x.trash())
+    }
+
+    fn purge_trash(&self, before: &DateTime<Utc>) -> Result<usize> {
+        self.write(|x| x.purge_trash(before))
+    }
+
+    fn changes_since(&self, cursor: u64) -> Result<ChangeSet> {
+        self.read(|x| x.changes_since(cursor))
+    }
+
+    fn query(&self, filter: &TaskFilter) -> Result<Vec<Task>> {
+        self.read(|x| x.memory()?.query(filter))
+    }
+
+    fn search_text(&self, query: &str, limit: usize) -> Result<Vec<Task>> {
+        self.read(|x| x.memory()?.search_text(query, limit))
+    }
+
+    fn health(&self) -> Result<HealthReport> {
+        self.read(|x| {
+            let mut checks = x.memory()?.health()?.checks;
+            checks.push(HealthCheck::ok(
+                "replica",
+                format!(
+                    "{} at clock {}, {} tombstones",
+                    x.replica.replica,
+                    x.replica.clock,
+                    x.tombstones().len()
+                ),
+            ));
+            let siblings = self.siblings()?;
+            let skipped: Vec<String> = siblings
+                .iter()
+                .filter_map(|x| read_state(x).err())
+                .map(|e| format!("{e:#}"))
+                .collect();
+            let detail = format!("{} merged", siblings.len() - skipped.len());
+            checks.push(match skipped.is_empty() {
+                true => HealthCheck::ok("siblings", detail),
+                false => HealthCheck::degraded(
+                    "siblings",
+                    format!("{detail}, skipped:\n{}", skipped.join("\n")),
+                ),
+            });
+            Ok(HealthReport::new(checks))
+        })
+    }
+
+    fn sync_state(&self, peer: &str) -> Result<SyncState> {
+        self.read(|x| Ok(x.local.sync_states.get(peer).cloned().unwrap_or_default()))
+    }
+
+    fn save_sync_state(&self, peer: &str, sync_state: &SyncState) -> Result<()> {
+        self.write(|x| {
+            x.local
+                .sync_states
+                .insert(peer.to_string(), sync_state.clone());
+            Ok(())
+        })
+    }
+
+    fn sync_bases(&self, peer: &str) -> Result<Vec<Task>> {
+        self.read(|x| {
+            Ok(x.local
+                .sync_bases
+                .get(peer)
+                .map(|x| x.values().cloned().collect())
+                .unwrap_or_default())
+        })
+    }
+
+    fn save_sync_bases(&self, peer: &str, tasks: &[Task]) -> Result<()> {
+        self.write(|x| {
+            let bases = x.local.sync_bases.entry(peer.to_string()).or_default();
+            for task in tasks {
+                bases.insert(task.ulid.clone(), task.clone());
+            }
+            Ok(())
+        })
+    }
+
+    fn conflicts(&self) -> Result<Vec<SyncConflict>> {
+        self.read(|x| {
+            let mut conflicts: Vec<SyncConflict> = x.local.conflicts.values().cloned().collect();
+            conflicts.sort_by_key(|x| x.detected_utc);
+            Ok(conflicts)
+        })
+    }
+
+    fn save_conflict(&self, conflict: &SyncConflict) -> Result<()> {
+        self.write(|x| {
+            x.local
+                .conflicts
+                .insert(conflict.ulid.clone(), conflict.clone());
+            Ok(())
+        })
+    }
+
+    fn remove_conflict(&self, ulid: &str) -> Result<()> {
+        self.write(|x| {
+            x.local.conflicts.remove(ulid);
+            Ok(())
+        })
+    }
+
+    fn push_operation(&self, operation: &Operation) -> Result<()> {
+        self.write(|x| {
+            let operations = &mut x.local.operations;
+            operations.push(operation.clone());
+            let excess = operations.len().saturating_sub(JOURNAL_LENGTH);
+            operations.drain(..excess);
+            Ok(())
+        })
+    }
+
+    fn pop_operation(&self) -> Result<Option<Operation>> {
+        self.write(|x| Ok(x.local.operations.pop()))
+    }
+
+    fn history(&self, ulid: &str) -> Result<Vec<TaskEvent>> {
+        self.read(|x| {
+            Ok(x.local
+                .events
+                .iter()
+                .filter(|x| x.ulid == ulid)
+                .cloned()
+                .collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::storage::conformance;
+    use crate::storage::storage::HealthStatus;
+
+    fn tags(tags: &[&str]) -> Option<Vec<String>> {
+        Some(tags.iter().map(|x| x.to_string()).collect())
+    }
+
+    #[test]
+    fn crdt_storage_conforms() {
+        let root = tempfile::tempdir().unwrap();
+        let count = Cell::new(0);
+        conformance::run_all(&|| {
+            count.set(count.get() + 1);
+            let path = root
+                .path()
+                .join(count.get().to_string())
+                .join("replica.json");
+            Box::new(CrdtStorage::open(path).unwrap())
+        });
+    }
+
+    #[test]
+    fn concurrent_edits_merge_field_by_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = CrdtStorage::open(dir.path().join("laptop.json")).unwrap();
+        let task = Task {
+            body: "water the plants".to_string(),
+            tags: tags(&["home", "chores"]),
+            ..Default::default()
+        };
+        laptop.save(&task).unwrap();
+        let phone = CrdtStorage::open(dir.path().join("phone.json")).unwrap();
+        assert!(phone.search_using_ulid(&task.ulid).unwrap().len() == 1);
+
+        // edit the replicas apart from each other
+        let outside = tempfile::tempdir().unwrap();
+        fs::rename(
+            dir.path().join("phone.json"),
+            outside.path().join("phone.json"),
+        )
+        .unwrap();
+        let phone = CrdtStorage::open(outside.path().join("phone.json")).unwrap();
+        laptop
+            .update(&Task {
+                body: "water the cactus".to_string(),
+                tags: tags(&["home", "garden"]),
+                ..task.clone()
+            })
+            .unwrap();
+        phone
+            .update(&Task {
+                due_utc: Some("2024-03-01T09:00:00Z".parse().unwrap()),
+                tags: tags(&["home", "chores", "weekly"]),
+                ..task.clone()
+            })
+            .unwrap();
+
+        assert!(laptop
+            .merge_replica(outside.path().join("phone.json"))
+            .unwrap());
+        assert!(phone.merge_replica(dir.path().join("laptop.json")).unwrap());
+        let merged = laptop.search_using_ulid(&task.ulid).unwrap().pop().unwrap();
+        assert_eq!(merged.body, "water the cactus");
+        assert_eq!(
+            merged.due_utc,
+            Some("2024-03-01T09:00:00Z".parse().unwrap())
+        );
+        // chores was removed on the laptop, weekly added on the phone
+        assert_eq!(merged.tags, tags(&["garden", "home", "weekly"]));
+        assert_eq!(
+            phone.search_using_ulid(&task.ulid).unwrap().pop().unwrap(),
+            merged
+        );
+        assert!(!laptop
+            .merge_replica(outside.path().join("phone.json"))
+            .unwrap());
+    }
+
+    #[test]
+    fn purged_tombstones_keep_stale_replicas_from_resurrecting() {
+        let mut laptop = CrdtState::new();
+        let mut phone = CrdtState::new();
+        let task = Task {
+            body: "water the plants".to_string(),
+            tags: tags(&["home"]),
+            ..Default::default()
+        };
+        laptop.save(&task).unwrap();
+        phone.merge(&laptop.replica).unwrap();
+        let stale = phone.replica.clone();
+
+        laptop.delete(&task).unwrap();
+        let later = Utc::now() + chrono::Duration::days(1);
+        assert_eq!(laptop.purge_trash(&later).unwrap(), 1);
+        assert_eq!(laptop.purge_tombstones(u64::MAX).unwrap(), 1);
+        assert!(laptop.tombstones().is_empty());
+        assert!(!laptop.merge(&stale).unwrap(), "nothing to bring back");
+        assert!(laptop.tasks().unwrap().is_empty());
+        assert!(laptop.trash().unwrap().is_empty());
+
+        // the phone still gets the delete and can restore the task from its trash
+        phone.merge(&laptop.replica).unwrap();
+        assert!(phone.tasks().unwrap().is_empty());
+        let trashed = phone.trash().unwrap().pop().unwrap().task;
+        phone
+            .import(&Task {
+                modified_utc: Some(later),
+                ..trashed
+            })
+            .unwrap();
+        laptop.merge(&phone.replica).unwrap();
+        assert_eq!(laptop.tasks().unwrap(), phone.tasks().unwrap());
+        assert_eq!(laptop.tasks().unwrap()[0].tags, task.tags);
+    }
+
+    #[test]
+    fn files_that_arent_replicas_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = CrdtStorage::open(dir.path().join("laptop.json")).unwrap();
+        laptop.save(&Task::default()).unwrap();
+        fs::write(dir.path().join("settings.json"), "{}").unwrap();
+        let phone = CrdtStorage::open(dir.path().join("phone.json")).unwrap();
+        assert_eq!(phone.search_using_ulid("").unwrap().len(), 1);
+
+        let health = phone.health().unwrap();
+        assert_eq!(health.status, HealthStatus::Degraded);
+        let siblings = health.checks.iter().find(|x| x.name == "siblings").unwrap();
+        assert!(
+            siblings.detail.contains("settings.json"),
+            "{}",
+            siblings.detail
+        );
+    }
+
+    #[test]
+    fn merge_order_doesnt_matter() {
+        let mut replicas: Vec<CrdtState> = (0..3).map(|_| CrdtState::new()).collect();
+        let task = Task {
+            tags: tags(&["a"]),
+            ..Default::default()
+        };
+        replicas[0].save(&task).unwrap();
+        let first = replicas[0].replica.clone();
+        for replica in &mut replicas[1..] {
+            replica.merge(&first).unwrap();
+        }
+        for (i, replica) in replicas.iter_mut().enumerate() {
+            replica
+                .update(&Task {
+                    body: format!("edited on {i}"),
+                    tags: tags(&[&i.to_string()]),
+                    ..task.clone()
+                })
+                .unwrap();
+        }
+        replicas[2].delete(&task).unwrap();
+        replicas[1].import(&task).unwrap();
+
+        let snapshots: Vec<Replica> = replicas.iter().map(|x| x.replica.clone()).collect();
+        let mut forward = CrdtState::new();
+        let mut backward = CrdtState::new();
+        for snapshot in &snapshots {
+            forward.merge(snapshot).unwrap();
+        }
+        for snapshot in snapshots.iter().rev() {
+            backward.merge(snapshot).unwrap();
+        }
+        assert_eq!(forward.replica.documents, backward.replica.documents);
+        assert_eq!(forward.tasks().unwrap(), backward.tasks().unwrap());
+    }
+}
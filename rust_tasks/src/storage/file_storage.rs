@@ -0,0 +1,471 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::tasks::{summary::SummaryConfig, Task};
+
+use super::filter::TaskFilter;
+use super::memory_storage::{MemoryState, MemoryStorage};
+use super::storage::{
+    ChangeSet, DaySummaryResult, HealthCheck, HealthReport, Operation, SyncConflict, SyncState,
+    TaskBatch, TaskEvent, TaskStorage, Tombstone, TrashedTask,
+};
+
+/// How FileStorage lays the tasks out in its directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub enum FileFormat {
+    /// One task per line in `tasks.jsonl`
+    #[default]
+    JsonLines,
+    /// One `<ulid>.yml` per task in `tasks/`
+    Yaml,
+}
+
+const LOCK_FILE: &str = ".lock";
+const STATE_FILE: &str = "state.json";
+const JSON_LINES_FILE: &str = "tasks.jsonl";
+const YAML_DIR: &str = "tasks";
+
+/// Keeps tasks as plain files in a directory so they can live in git or a synced folder.
+/// Tombstones, change cursors and sync metadata go to `state.json` next to them.
+///
+/// Every call locks the directory, loads it into a MemoryStorage and writes it back, so
+/// concurrent invocations see each other's writes. Tasks edited by hand get a new change on the
+/// next load and tasks removed by hand get a tombstone.
+pub struct FileStorage {
+    dir: PathBuf,
+    format: FileFormat,
+    /// The loaded storage while a transaction holds the lock
+    open: RefCell<Option<MemoryStorage>>,
+}
+
+/// `state.json`, the MemoryState without its tasks
+#[derive(Default, Serialize, Deserialize)]
+struct StateFile {
+    #[serde(flatten)]
+    state: MemoryState,
+    /// Change sequence and content fingerprint of each task when it was last written
+    #[serde(default)]
+    task_changes: BTreeMap<String, (u64, u64)>,
+}
+
+impl FileStorage {
+    pub fn open(dir: impl AsRef<Path>, format: FileFormat) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Couldn't create task directory {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            format,
+            open: RefCell::new(None),
+        })
+    }
+
+    /// Blocks until no other invocation is using the directory, unlocked when dropped
+    fn lock(&self) -> Result<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.dir.join(LOCK_FILE))?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    /// Runs `f` on the directory's contents and writes them back if `write` is set
+    fn with_storage<T>(
+        &self,
+        write: bool,
+        f: impl FnOnce(&MemoryStorage) -> Result<T>,
+    ) -> Result<T> {
+        if let Some(storage) = self.open.borrow().as_ref() {
+            return f(storage);
+        }
+        let _lock = self.lock()?;
+        let (storage, changed_on_disk) = self.load()?;
+        let result = f(&storage)?;
+        if write || changed_on_disk {
+            self.store(storage.into_state())?;
+        }
+        Ok(result)
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&MemoryStorage) -> Result<T>) -> Result<T> {
+        self.with_storage(false, f)
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&MemoryStorage) -> Result<T>) -> Result<T> {
+        self.with_storage(true, f)
+    }
+
+    /// The stored state and whether the tasks were changed by something else since the last write
+    fn load(&self) -> Result<(MemoryStorage, bool)> {
+        let state_path = self.dir.join(STATE_FILE);
+        let StateFile {
+            mut state,
+            task_changes,
+        } = match fs::read_to_string(&state_path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Couldn't parse {}", state_path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StateFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut changed = false;
+        for (task, content) in self.read_tasks()? {
+            let fingerprint = fingerprint(&content);
+            match task_changes.get(&task.ulid) {
+                Some((change, recorded)) if *recorded == fingerprint => {
+                    state.tasks.insert(task.ulid.clone(), (task, *change));
+                }
+                _ => {
+                    changed = true;
+                    state.tombstones.remove(&task.ulid);
+                    state.put_task(task);
+                }
+            }
+        }
+        let now = Utc::now().trunc_subsecs(0);
+        for ulid in task_changes.keys() {
+            if !state.tasks.contains_key(ulid) {
+                changed = true;
+                state.put_tombstone(ulid, now);
+            }
+        }
+        Ok((MemoryStorage::from_state(state), changed))
+    }
+
+    /// Tasks with the content they were read from
+    fn read_tasks(&self) -> Result<Vec<(Task, String)>> {
+        match self.format {
+            FileFormat::JsonLines => {
+                let path = self.dir.join(JSON_LINES_FILE);
+                let content = match fs::read_to_string(&path) {
+                    Ok(x) => x,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+                    Err(e) => return Err(e.into()),
+                };
+                content
+                    .lines()
+                    .enumerate()
+                    .filter(|(_, line)| !line.trim().is_empty())
+                    .map(|(number, line)| {
+                        let task = serde_json::from_str(line).with_context(|| {
+                            format!("Couldn't parse {} line {}", path.display(), number + 1)
+                        })?;
+                        Ok((task, line.to_string()))
+                    })
+                    .collect()
+            }
+            FileFormat::Yaml => {
+                let dir = self.dir.join(YAML_DIR);
+                if !dir.exists() {
+                    return Ok(vec![]);
+                }
+                let mut tasks = vec![];
+                for entry in fs::read_dir(&dir)? {
+                    let path = entry?.path();
+                    if path.extension().is_none_or(|x| x != "yml") {
+                        continue;
+                    }
+                    let content = fs::read_to_string(&path)?;
+                    let task = serde_yaml::from_str(&content)
+                        .with_context(|| format!("Couldn't parse {}", path.display()))?;
+                    tasks.push((task, content));
+                }
+                Ok(tasks)
+            }
+        }
+    }
+
+    fn store(&self, state: MemoryState) -> Result<()> {
+        let mut task_changes = BTreeMap::new();
+        let mut contents = BTreeMap::new();
+        for (ulid, (task, change)) in &state.tasks {
+            let content = match self.format {
+                FileFormat::JsonLines => serde_json::to_string(task)?,
+                FileFormat::Yaml => serde_yaml::to_string(task)?,
+            };
+            task_changes.insert(ulid.clone(), (*change, fingerprint(&content)));
+            contents.insert(ulid.clone(), content);
+        }
+
+        match self.format {
+            FileFormat::JsonLines => {
+                let lines: String = contents.values().map(|x| format!("{x}\n")).collect();
+                write_atomically(&self.dir.join(JSON_LINES_FILE), &lines)?;
+            }
+            FileFormat::Yaml => {
+                let dir = self.dir.join(YAML_DIR);
+                fs::create_dir_all(&dir)?;
+                for entry in fs::read_dir(&dir)? {
+                    let path = entry?.path();
+                    let stem = path
+                        .file_stem()
+                        .and_then(|x| x.to_str())
+                        .unwrap_or_default();
+                    if path.extension().is_some_and(|x| x == "yml") && !contents.contains_key(stem)
+                    {
+                        fs::remove_file(&path)?;
+                    }
+                }
+                for (ulid, content) in &contents {
+                    let path = dir.join(format!("{ulid}.yml"));
+                    // leave unchanged files alone so synced folders don't churn
+                    if fs::read_to_string(&path).is_ok_and(|x| &x == content) {
+                        continue;
+                    }
+                    write_atomically(&path, content)?;
+                }
+            }
+        }
+
+        let state_file = StateFile {
+            state,
+            task_changes,
+        };
+        write_atomically(
+            &self.dir.join(STATE_FILE),
+            &serde_json::to_string_pretty(&state_file)?,
+        )
+    }
+}
+
+/// Writes to a temporary file first so a crash never leaves half a file behind
+pub(super) fn write_atomically(path: &Path, content: &str) -> Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, content)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// 64-bit FNV-1a of the content. Fingerprints are kept in `state.json`, so unlike `DefaultHasher`
+/// the hash must not change between Rust releases.
+fn fingerprint(content: &str) -> u64 {
+    content.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl TaskStorage for FileStorage {
+    fn save(&self, task: &Task) -> Result<()> {
+        self.write(|x| x.save(task))
+    }
+
+    fn delete(&self, task: &Task) -> Result<()> {
+        self.write(|x| x.delete(task))
+    }
+
+    fn update(&self, task: &Task) -> Result<()> {
+        self.write(|x| x.update(task))
+    }
+
+    fn import(&self, task: &Task) -> Result<()> {
+        self.write(|x| x.import(task))
+    }
+
+    fn apply_batch(&self, batch: &TaskBatch) -> Result<()> {
+        self.write(|x| x.apply_batch(batch))
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        if self.open.borrow().is_some() {
+            return f();
+        }
+        let _lock = self.lock()?;
+        let (storage, _) = self.load()?;
+        *self.open.borrow_mut() = Some(storage);
+        let result = f();
+        let storage = self.open.borrow_mut().take();
+        result?;
+        match storage {
+            Some(storage) => self.store(storage.into_state()),
+            None => Ok(()),
+        }
+    }
+
+    fn search_using_ulid(&self, ulid: &str) -> Result<Vec<Task>> {
+        self.read(|x| x.search_using_ulid(ulid))
+    }
+
+    fn next_tasks(&self, count: usize) -> Result<Vec<Task>> {
+        self.read(|x| x.next_tasks(count))
+    }
+
+    fn summarize_day(&self, summary: &SummaryConfig) -> Result<DaySummaryResult> {
+        self.read(|x| x.summarize_day(summary))
+    }
+
+    fn tombstones(&self) -> Result<Vec<Tombstone>> {
+        self.read(|x| x.tombstones())
+    }
+
+    fn apply_tombstone(&self, tombstone: &Tombstone) -> Result<()> {
+        self.write(|x| x.apply_tombstone(tombstone))
+    }
+
+    fn purge_tombstones(&self, cursor: u64) -> Result<usize> {
+        self.write(|x| x.purge_tombstones(cursor))
+    }
+
+    fn trash(&self) -> Result<Vec<TrashedTask>> {
+        self.read(|x| x.trash())
+    }
+
+    fn purge_trash(&self, before: &DateTime<Utc>) -> Result<usize> {
+        self.write(|x| x.purge_trash(before))
+    }
+
+    fn changes_since(&self, cursor: u64) -> Result<ChangeSet> {
+        self.read(|x| x.changes_since(cursor))
+    }
+
+    fn query(&self, filter: &TaskFilter) -> Result<Vec<Task>> {
+        self.read(|x| x.query(filter))
+    }
+
+    fn search_text(&self, query: &str, limit: usize) -> Result<Vec<Task>> {
+        self.read(|x| x.search_text(query, limit))
+    }
+
+    fn health(&self) -> Result<HealthReport> {
+        self.read(|x| {
+            let mut report = x.health()?;
+            report.checks.push(HealthCheck::ok(
+                "directory",
+                format!("{} ({:?})", self.dir.display(), self.format),
+            ));
+            Ok(report)
+        })
+    }
+
+    fn sync_state(&self, peer: &str) -> Result<SyncState> {
+        self.read(|x| x.sync_state(peer))
+    }
+
+    fn save_sync_state(&self, peer: &str, sync_state: &SyncState) -> Result<()> {
+        self.write(|x| x.save_sync_state(peer, sync_state))
+    }
+
+    fn sync_bases(&self, peer: &str) -> Result<Vec<Task>> {
+        self.read(|x| x.sync_bases(peer))
+    }
+
+    fn save_sync_bases(&self, peer: &str, tasks: &[Task]) -> Result<()> {
+        self.write(|x| x.save_sync_bases(peer, tasks))
+    }
+
+    fn conflicts(&self) -> Result<Vec<SyncConflict>> {
+        self.read(|x| x.conflicts())
+    }
+
+    fn save_conflict(&self, conflict: &SyncConflict) -> Result<()> {
+        self.write(|x| x.save_conflict(conflict))
+    }
+
+    fn remove_conflict(&self, ulid: &str) -> Result<()> {
+        self.write(|x| x.remove_conflict(ulid))
+    }
+
+    fn history(&self, ulid: &str) -> Result<Vec<TaskEvent>> {
+        self.read(|x| x.history(ulid))
+    }
+
+    fn push_operation(&self, operation: &Operation) -> Result<()> {
+        self.write(|x| x.push_operation(operation))
+    }
+
+    fn pop_operation(&self) -> Result<Option<Operation>> {
+        self.write(|x| x.pop_operation())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::storage::conformance;
+
+    #[test]
+    fn file_storage_conforms() {
+        let root = tempfile::tempdir().unwrap();
+        let count = Cell::new(0);
+        for format in [FileFormat::JsonLines, FileFormat::Yaml] {
+            conformance::run_all(&|| {
+                count.set(count.get() + 1);
+                let dir = root.path().join(count.get().to_string());
+                Box::new(FileStorage::open(dir, format).unwrap())
+            });
+        }
+    }
+
+    #[test]
+    fn fingerprints_are_stable() {
+        assert_eq!(fingerprint(""), 0xcbf29ce484222325);
+        assert_eq!(fingerprint("a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn hand_edits_are_picked_up_as_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::open(dir.path(), FileFormat::Yaml).unwrap();
+        let edited = Task::default();
+        let removed = Task::default();
+        storage.save(&edited).unwrap();
+        storage.save(&removed).unwrap();
+        let cursor = storage.changes_since(0).unwrap().cursor;
+
+        let path = dir
+            .path()
+            .join(YAML_DIR)
+            .join(format!("{}.yml", edited.ulid));
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replace("body: ''", "body: edited by hand")).unwrap();
+        fs::remove_file(
+            dir.path()
+                .join(YAML_DIR)
+                .join(format!("{}.yml", removed.ulid)),
+        )
+        .unwrap();
+
+        let changes = storage.changes_since(cursor).unwrap();
+        assert_eq!(changes.tasks.len(), 1);
+        assert_eq!(changes.tasks[0].body, "edited by hand");
+        assert_eq!(changes.tombstones.len(), 1);
+        assert_eq!(changes.tombstones[0].ulid, removed.ulid);
+        // recorded once, not again on every read
+        assert!(storage
+            .changes_since(changes.cursor)
+            .unwrap()
+            .tasks
+            .is_empty());
+    }
+
+    #[test]
+    fn concurrent_writers_dont_lose_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let dir = dir.path().to_path_buf();
+                std::thread::spawn(move || {
+                    let storage = FileStorage::open(dir, FileFormat::JsonLines).unwrap();
+                    for _ in 0..10 {
+                        storage.save(&Task::default()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let storage = FileStorage::open(dir.path(), FileFormat::JsonLines).unwrap();
+        assert_eq!(storage.query(&TaskFilter::default()).unwrap().len(), 40);
+    }
+}
@@ -0,0 +1,133 @@
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::tasks::Task;
+
+/// Backend agnostic description of which tasks to fetch.
+///
+/// Every field is optional and an empty filter matches all tasks. Fields are combined with AND.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskFilter {
+    /// Tasks must have all of these tags
+    pub tags: Vec<String>,
+    pub state: Option<TaskState>,
+    pub due: Option<DateRange>,
+    pub ready: Option<DateRange>,
+    pub closed: Option<DateRange>,
+    /// Tasks modified after this time. Tasks without a modified_utc are always included since we
+    /// can't tell when they last changed.
+    pub modified_since: Option<DateTime<Utc>>,
+    /// Case insensitive substring of the body
+    pub body_contains: Option<String>,
+    pub order_by: Option<TaskOrder>,
+    pub limit: Option<usize>,
+}
+
+impl TaskFilter {
+    /// Whether the task passes every condition, order and limit aside
+    pub fn matches(&self, task: &Task) -> bool {
+        let tags = task.tags.as_deref().unwrap_or_default();
+        self.tags.iter().all(|x| tags.contains(x))
+            && match self.state {
+                Some(TaskState::Open) => task.closed_utc.is_none(),
+                Some(TaskState::Closed) => task.closed_utc.is_some(),
+                None => true,
+            }
+            && self.due.as_ref().is_none_or(|x| x.contains(task.due_utc))
+            && self
+                .ready
+                .as_ref()
+                .is_none_or(|x| x.contains(task.ready_utc))
+            && self
+                .closed
+                .as_ref()
+                .is_none_or(|x| x.contains(task.closed_utc))
+            && self
+                .modified_since
+                .is_none_or(|x| task.modified_utc.is_none_or(|y| y > x))
+            && self
+                .body_contains
+                .as_ref()
+                .is_none_or(|x| task.body.to_lowercase().contains(&x.to_lowercase()))
+    }
+
+    /// Filters, orders and limits tasks the way SQLiteStorage does, for storages that keep tasks
+    /// in memory
+    pub fn apply(&self, tasks: impl IntoIterator<Item = Task>) -> Vec<Task> {
+        let mut tasks: Vec<Task> = tasks.into_iter().filter(|x| self.matches(x)).collect();
+        // tasks without the date sort first, like NULLs in SQLite
+        match self.order_by {
+            Some(TaskOrder::DueAsc) => tasks.sort_by_key(|x| x.due_utc),
+            Some(TaskOrder::DueDesc) => tasks.sort_by_key(|x| std::cmp::Reverse(x.due_utc)),
+            Some(TaskOrder::ModifiedDesc) => {
+                tasks.sort_by_key(|x| std::cmp::Reverse(x.modified_utc))
+            }
+            Some(TaskOrder::ClosedDesc) => tasks.sort_by_key(|x| std::cmp::Reverse(x.closed_utc)),
+            None => (),
+        }
+        if let Some(limit) = self.limit {
+            tasks.truncate(limit);
+        }
+        tasks
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Open,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskOrder {
+    DueAsc,
+    DueDesc,
+    ModifiedDesc,
+    ClosedDesc,
+}
+
+/// Half open range `[from, to)`. Tasks without the date never match.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DateRange {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl DateRange {
+    /// Range covering whole days from `start` to `end`, both inclusive
+    pub fn from_dates(start: Option<NaiveDate>, end: Option<NaiveDate>) -> Self {
+        DateRange {
+            from: start.map(|x| x.and_time(Default::default()).and_utc()),
+            to: end.map(|x| (x + Days::new(1)).and_time(Default::default()).and_utc()),
+        }
+    }
+
+    pub fn contains(&self, date: Option<DateTime<Utc>>) -> bool {
+        match date {
+            None => false,
+            Some(date) => self.from.is_none_or(|x| date >= x) && self.to.is_none_or(|x| date < x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_range_covers_whole_days() {
+        let date = NaiveDate::from_ymd_opt(2023, 8, 6).unwrap();
+        let range = DateRange::from_dates(Some(date), Some(date));
+        assert_eq!(range.from, "2023-08-06T00:00:00Z".parse().ok());
+        assert_eq!(range.to, "2023-08-07T00:00:00Z".parse().ok());
+    }
+
+    #[test]
+    fn empty_filter_deserializes() {
+        let filter: TaskFilter = serde_json::from_str("{}").unwrap();
+        assert_eq!(filter, TaskFilter::default());
+    }
+}
@@ -0,0 +1,434 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::bail;
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::tasks::{priority::by_priority, summary::SummaryConfig, Task};
+
+use super::filter::TaskFilter;
+use super::storage::{
+    ulid_matches, ChangeSet, DaySummaryResult, HealthCheck, HealthReport, Operation, SyncConflict,
+    SyncState, TaskBatch, TaskEvent, TaskEventKind, TaskStorage, Tombstone, TrashedTask,
+    JOURNAL_LENGTH,
+};
+use super::text_search;
+
+/// Keeps everything in memory, for tests and for embedding without a database. Follows the same
+/// contract as SQLiteStorage, including tombstones and change cursors.
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: RefCell<MemoryState>,
+}
+
+/// Everything a MemoryStorage holds. FileStorage persists it, writing the tasks out separately.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub(crate) struct MemoryState {
+    /// Tasks with the change sequence of their last write
+    #[serde(skip)]
+    pub tasks: BTreeMap<String, (Task, u64)>,
+    pub tombstones: BTreeMap<String, (DateTime<Utc>, u64)>,
+    /// Deleted tasks, keyed like their tombstones
+    #[serde(default)]
+    pub trash: BTreeMap<String, Task>,
+    pub change_sequence: u64,
+    pub sync_states: BTreeMap<String, SyncState>,
+    pub sync_bases: BTreeMap<String, BTreeMap<String, Task>>,
+    pub conflicts: BTreeMap<String, SyncConflict>,
+    #[serde(default)]
+    pub events: Vec<TaskEvent>,
+    #[serde(default)]
+    pub operations: Vec<Operation>,
+}
+
+impl MemoryState {
+    fn next_change(&mut self) -> u64 {
+        self.change_sequence += 1;
+        self.change_sequence
+    }
+
+    pub fn put_task(&mut self, task: Task) {
+        let change = self.next_change();
+        self.tasks.insert(task.ulid.clone(), (task, change));
+    }
+
+    fn record(&mut self, kind: TaskEventKind, before: Option<Task>, after: Option<Task>) {
+        self.events.push(TaskEvent::new(kind, before, after));
+    }
+
+    pub fn put_tombstone(&mut self, ulid: &str, deleted_utc: DateTime<Utc>) {
+        let change = self.next_change();
+        self.tombstones
+            .insert(ulid.to_string(), (deleted_utc, change));
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn from_state(state: MemoryState) -> Self {
+        Self {
+            state: RefCell::new(state),
+        }
+    }
+
+    pub(crate) fn into_state(self) -> MemoryState {
+        self.state.into_inner()
+    }
+
+    /// Runs `f` and puts the previous state back if it fails
+    fn atomically<T>(&self, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let snapshot = self.state.borrow().clone();
+        let result = f();
+        if result.is_err() {
+            *self.state.borrow_mut() = snapshot;
+        }
+        result
+    }
+
+    fn tasks(&self) -> Vec<Task> {
+        let state = self.state.borrow();
+        state.tasks.values().map(|(x, _)| x.clone()).collect()
+    }
+}
+
+/// The task as SQLiteStorage would read it back
+pub(crate) fn stored(task: &Task, modified_utc: Option<DateTime<Utc>>) -> Task {
+    let tags = task.tags.clone().map(|mut x| {
+        x.sort();
+        x.dedup();
+        x
+    });
+    Task {
+        modified_utc: modified_utc.map(|x| x.trunc_subsecs(0)),
+        tags: tags.filter(|x| !x.is_empty()),
+        ..task.clone()
+    }
+}
+
+impl TaskStorage for MemoryStorage {
+    fn save(&self, task: &Task) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        if state.tasks.contains_key(&task.ulid) {
+            bail!("Task with ulid: {} already exists", task.ulid);
+        }
+        let task = stored(task, Some(Utc::now()));
+        state.put_task(task.clone());
+        state.record(TaskEventKind::Save, None, Some(task));
+        Ok(())
+    }
+
+    fn delete(&self, task: &Task) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let Some((before, _)) = state.tasks.remove(&task.ulid) else {
+            bail!("Task with ulid: {} doesn't exist", task.ulid);
+        };
+        state.put_tombstone(&task.ulid, Utc::now().trunc_subsecs(0));
+        state.trash.insert(task.ulid.clone(), before.clone());
+        state.record(TaskEventKind::Delete, Some(before), None);
+        Ok(())
+    }
+
+    fn update(&self, task: &Task) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        if let Some((before, _)) = state.tasks.get(&task.ulid).cloned() {
+            let after = stored(task, Some(Utc::now()));
+            state.put_task(after.clone());
+            let kind = TaskEventKind::of_update(&before, &after);
+            state.record(kind, Some(before), Some(after));
+        }
+        Ok(())
+    }
+
+    fn import(&self, task: &Task) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let task = stored(task, task.modified_utc);
+        let before = state.tasks.get(&task.ulid).map(|(x, _)| x.clone());
+        if before.as_ref().is_some_and(|x| x.same_as(&task)) {
+            return Ok(());
+        }
+        state.tombstones.remove(&task.ulid);
+        state.trash.remove(&task.ulid);
+        state.put_task(task.clone());
+        state.record(TaskEventKind::Import, before, Some(task));
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: &TaskBatch) -> anyhow::Result<()> {
+        self.atomically(|| {
+            batch.save.iter().try_for_each(|x| self.save(x))?;
+            batch.update.iter().try_for_each(|x| self.update(x))?;
+            batch.import.iter().try_for_each(|x| self.import(x))?;
+            batch.delete.iter().try_for_each(|x| self.delete(x))?;
+            batch
+                .tombstones
+                .iter()
+                .try_for_each(|x| self.apply_tombstone(x))
+        })
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut() -> anyhow::Result<()>) -> anyhow::Result<()> {
+        self.atomically(f)
+    }
+
+    fn search_using_ulid(&self, ulid: &str) -> anyhow::Result<Vec<Task>> {
+        Ok(self
+            .tasks()
+            .into_iter()
+            .filter(|x| ulid_matches(&x.ulid, ulid))
+            .collect())
+    }
+
+    fn next_tasks(&self, count: usize) -> anyhow::Result<Vec<Task>> {
+        let now = Utc::now();
+        let tasks = self
+            .tasks()
+            .into_iter()
+            .filter(|x| {
+                x.due_utc
+                    .is_some_and(|x| x.date_naive() <= now.date_naive())
+                    && x.closed_utc.is_none()
+                    && x.ready_utc.is_none_or(|x| now >= x)
+            })
+            .collect();
+        Ok(by_priority(tasks, count))
+    }
+
+    fn summarize_day(&self, summary: &SummaryConfig) -> anyhow::Result<DaySummaryResult> {
+        let today = Utc::now().date_naive();
+        let tasks = self.tasks();
+        let due_today_or_before = |x: &Task| x.due_utc.is_some_and(|x| x.date_naive() <= today);
+        let closed_today = |x: &Task| x.closed_utc.is_some_and(|x| x.date_naive() == today);
+        let total_tasks = tasks
+            .iter()
+            .filter(|x| (due_today_or_before(x) && x.closed_utc.is_none()) || closed_today(x))
+            .count();
+        let done_tasks = tasks.iter().filter(|x| closed_today(x)).count();
+        let mut open_tags_count = HashMap::new();
+        for tag in summary.relevant_tags() {
+            let count = tasks
+                .iter()
+                .filter(|x| {
+                    x.due_utc.is_some_and(|x| x.date_naive() == today)
+                        && x.closed_utc.is_none()
+                        && x.tags.as_ref().is_some_and(|x| x.contains(&tag))
+                })
+                .count();
+            open_tags_count.insert(tag, count);
+        }
+        Ok(DaySummaryResult {
+            total_tasks,
+            done_tasks,
+            open_tags_count: Some(open_tags_count),
+        })
+    }
+
+    fn tombstones(&self) -> anyhow::Result<Vec<Tombstone>> {
+        let state = self.state.borrow();
+        Ok(state
+            .tombstones
+            .iter()
+            .map(|(ulid, (deleted_utc, _))| Tombstone {
+                ulid: ulid.clone(),
+                deleted_utc: *deleted_utc,
+            })
+            .collect())
+    }
+
+    fn apply_tombstone(&self, tombstone: &Tombstone) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        if let Some((task, _)) = state.tasks.get(&tombstone.ulid).cloned() {
+            if task.modified_utc > Some(tombstone.deleted_utc) {
+                // edited after it was deleted elsewhere so the edit wins
+                return Ok(());
+            }
+            state.tasks.remove(&tombstone.ulid);
+            state.trash.insert(task.ulid.clone(), task.clone());
+            state.record(TaskEventKind::Delete, Some(task), None);
+        }
+        let recorded = state.tombstones.get(&tombstone.ulid).map(|(x, _)| *x);
+        if recorded >= Some(tombstone.deleted_utc) {
+            return Ok(());
+        }
+        state.put_tombstone(&tombstone.ulid, tombstone.deleted_utc);
+        Ok(())
+    }
+
+    fn purge_tombstones(&self, cursor: u64) -> anyhow::Result<usize> {
+        let mut state = self.state.borrow_mut();
+        let MemoryState {
+            tasks,
+            tombstones,
+            trash,
+            sync_bases,
+            ..
+        } = &mut *state;
+        let count = tombstones.len();
+        tombstones.retain(|ulid, (_, change)| *change > cursor || trash.contains_key(ulid));
+        let purged = count - tombstones.len();
+        // bases of deleted tasks are no longer needed either
+        for bases in sync_bases.values_mut() {
+            bases.retain(|ulid, _| tasks.contains_key(ulid));
+        }
+        Ok(purged)
+    }
+
+    fn trash(&self) -> anyhow::Result<Vec<TrashedTask>> {
+        let state = self.state.borrow();
+        let mut trash: Vec<TrashedTask> = state
+            .trash
+            .iter()
+            .filter_map(|(ulid, task)| {
+                let (deleted_utc, _) = state.tombstones.get(ulid)?;
+                Some(TrashedTask {
+                    task: task.clone(),
+                    deleted_utc: *deleted_utc,
+                })
+            })
+            .collect();
+        trash.sort_by(|a, b| (b.deleted_utc, &b.task.ulid).cmp(&(a.deleted_utc, &a.task.ulid)));
+        Ok(trash)
+    }
+
+    fn purge_trash(&self, before: &DateTime<Utc>) -> anyhow::Result<usize> {
+        let mut state = self.state.borrow_mut();
+        let MemoryState {
+            tombstones, trash, ..
+        } = &mut *state;
+        let count = trash.len();
+        trash.retain(|ulid, _| tombstones.get(ulid).is_some_and(|(x, _)| *x >= *before));
+        Ok(count - trash.len())
+    }
+
+    fn changes_since(&self, cursor: u64) -> anyhow::Result<ChangeSet> {
+        let state = self.state.borrow();
+        Ok(ChangeSet {
+            tasks: state
+                .tasks
+                .values()
+                .filter(|(_, change)| *change > cursor)
+                .map(|(x, _)| x.clone())
+                .collect(),
+            tombstones: state
+                .tombstones
+                .iter()
+                .filter(|(_, (_, change))| *change > cursor)
+                .map(|(ulid, (deleted_utc, _))| Tombstone {
+                    ulid: ulid.clone(),
+                    deleted_utc: *deleted_utc,
+                })
+                .collect(),
+            cursor: state.change_sequence,
+        })
+    }
+
+    fn query(&self, filter: &TaskFilter) -> anyhow::Result<Vec<Task>> {
+        Ok(filter.apply(self.tasks()))
+    }
+
+    fn search_text(&self, query: &str, limit: usize) -> anyhow::Result<Vec<Task>> {
+        Ok(text_search::rank(self.tasks(), query, limit))
+    }
+
+    fn health(&self) -> anyhow::Result<HealthReport> {
+        let state = self.state.borrow();
+        let detail = format!(
+            "{} tasks, {} tombstones",
+            state.tasks.len(),
+            state.tombstones.len()
+        );
+        Ok(HealthReport::new(vec![HealthCheck::ok(
+            "row_counts",
+            detail,
+        )]))
+    }
+
+    fn sync_state(&self, peer: &str) -> anyhow::Result<SyncState> {
+        let state = self.state.borrow();
+        Ok(state.sync_states.get(peer).cloned().unwrap_or_default())
+    }
+
+    fn save_sync_state(&self, peer: &str, sync_state: &SyncState) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        state
+            .sync_states
+            .insert(peer.to_string(), sync_state.clone());
+        Ok(())
+    }
+
+    fn sync_bases(&self, peer: &str) -> anyhow::Result<Vec<Task>> {
+        let state = self.state.borrow();
+        Ok(state
+            .sync_bases
+            .get(peer)
+            .map(|x| x.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn save_sync_bases(&self, peer: &str, tasks: &[Task]) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let bases = state.sync_bases.entry(peer.to_string()).or_default();
+        for task in tasks {
+            bases.insert(task.ulid.clone(), task.clone());
+        }
+        Ok(())
+    }
+
+    fn conflicts(&self) -> anyhow::Result<Vec<SyncConflict>> {
+        let state = self.state.borrow();
+        let mut conflicts: Vec<SyncConflict> = state.conflicts.values().cloned().collect();
+        conflicts.sort_by_key(|x| x.detected_utc);
+        Ok(conflicts)
+    }
+
+    fn save_conflict(&self, conflict: &SyncConflict) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        state
+            .conflicts
+            .insert(conflict.ulid.clone(), conflict.clone());
+        Ok(())
+    }
+
+    fn remove_conflict(&self, ulid: &str) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        state.conflicts.remove(ulid);
+        Ok(())
+    }
+
+    fn push_operation(&self, operation: &Operation) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        state.operations.push(operation.clone());
+        let excess = state.operations.len().saturating_sub(JOURNAL_LENGTH);
+        state.operations.drain(..excess);
+        Ok(())
+    }
+
+    fn pop_operation(&self) -> anyhow::Result<Option<Operation>> {
+        let mut state = self.state.borrow_mut();
+        Ok(state.operations.pop())
+    }
+
+    fn history(&self, ulid: &str) -> anyhow::Result<Vec<TaskEvent>> {
+        let state = self.state.borrow();
+        Ok(state
+            .events
+            .iter()
+            .filter(|x| x.ulid == ulid)
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::conformance;
+
+    #[test]
+    fn memory_storage_conforms() {
+        conformance::run_all(&|| Box::new(MemoryStorage::new()));
+    }
+}
@@ -0,0 +1,382 @@
+use rusqlite::Connection;
+use thiserror::Error;
+
+/// A schema change applied to SQLite databases. The version is stored in `PRAGMA user_version`
+/// once the migration succeeds.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum MigrationError {
+    #[error("database schema version {found} is newer than the latest supported version {supported}, upgrade rust_tasks")]
+    NewerSchema { found: u32, supported: u32 },
+}
+
+// Migrations must never be edited once released, add a new one instead. Version 1 uses IF NOT
+// EXISTS so that databases created before migrations existed are adopted as is.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create tasks, deleted_tasks, task_to_tag and tasks_view",
+        sql: "CREATE TABLE IF NOT EXISTS tasks (
+  ulid text not null primary key,
+  body text not null,
+  modified_utc text,
+  ready_utc text,
+  due_utc text,
+  closed_utc text,
+  recurrence_duration text,
+  priority_adjustment float,
+  user text,
+  metadata text
+);
+CREATE TABLE IF NOT EXISTS deleted_tasks (
+  task_ulid text not null primary key,
+  modified_utc text
+);
+CREATE TABLE IF NOT EXISTS task_to_tag (
+    ulid TEXT NOT NULL PRIMARY KEY,
+    task_ulid TEXT NOT NULL,
+    tag text NOT NULL,
+    FOREIGN KEY(task_ulid) REFERENCES tasks(ulid),
+    CONSTRAINT no_duplicate_tags UNIQUE(task_ulid, tag)
+);
+CREATE VIEW IF NOT EXISTS tasks_view AS
+SELECT
+    tasks.*,
+    tasks.priority_adjustment AS priority,
+    group_concat(distinct task_to_tag.tag) AS tags
+FROM tasks LEFT JOIN task_to_tag ON tasks.ulid = task_to_tag.task_ulid
+GROUP BY tasks.ulid;
+",
+    },
+    Migration {
+        version: 2,
+        description: "index task_to_tag.task_ulid and tasks.due_utc",
+        sql: "CREATE INDEX IF NOT EXISTS task_to_tag_task_ulid ON task_to_tag (task_ulid);
+CREATE INDEX IF NOT EXISTS tasks_due_utc ON tasks (due_utc);
+",
+    },
+    Migration {
+        version: 3,
+        description: "create sync_peers",
+        sql: "CREATE TABLE sync_peers (
+  peer text not null primary key,
+  last_attempt_utc text,
+  last_success_utc text,
+  last_error text
+);
+",
+    },
+    Migration {
+        version: 4,
+        description: "give tombstones without a deletion time the current time",
+        sql: "UPDATE deleted_tasks SET modified_utc = strftime('%Y-%m-%d %H:%M:%S', 'now')
+WHERE modified_utc IS NULL;
+",
+    },
+    Migration {
+        version: 5,
+        description: "track a change sequence for tasks and tombstones and sync cursors per peer",
+        sql: "CREATE TABLE change_sequence (
+  id integer primary key check (id = 0),
+  value integer not null
+);
+INSERT INTO change_sequence (id, value) VALUES (0, 1);
+ALTER TABLE tasks ADD COLUMN change_seq integer not null default 0;
+UPDATE tasks SET change_seq = 1;
+ALTER TABLE deleted_tasks ADD COLUMN change_seq integer not null default 0;
+UPDATE deleted_tasks SET change_seq = 1;
+ALTER TABLE sync_peers ADD COLUMN local_cursor integer not null default 0;
+ALTER TABLE sync_peers ADD COLUMN remote_cursor integer not null default 0;
+
+-- triggers so that every writer, including raw SQL, moves the sequence forward
+CREATE TRIGGER tasks_insert_change AFTER INSERT ON tasks BEGIN
+  UPDATE change_sequence SET value = value + 1;
+  UPDATE tasks SET change_seq = (SELECT value FROM change_sequence) WHERE ulid = NEW.ulid;
+END;
+CREATE TRIGGER tasks_update_change AFTER UPDATE ON tasks
+WHEN NEW.change_seq = OLD.change_seq BEGIN
+  UPDATE change_sequence SET value = value + 1;
+  UPDATE tasks SET change_seq = (SELECT value FROM change_sequence) WHERE ulid = NEW.ulid;
+END;
+CREATE TRIGGER task_to_tag_insert_change AFTER INSERT ON task_to_tag BEGIN
+  UPDATE change_sequence SET value = value + 1;
+  UPDATE tasks SET change_seq = (SELECT value FROM change_sequence) WHERE ulid = NEW.task_ulid;
+END;
+CREATE TRIGGER task_to_tag_delete_change AFTER DELETE ON task_to_tag BEGIN
+  UPDATE change_sequence SET value = value + 1;
+  UPDATE tasks SET change_seq = (SELECT value FROM change_sequence) WHERE ulid = OLD.task_ulid;
+END;
+CREATE TRIGGER deleted_tasks_insert_change AFTER INSERT ON deleted_tasks BEGIN
+  UPDATE change_sequence SET value = value + 1;
+  UPDATE deleted_tasks SET change_seq = (SELECT value FROM change_sequence)
+  WHERE task_ulid = NEW.task_ulid;
+END;
+CREATE TRIGGER deleted_tasks_update_change AFTER UPDATE ON deleted_tasks
+WHEN NEW.change_seq = OLD.change_seq BEGIN
+  UPDATE change_sequence SET value = value + 1;
+  UPDATE deleted_tasks SET change_seq = (SELECT value FROM change_sequence)
+  WHERE task_ulid = NEW.task_ulid;
+END;
+",
+    },
+    Migration {
+        version: 6,
+        description: "create sync_bases",
+        sql: "CREATE TABLE sync_bases (
+  peer text not null,
+  task_ulid text not null,
+  task text not null,
+  PRIMARY KEY (peer, task_ulid)
+);
+",
+    },
+    Migration {
+        version: 7,
+        description: "create sync_conflicts",
+        sql: "CREATE TABLE sync_conflicts (
+  task_ulid text not null primary key,
+  peer text not null,
+  fields text not null,
+  local_task text not null,
+  remote_task text not null,
+  detected_utc text not null
+);
+",
+    },
+    Migration {
+        version: 8,
+        description: "create task_events",
+        sql: "CREATE TABLE task_events (
+  id integer primary key autoincrement,
+  task_ulid text not null,
+  kind text not null,
+  before_task text,
+  after_task text,
+  recorded_utc text not null
+);
+CREATE INDEX task_events_task_ulid ON task_events (task_ulid);
+",
+    },
+    Migration {
+        version: 9,
+        description: "create operations",
+        sql: "CREATE TABLE operations (
+  id integer primary key autoincrement,
+  command text not null,
+  events text not null,
+  recorded_utc text not null
+);
+",
+    },
+    Migration {
+        version: 10,
+        description: "keep deleted tasks in deleted_tasks.task as a trash",
+        sql: "ALTER TABLE deleted_tasks ADD COLUMN task text;
+
+-- emptying the trash doesn't change the tombstone so it isn't a change to sync
+DROP TRIGGER deleted_tasks_update_change;
+CREATE TRIGGER deleted_tasks_update_change
+AFTER UPDATE OF task_ulid, modified_utc ON deleted_tasks
+WHEN NEW.change_seq = OLD.change_seq BEGIN
+  UPDATE change_sequence SET value = value + 1;
+  UPDATE deleted_tasks SET change_seq = (SELECT value FROM change_sequence)
+  WHERE task_ulid = NEW.task_ulid;
+END;
+",
+    },
+    Migration {
+        version: 11,
+        description: "create tasks_fts for full-text search, kept current by triggers",
+        sql: "CREATE VIRTUAL TABLE tasks_fts USING fts5(ulid UNINDEXED, body, tags, metadata);
+INSERT INTO tasks_fts (ulid, body, tags, metadata)
+SELECT ulid, body,
+  (SELECT group_concat(tag, ' ') FROM task_to_tag WHERE task_ulid = tasks.ulid), metadata
+FROM tasks;
+
+CREATE TRIGGER tasks_fts_insert AFTER INSERT ON tasks BEGIN
+  INSERT INTO tasks_fts (ulid, body, metadata) VALUES (NEW.ulid, NEW.body, NEW.metadata);
+END;
+CREATE TRIGGER tasks_fts_update AFTER UPDATE OF body, metadata ON tasks BEGIN
+  UPDATE tasks_fts SET body = NEW.body, metadata = NEW.metadata WHERE ulid = NEW.ulid;
+END;
+CREATE TRIGGER tasks_fts_delete AFTER DELETE ON tasks BEGIN
+  DELETE FROM tasks_fts WHERE ulid = OLD.ulid;
+END;
+CREATE TRIGGER task_to_tag_fts_insert AFTER INSERT ON task_to_tag BEGIN
+  UPDATE tasks_fts SET tags =
+    (SELECT group_concat(tag, ' ') FROM task_to_tag WHERE task_ulid = NEW.task_ulid)
+  WHERE ulid = NEW.task_ulid;
+END;
+CREATE TRIGGER task_to_tag_fts_delete AFTER DELETE ON task_to_tag BEGIN
+  UPDATE tasks_fts SET tags =
+    (SELECT group_concat(tag, ' ') FROM task_to_tag WHERE task_ulid = OLD.task_ulid)
+  WHERE ulid = OLD.task_ulid;
+END;
+",
+    },
+    Migration {
+        version: 12,
+        description: "add tasks.recurrence_rule for RRULE recurrence",
+        sql: "ALTER TABLE tasks ADD COLUMN recurrence_rule text;",
+    },
+    Migration {
+        version: 13,
+        description: "add tasks.recurrence_mode",
+        sql: "ALTER TABLE tasks ADD COLUMN recurrence_mode text;",
+    },
+    Migration {
+        version: 14,
+        description: "add tasks.ready_lead to keep ready_utc relative to due_utc",
+        sql: "ALTER TABLE tasks ADD COLUMN ready_lead text;",
+    },
+    Migration {
+        version: 15,
+        description: "add tasks.recurrence_until, recurrence_count and recurrence_occurrence",
+        sql: "ALTER TABLE tasks ADD COLUMN recurrence_until text;
+ALTER TABLE tasks ADD COLUMN recurrence_count integer;
+ALTER TABLE tasks ADD COLUMN recurrence_occurrence integer;",
+    },
+    Migration {
+        version: 16,
+        description: "key tasks_fts rows by the rowid of their task",
+        sql: "DROP TRIGGER tasks_fts_insert;
+DROP TRIGGER tasks_fts_update;
+DROP TRIGGER tasks_fts_delete;
+DROP TRIGGER task_to_tag_fts_insert;
+DROP TRIGGER task_to_tag_fts_delete;
+DELETE FROM tasks_fts;
+-- looking rows up by the unindexed ulid scanned the whole index on every write
+INSERT INTO tasks_fts (rowid, ulid, body, tags, metadata)
+SELECT rowid, ulid, body,
+  (SELECT group_concat(tag, ' ') FROM task_to_tag WHERE task_ulid = tasks.ulid), metadata
+FROM tasks;
+
+CREATE TRIGGER tasks_fts_insert AFTER INSERT ON tasks BEGIN
+  INSERT INTO tasks_fts (rowid, ulid, body, metadata)
+  VALUES (NEW.rowid, NEW.ulid, NEW.body, NEW.metadata);
+END;
+CREATE TRIGGER tasks_fts_update AFTER UPDATE OF body, metadata ON tasks BEGIN
+  UPDATE tasks_fts SET body = NEW.body, metadata = NEW.metadata WHERE rowid = NEW.rowid;
+END;
+CREATE TRIGGER tasks_fts_delete AFTER DELETE ON tasks BEGIN
+  DELETE FROM tasks_fts WHERE rowid = OLD.rowid;
+END;
+CREATE TRIGGER task_to_tag_fts_insert AFTER INSERT ON task_to_tag BEGIN
+  UPDATE tasks_fts SET tags =
+    (SELECT group_concat(tag, ' ') FROM task_to_tag WHERE task_ulid = NEW.task_ulid)
+  WHERE rowid = (SELECT rowid FROM tasks WHERE ulid = NEW.task_ulid);
+END;
+CREATE TRIGGER task_to_tag_fts_delete AFTER DELETE ON task_to_tag BEGIN
+  UPDATE tasks_fts SET tags =
+    (SELECT group_concat(tag, ' ') FROM task_to_tag WHERE task_ulid = OLD.task_ulid)
+  WHERE rowid = (SELECT rowid FROM tasks WHERE ulid = OLD.task_ulid);
+END;
+",
+    },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |x| x.version)
+}
+
+pub fn schema_version(connection: &Connection) -> anyhow::Result<u32> {
+    let version = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version)
+}
+
+/// Migrations that haven't been applied yet, fails if the database is newer than this binary
+pub fn pending(connection: &Connection) -> anyhow::Result<Vec<&'static Migration>> {
+    let version = schema_version(connection)?;
+    let supported = latest_version();
+    if version > supported {
+        return Err(MigrationError::NewerSchema {
+            found: version,
+            supported,
+        }
+        .into());
+    }
+    Ok(MIGRATIONS.iter().filter(|x| x.version > version).collect())
+}
+
+/// Applies pending migrations in order, each in its own transaction. Returns the applied
+/// migrations.
+pub fn migrate(connection: &Connection) -> anyhow::Result<Vec<&'static Migration>> {
+    let migrations = pending(connection)?;
+    for migration in &migrations {
+        let tx = connection.unchecked_transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+    Ok(migrations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as u32 + 1);
+        }
+    }
+
+    #[test]
+    fn fresh_database_is_migrated_to_latest() {
+        let connection = Connection::open_in_memory().unwrap();
+        assert_eq!(schema_version(&connection).unwrap(), 0);
+        let applied = migrate(&connection).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(schema_version(&connection).unwrap(), latest_version());
+        assert!(migrate(&connection).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unversioned_database_keeps_its_data() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(MIGRATIONS[0].sql).unwrap();
+        connection
+            .execute(
+                "INSERT INTO tasks (ulid, body) VALUES ('8vag', 'follow up wit')",
+                (),
+            )
+            .unwrap();
+        migrate(&connection).unwrap();
+        let count: usize = connection
+            .query_row("SELECT count(*) FROM tasks_view", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        let index_count: usize = connection
+            .query_row(
+                "SELECT count(*) FROM sqlite_master WHERE type='index' AND name='tasks_due_utc'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(index_count, 1);
+    }
+
+    #[test]
+    fn newer_database_is_rejected() {
+        let connection = Connection::open_in_memory().unwrap();
+        let newer = latest_version() + 1;
+        connection
+            .pragma_update(None, "user_version", newer)
+            .unwrap();
+        let err = migrate(&connection).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MigrationError>(),
+            Some(&MigrationError::NewerSchema {
+                found: newer,
+                supported: latest_version()
+            })
+        );
+    }
+}
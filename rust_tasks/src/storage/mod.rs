@@ -1,4 +1,14 @@
 // pub mod sqlite_storage;
 pub mod api_storage;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod crdt_storage;
+pub mod file_storage;
+pub mod filter;
+pub mod memory_storage;
+pub mod migrations;
 pub mod sqlite_storage;
+#[allow(clippy::module_inception)]
 pub mod storage;
+pub mod sync;
+pub mod text_search;
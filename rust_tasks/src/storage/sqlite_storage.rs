@@ -1,50 +1,27 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 
 use anyhow::bail;
-use chrono::{naive::NaiveDate, Duration, Local, Utc};
-use rusqlite::{params, Connection};
+use chrono::{DateTime, Local, Utc};
+use iso8601_duration::Duration;
+use rusqlite::{
+    params, params_from_iter,
+    types::{Type, Value},
+    Connection, OptionalExtension, Params, Row,
+};
 use ulid::Ulid;
 
-use crate::tasks::{summary::SummaryConfig, Task};
-
-use super::storage::{DaySummaryResult, TaskStorage};
-
-const CREATE_TASKS_TABLE_QUERY: &str = "CREATE TABLE IF NOT EXISTS tasks (
-  ulid text not null primary key,
-  body text not null,
-  modified_utc text,
-  ready_utc text,
-  due_utc text,
-  closed_utc text,
-  recurrence_duration text,
-  priority_adjustment float,
-  user text,
-  metadata text
-);
-";
-
-const CREATE_DELETED_TASKS_QUERY: &str = "CREATE TABLE IF NOT EXISTS deleted_tasks (
-  task_ulid text not null primary key,
-  modified_utc text
-);";
-
-const CREATE_TAGS_TABLE_QUERY: &str = "CREATE TABLE IF NOT EXISTS task_to_tag (
-    ulid TEXT NOT NULL PRIMARY KEY,
-    task_ulid TEXT NOT NULL,
-    tag text NOT NULL,
-    FOREIGN KEY(task_ulid) REFERENCES tasks(ulid),
-    CONSTRAINT no_duplicate_tags UNIQUE(task_ulid, tag)
-);
-";
-
-const CREATE_TASKS_VIEW: &str = "CREATE VIEW IF NOT EXISTS tasks_view AS
-SELECT 
-    tasks.*,
-    tasks.priority_adjustment AS priority,
-    group_concat(distinct task_to_tag.tag) AS tags
-FROM tasks LEFT JOIN task_to_tag ON tasks.ulid = task_to_tag.task_ulid
-GROUP BY tasks.ulid;
-";
+use crate::tasks::{priority::by_priority, summary::SummaryConfig, Task};
+
+use super::filter::{DateRange, TaskFilter, TaskOrder, TaskState};
+use super::migrations::{self, Migration};
+use super::storage::{
+    ChangeSet, DaySummaryResult, HealthCheck, HealthReport, Operation, SyncConflict, SyncState,
+    TaskBatch, TaskEvent, TaskEventKind, TaskStorage, Tombstone, TrashedTask, JOURNAL_LENGTH,
+};
+use super::text_search;
+
+/// kind, before_task, after_task and recorded_utc from task_events
+type EventRow = (String, Option<String>, Option<String>, DateTime<Utc>);
 
 pub struct SQLiteStorage {
     pub connection: Connection,
@@ -52,108 +29,137 @@ pub struct SQLiteStorage {
 
 impl TaskStorage for SQLiteStorage {
     fn save(&self, task: &Task) -> anyhow::Result<()> {
-        let query = "INSERT INTO tasks (ulid, body, modified_utc, ready_utc, due_utc, closed_utc, recurrence_duration, priority_adjustment, user, metadata) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
-        let mut stmt = self.connection.prepare(query)?;
-        stmt.execute(params![
-            task.ulid,
-            task.body,
-            get_utc_now_db_str(),
-            task.ready_utc,
-            task.due_utc,
-            task.closed_utc,
-            task.recurrence_duration.map(|x| x.to_string()),
-            task.priority_adjustment,
-            task.user,
-            task.metadata,
-        ])?;
+        self.atomically(|| {
+            self.insert_task(task, Some(get_utc_now_db_str()))?;
+            let after = self.find_task(&task.ulid)?;
+            self.record_event(TaskEventKind::Save, None, after)
+        })
+    }
 
-        let tags_query = "INSERT INTO task_to_tag (ulid, task_ulid, tag) VALUES (?, ?, ?) ON CONFLICT DO NOTHING";
-        let mut stmt = self.connection.prepare(tags_query)?;
-        if let Some(tags) = &task.tags {
-            tags.iter().for_each(|x| {
-                stmt.execute(params![
-                    Ulid::new().to_string().to_lowercase(),
+    fn delete(&self, task: &Task) -> anyhow::Result<()> {
+        self.atomically(|| {
+            let Some(before) = self.find_task(&task.ulid)? else {
+                bail!("Task with ulid: {} doesn't exist", task.ulid);
+            };
+            self.remove_task_rows(&task.ulid)?;
+            self.connection.execute(
+                "INSERT INTO deleted_tasks (task_ulid, modified_utc, task) VALUES (?, ?, ?)
+                ON CONFLICT (task_ulid) DO UPDATE SET
+                modified_utc = excluded.modified_utc, task = excluded.task",
+                params![
                     task.ulid,
-                    x
-                ])
-                .unwrap();
+                    get_utc_now_db_str(),
+                    serde_json::to_string(&before)?
+                ],
+            )?;
+            self.record_event(TaskEventKind::Delete, Some(before), None)
+        })
+    }
+
+    fn update(&self, task: &Task) -> anyhow::Result<()> {
+        self.atomically(|| {
+            let Some(before) = self.find_task(&task.ulid)? else {
+                return Ok(());
+            };
+            let query = r#"UPDATE tasks SET 
+                body = ?, modified_utc = ?, ready_utc = ?, due_utc = ?, closed_utc = ?,
+                recurrence_duration = ?, recurrence_rule = ?, recurrence_mode = ?, ready_lead = ?,
+                recurrence_until = ?, recurrence_count = ?, recurrence_occurrence = ?,
+                priority_adjustment = ?, user = ?, metadata =? WHERE ulid = ?;"#;
+            let mut stmt = self.connection.prepare(query)?;
+            stmt.execute(params![
+                task.body,
+                get_utc_now_db_str(),
+                task.ready_utc,
+                task.due_utc,
+                task.closed_utc,
+                task.recurrence_duration.map(|x| x.to_string()),
+                task.recurrence_rule.as_ref().map(|x| x.to_string()),
+                task.recurrence_mode.map(|x| x.to_string()),
+                task.ready_lead.map(|x| x.to_string()),
+                task.recurrence_until,
+                task.recurrence_count,
+                task.recurrence_occurrence,
+                task.priority_adjustment,
+                task.user,
+                task.metadata,
+                task.ulid,
+            ])?;
+            let drop_tags_query = "DELETE FROM task_to_tag WHERE task_ulid = ?";
+            self.connection
+                .prepare(drop_tags_query)?
+                .execute(params![task.ulid])?;
+            self.insert_tags(task)?;
+            let after = self.find_task(&task.ulid)?;
+            let kind = after.as_ref().map_or(TaskEventKind::Update, |x| {
+                TaskEventKind::of_update(&before, x)
             });
-        }
+            self.record_event(kind, Some(before), after)
+        })
+    }
 
-        Ok(())
+    fn import(&self, task: &Task) -> anyhow::Result<()> {
+        self.atomically(|| {
+            let before = self.find_task(&task.ulid)?;
+            if before.as_ref().is_some_and(|x| x.same_as(task)) {
+                return Ok(());
+            }
+            self.remove_task_rows(&task.ulid)?;
+            self.insert_task(task, task.modified_utc.as_ref().map(format_db_datetime))?;
+            self.connection.execute(
+                "DELETE FROM deleted_tasks WHERE task_ulid = ?",
+                params![task.ulid],
+            )?;
+            let after = self.find_task(&task.ulid)?;
+            self.record_event(TaskEventKind::Import, before, after)
+        })
     }
 
-    fn delete(&self, task: &Task) -> anyhow::Result<()> {
-        let cnts = self.count_tasks(&format!("ulid = '{}'", task.ulid));
-        if cnts == 0 {
-            bail!("Task with ulid: {} doesn't exist", task.ulid);
-        }
-        let query = "DELETE FROM tasks WHERE ulid = ?";
-        let mut stmt = self.connection.prepare(query)?;
-        stmt.execute(params![task.ulid])?;
-        let mut delete_insert_stmt = self
-            .connection
-            .prepare("INSERT INTO deleted_tasks (task_ulid, modified_utc) VALUES (?, ?)")?;
-        delete_insert_stmt.execute(params![task.ulid, get_utc_now_db_str()])?;
-        let drop_tags_query = "DELETE FROM task_to_tag WHERE task_ulid = ?";
-        self.connection
-            .prepare(drop_tags_query)?
-            .execute(params![task.ulid])?;
-        Ok(())
+    fn apply_batch(&self, batch: &TaskBatch) -> anyhow::Result<()> {
+        self.atomically(|| {
+            for task in &batch.save {
+                self.save(task)?;
+            }
+            for task in &batch.update {
+                self.update(task)?;
+            }
+            for task in &batch.import {
+                self.import(task)?;
+            }
+            for task in &batch.delete {
+                self.delete(task)?;
+            }
+            for tombstone in &batch.tombstones {
+                self.apply_tombstone(tombstone)?;
+            }
+            Ok(())
+        })
     }
 
-    fn update(&self, task: &Task) -> anyhow::Result<()> {
-        let query = r#"UPDATE tasks SET 
-            body = ?, modified_utc = ?, ready_utc = ?, due_utc = ?, closed_utc = ?,
-            recurrence_duration = ?, priority_adjustment = ?, user = ?, metadata =?
-            WHERE ulid = ?;"#;
-        let mut stmt = self.connection.prepare(query)?;
-        stmt.execute(params![
-            task.body,
-            get_utc_now_db_str(),
-            task.ready_utc,
-            task.due_utc,
-            task.closed_utc,
-            task.recurrence_duration.map(|x| x.to_string()),
-            task.priority_adjustment,
-            task.user,
-            task.metadata,
-            task.ulid,
-        ])?;
-        let drop_tags_query = "DELETE FROM task_to_tag WHERE task_ulid = ?";
-        self.connection
-            .prepare(drop_tags_query)?
-            .execute(params![task.ulid])?;
-        let tags_query = "INSERT INTO task_to_tag (ulid, task_ulid, tag) VALUES (?, ?, ?) ON CONFLICT DO NOTHING";
-        let mut stmt = self.connection.prepare(tags_query)?;
-        if let Some(tags) = &task.tags {
-            tags.iter().for_each(|x| {
-                stmt.execute(params![
-                    Ulid::new().to_string().to_lowercase(),
-                    task.ulid,
-                    x
-                ])
-                .unwrap();
-            });
-        }
-        Ok(())
+    fn transaction(&self, f: &mut dyn FnMut() -> anyhow::Result<()>) -> anyhow::Result<()> {
+        self.atomically(f)
     }
 
     fn search_using_ulid(&self, ulid: &str) -> anyhow::Result<Vec<Task>> {
-        let extra_sql_clause = format!("WHERE ulid LIKE '%{}'", ulid);
-        self.get_tasks(Some(&extra_sql_clause))
+        let escaped = ulid
+            .to_lowercase()
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        self.query_tasks(
+            r"WHERE lower(ulid) LIKE ? ESCAPE '\' OR lower(ulid) LIKE ? ESCAPE '\'",
+            params![format!("{escaped}%"), format!("%{escaped}")],
+        )
     }
 
     fn next_tasks(&self, number: usize) -> anyhow::Result<Vec<Task>> {
-        let extra_clause = format!(
+        let tasks = self.get_tasks(Some(
             r#"WHERE
                     DATE(due_utc) <= DATE('now') AND
                     closed_utc IS NULL AND
-                    (ready_utc IS NULL OR DATETIME('now') >= DATETIME(ready_utc))
-                ORDER BY due_utc ASC, priority DESC LIMIT {}"#,
-            number
-        );
-        self.get_tasks(Some(&extra_clause))
+                    (ready_utc IS NULL OR DATETIME('now') >= DATETIME(ready_utc))"#,
+        ))?;
+        Ok(by_priority(tasks, number))
     }
 
     fn summarize_day(&self, summary: &SummaryConfig) -> anyhow::Result<DaySummaryResult> {
@@ -180,119 +186,461 @@ impl TaskStorage for SQLiteStorage {
         })
     }
 
-    fn deleted_ulids(&self, n_days: &usize) -> anyhow::Result<HashSet<String>> {
-        let check_date = Utc::now().date_naive() - Duration::days(*n_days as i64);
-        let query = format!(
-            "SELECT task_ulid FROM deleted_tasks WHERE modified_utc > '{}' OR modified_utc IS NULL",
-            check_date
-        );
-        let mut stmt = self.connection.prepare(&query)?;
-        let ulids: HashSet<String> = stmt
-            .query_map([], |row| Ok(row.get(0)?))?
-            .map(|x| x.unwrap())
-            .collect();
-        Ok(ulids)
-    }
-
-    fn sync(&self, task_storage: &dyn TaskStorage, n_days: usize) -> anyhow::Result<()> {
-        let date = Utc::now().date_naive() - Duration::days(n_days as i64);
-        self.sync_deleted(task_storage, &n_days)?;
-        let updated_clause = format!("WHERE modified_utc > '{}' OR modified_utc IS NULL", date);
-        let self_tasks = self.unsafe_query(&updated_clause)?;
-        let other_tasks = task_storage.unsafe_query(&updated_clause)?;
-        let self_map = create_tasks_hashmap(self_tasks);
-        let other_map = create_tasks_hashmap(other_tasks);
-        let mut upstream_added = 0;
-        let mut local_updated = 0;
-        let mut upstream_updated = 0;
-        for k in self_map.keys() {
-            let self_task = self_map.get(k).unwrap(); // I'm sure this exists
-            let other_task = other_map.get(k);
-            match other_task {
-                None => {
-                    upstream_added += 1;
-                    if let Err(_e) = task_storage.save(self_task) {
-                        upstream_added -= 1;
-                        upstream_updated += 1;
-                        task_storage.update(self_task)?
-                    }
+    fn tombstones(&self) -> anyhow::Result<Vec<Tombstone>> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT task_ulid, modified_utc FROM deleted_tasks")?;
+        let tombstones = stmt
+            .query_map([], |row| {
+                Ok(Tombstone {
+                    ulid: row.get(0)?,
+                    deleted_utc: row.get(1)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(tombstones)
+    }
+
+    fn apply_tombstone(&self, tombstone: &Tombstone) -> anyhow::Result<()> {
+        self.atomically(|| {
+            let mut trashed = None;
+            if let Some(task) = self.find_task(&tombstone.ulid)? {
+                if task.modified_utc > Some(tombstone.deleted_utc) {
+                    // edited after it was deleted elsewhere so the edit wins
+                    return Ok(());
                 }
-                Some(other) => {
-                    if other != self_task {
-                        // FIXME! custom code to ensure all other fields are the same excluding the
-                        // modfied utc
-                        let other_clean = Task {
-                            modified_utc: None,
-                            ..other.clone()
-                        };
-                        let self_clean = Task {
-                            modified_utc: None,
-                            ..self_task.clone()
-                        };
-                        if self_clean != other_clean {
-                            if other.modified_utc > self_task.modified_utc {
-                                self.update(other)?;
-                                local_updated += 1;
-                            } else {
-                                task_storage.update(self_task)?;
-                                upstream_updated += 1;
-                            }
-                        }
-                    }
+                self.remove_task_rows(&task.ulid)?;
+                trashed = Some(serde_json::to_string(&task)?);
+                self.record_event(TaskEventKind::Delete, Some(task), None)?;
+            }
+            let recorded: Option<DateTime<Utc>> = self
+                .connection
+                .query_row(
+                    "SELECT modified_utc FROM deleted_tasks WHERE task_ulid = ?",
+                    params![tombstone.ulid],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .flatten();
+            if recorded >= Some(tombstone.deleted_utc) {
+                if let Some(trashed) = trashed {
+                    self.connection.execute(
+                        "UPDATE deleted_tasks SET task = ? WHERE task_ulid = ?",
+                        params![trashed, tombstone.ulid],
+                    )?;
                 }
+                return Ok(());
             }
+            self.connection.execute(
+                "INSERT INTO deleted_tasks (task_ulid, modified_utc, task) VALUES (?, ?, ?)
+                ON CONFLICT (task_ulid) DO UPDATE SET modified_utc = excluded.modified_utc,
+                task = COALESCE(excluded.task, deleted_tasks.task)",
+                params![
+                    tombstone.ulid,
+                    format_db_datetime(&tombstone.deleted_utc),
+                    trashed
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn purge_tombstones(&self, cursor: u64) -> anyhow::Result<usize> {
+        self.atomically(|| {
+            let purged = self.connection.execute(
+                "DELETE FROM deleted_tasks WHERE task IS NULL AND change_seq <= ?",
+                params![cursor],
+            )?;
+            // bases of deleted tasks are no longer needed either
+            self.connection.execute(
+                "DELETE FROM sync_bases WHERE task_ulid NOT IN (SELECT ulid FROM tasks)",
+                [],
+            )?;
+            Ok(purged)
+        })
+    }
+
+    fn trash(&self) -> anyhow::Result<Vec<TrashedTask>> {
+        let mut stmt = self.connection.prepare(
+            "SELECT task, modified_utc FROM deleted_tasks WHERE task IS NOT NULL
+            ORDER BY DATETIME(modified_utc) DESC, task_ulid DESC",
+        )?;
+        let rows: Vec<(String, DateTime<Utc>)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        rows.into_iter()
+            .map(|(task, deleted_utc)| {
+                Ok(TrashedTask {
+                    task: serde_json::from_str(&task)?,
+                    deleted_utc,
+                })
+            })
+            .collect()
+    }
+
+    fn purge_trash(&self, before: &DateTime<Utc>) -> anyhow::Result<usize> {
+        // the tombstone stays until purge_tombstones so sync keeps the task deleted
+        Ok(self.connection.execute(
+            "UPDATE deleted_tasks SET task = NULL
+            WHERE task IS NOT NULL AND DATETIME(modified_utc) < DATETIME(?)",
+            params![format_db_datetime(before)],
+        )?)
+    }
+
+    fn query(&self, filter: &TaskFilter) -> anyhow::Result<Vec<Task>> {
+        let (clause, values) = filter_to_sql(filter);
+        self.query_tasks(&clause, params_from_iter(values))
+    }
+
+    fn search_text(&self, query: &str, limit: usize) -> anyhow::Result<Vec<Task>> {
+        let Some(fts5_query) = text_search::fts5_query(query) else {
+            return Ok(vec![]);
+        };
+        // the unindexed ulid column gets a weight too
+        let mut stmt = self.connection.prepare(
+            "SELECT ulid FROM tasks_fts WHERE tasks_fts MATCH ?
+            ORDER BY bm25(tasks_fts, 0.0, ?, ?, ?), ulid DESC LIMIT ?",
+        )?;
+        let ulids: Vec<String> = stmt
+            .query_map(
+                params![
+                    fts5_query,
+                    text_search::BODY_WEIGHT,
+                    text_search::TAGS_WEIGHT,
+                    text_search::METADATA_WEIGHT,
+                    limit
+                ],
+                |row| row.get(0),
+            )?
+            .collect::<Result<_, _>>()?;
+        let mut tasks = vec![];
+        for ulid in ulids {
+            tasks.extend(self.find_task(&ulid)?);
         }
+        Ok(tasks)
+    }
 
-        let mut local_added = 0;
-        for k in other_map.keys() {
-            if !self_map.contains_key(k) {
-                local_added += 1;
-                let other_task = other_map.get(k).unwrap();
-                if let Err(_e) = self.save(other_task) {
-                    local_added -= 1;
-                    local_updated += 1;
-                    self.update(other_task)?
-                };
-            }
+    fn health(&self) -> anyhow::Result<HealthReport> {
+        let mut checks = vec![];
+
+        let mut stmt = self.connection.prepare("PRAGMA integrity_check")?;
+        let problems: Vec<String> = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        checks.push(match problems.as_slice() {
+            [ok] if ok == "ok" => HealthCheck::ok("integrity_check", "ok"),
+            _ => HealthCheck::degraded("integrity_check", problems.join("\n")),
+        });
+
+        let version = migrations::schema_version(&self.connection)?;
+        let latest = migrations::latest_version();
+        let detail = format!("version {version}, latest {latest}");
+        checks.push(match version == latest {
+            true => HealthCheck::ok("schema_version", detail),
+            false => HealthCheck::degraded("schema_version", detail),
+        });
+
+        let mut counts = vec![];
+        for table in ["tasks", "task_to_tag", "deleted_tasks"] {
+            let count: usize =
+                self.connection
+                    .query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+                        row.get(0)
+                    })?;
+            counts.push(format!("{table}: {count}"));
         }
-        println!(
-            "Successful sync: \n added {} and updated {} tasks to self\n added {} and updated {} tasks",
-            local_added, local_updated,
-            upstream_added, upstream_updated
-        );
+        checks.push(HealthCheck::ok("row_counts", counts.join(", ")));
+
+        let orphans: usize = self.connection.query_row(
+            "SELECT count(*) FROM task_to_tag WHERE task_ulid NOT IN (SELECT ulid FROM tasks)",
+            [],
+            |row| row.get(0),
+        )?;
+        let detail = format!("{orphans} task_to_tag rows without a task");
+        checks.push(match orphans {
+            0 => HealthCheck::ok("orphaned_tags", detail),
+            _ => HealthCheck::degraded("orphaned_tags", detail),
+        });
+
+        Ok(HealthReport::new(checks))
+    }
+
+    fn sync_state(&self, peer: &str) -> anyhow::Result<SyncState> {
+        let state = self
+            .connection
+            .query_row(
+                r#"SELECT last_attempt_utc, last_success_utc, last_error, local_cursor, remote_cursor
+                FROM sync_peers WHERE peer = ?"#,
+                params![peer],
+                |row| {
+                    Ok(SyncState {
+                        last_attempt_utc: row.get(0)?,
+                        last_success_utc: row.get(1)?,
+                        last_error: row.get(2)?,
+                        local_cursor: row.get(3)?,
+                        remote_cursor: row.get(4)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(state.unwrap_or_default())
+    }
+
+    fn save_sync_state(&self, peer: &str, state: &SyncState) -> anyhow::Result<()> {
+        let query = r#"INSERT INTO sync_peers
+            (peer, last_attempt_utc, last_success_utc, last_error, local_cursor, remote_cursor)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (peer) DO UPDATE SET
+            last_attempt_utc = excluded.last_attempt_utc,
+            last_success_utc = excluded.last_success_utc,
+            last_error = excluded.last_error,
+            local_cursor = excluded.local_cursor,
+            remote_cursor = excluded.remote_cursor"#;
+        self.connection.execute(
+            query,
+            params![
+                peer,
+                state.last_attempt_utc,
+                state.last_success_utc,
+                state.last_error,
+                state.local_cursor,
+                state.remote_cursor,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn sync_bases(&self, peer: &str) -> anyhow::Result<Vec<Task>> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT task FROM sync_bases WHERE peer = ?")?;
+        let bases: Vec<String> = stmt
+            .query_map(params![peer], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let tasks = bases
+            .iter()
+            .map(|x| serde_json::from_str(x))
+            .collect::<Result<_, _>>()?;
+        Ok(tasks)
+    }
+
+    fn save_sync_bases(&self, peer: &str, tasks: &[Task]) -> anyhow::Result<()> {
+        self.atomically(|| {
+            for task in tasks {
+                self.connection.execute(
+                    "INSERT OR REPLACE INTO sync_bases (peer, task_ulid, task) VALUES (?, ?, ?)",
+                    params![peer, task.ulid, serde_json::to_string(task)?],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn conflicts(&self) -> anyhow::Result<Vec<SyncConflict>> {
+        let mut stmt = self.connection.prepare(
+            r#"SELECT task_ulid, peer, fields, local_task, remote_task, detected_utc
+            FROM sync_conflicts ORDER BY detected_utc"#,
+        )?;
+        let rows: Vec<(String, String, String, String, String, DateTime<Utc>)> = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        rows.into_iter()
+            .map(|(ulid, peer, fields, local, remote, detected_utc)| {
+                Ok(SyncConflict {
+                    ulid,
+                    peer,
+                    fields: serde_json::from_str(&fields)?,
+                    local: serde_json::from_str(&local)?,
+                    remote: serde_json::from_str(&remote)?,
+                    detected_utc,
+                })
+            })
+            .collect()
+    }
+
+    fn save_conflict(&self, conflict: &SyncConflict) -> anyhow::Result<()> {
+        self.connection.execute(
+            r#"INSERT OR REPLACE INTO sync_conflicts
+            (task_ulid, peer, fields, local_task, remote_task, detected_utc)
+            VALUES (?, ?, ?, ?, ?, ?)"#,
+            params![
+                conflict.ulid,
+                conflict.peer,
+                serde_json::to_string(&conflict.fields)?,
+                serde_json::to_string(&conflict.local)?,
+                serde_json::to_string(&conflict.remote)?,
+                conflict.detected_utc,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove_conflict(&self, ulid: &str) -> anyhow::Result<()> {
+        self.connection.execute(
+            "DELETE FROM sync_conflicts WHERE task_ulid = ?",
+            params![ulid],
+        )?;
         Ok(())
     }
 
-    fn unsafe_query(&self, clause: &str) -> anyhow::Result<Vec<Task>> {
-        self.get_tasks(Some(clause))
+    fn history(&self, ulid: &str) -> anyhow::Result<Vec<TaskEvent>> {
+        let mut stmt = self.connection.prepare(
+            r#"SELECT kind, before_task, after_task, recorded_utc
+            FROM task_events WHERE task_ulid = ? ORDER BY id"#,
+        )?;
+        let rows: Vec<EventRow> = stmt
+            .query_map(params![ulid], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        rows.into_iter()
+            .map(|(kind, before, after, recorded_utc)| {
+                Ok(TaskEvent {
+                    ulid: ulid.to_string(),
+                    kind: kind.parse()?,
+                    before: before.map(|x| serde_json::from_str(&x)).transpose()?,
+                    after: after.map(|x| serde_json::from_str(&x)).transpose()?,
+                    recorded_utc,
+                })
+            })
+            .collect()
+    }
+
+    fn push_operation(&self, operation: &Operation) -> anyhow::Result<()> {
+        self.atomically(|| {
+            self.connection.execute(
+                "INSERT INTO operations (command, events, recorded_utc) VALUES (?, ?, ?)",
+                params![
+                    operation.command,
+                    serde_json::to_string(&operation.events)?,
+                    operation.recorded_utc,
+                ],
+            )?;
+            self.connection.execute(
+                "DELETE FROM operations WHERE id NOT IN
+                (SELECT id FROM operations ORDER BY id DESC LIMIT ?)",
+                params![JOURNAL_LENGTH],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn pop_operation(&self) -> anyhow::Result<Option<Operation>> {
+        self.atomically(|| {
+            let row: Option<(i64, String, String, DateTime<Utc>)> = self
+                .connection
+                .query_row(
+                    "SELECT id, command, events, recorded_utc FROM operations
+                    ORDER BY id DESC LIMIT 1",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+                .optional()?;
+            let Some((id, command, events, recorded_utc)) = row else {
+                return Ok(None);
+            };
+            self.connection
+                .execute("DELETE FROM operations WHERE id = ?", params![id])?;
+            Ok(Some(Operation {
+                command,
+                events: serde_json::from_str(&events)?,
+                recorded_utc,
+            }))
+        })
+    }
+
+    fn changes_since(&self, cursor: u64) -> anyhow::Result<ChangeSet> {
+        // read everything in one transaction so the cursor matches the rows
+        let (head, tasks, tombstones) = self.atomically(|| {
+            let head: u64 =
+                self.connection
+                    .query_row("SELECT value FROM change_sequence", [], |row| row.get(0))?;
+            let tasks = self.query_tasks(
+                "WHERE change_seq > ? AND change_seq <= ?",
+                params![cursor, head],
+            )?;
+            let mut stmt = self.connection.prepare(
+                "SELECT task_ulid, modified_utc FROM deleted_tasks WHERE change_seq > ? AND change_seq <= ?",
+            )?;
+            let tombstones = stmt
+                .query_map(params![cursor, head], |row| {
+                    Ok(Tombstone {
+                        ulid: row.get(0)?,
+                        deleted_utc: row.get(1)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok((head, tasks, tombstones))
+        })?;
+        Ok(ChangeSet {
+            tasks,
+            tombstones,
+            cursor: head,
+        })
     }
 }
 
 impl SQLiteStorage {
+    /// Runs `f` in a savepoint that is rolled back if `f` fails. Savepoints nest, so atomic
+    /// writes can be grouped into larger atomic writes.
+    fn atomically<T>(&self, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        self.connection.execute_batch("SAVEPOINT task_storage")?;
+        match f() {
+            Ok(x) => {
+                self.connection.execute_batch("RELEASE task_storage")?;
+                Ok(x)
+            }
+            Err(e) => {
+                self.connection
+                    .execute_batch("ROLLBACK TO task_storage; RELEASE task_storage")?;
+                Err(e)
+            }
+        }
+    }
+
     pub fn new(db_path: &str) -> Self {
-        let sql_storage = SQLiteStorage {
-            connection: Connection::open(db_path).unwrap(),
-        };
-        sql_storage.create_tasks_table().unwrap();
-        sql_storage
+        Self::open(db_path).unwrap()
     }
 
-    pub fn create_tasks_table(&self) -> anyhow::Result<()> {
-        self.connection.execute(CREATE_TASKS_TABLE_QUERY, ())?;
-        self.connection.execute(CREATE_DELETED_TASKS_QUERY, ())?;
-        self.connection.execute(CREATE_TAGS_TABLE_QUERY, ())?;
-        self.connection.execute(CREATE_TASKS_VIEW, ())?;
-        Ok(())
+    /// Opens the database and applies any pending migrations
+    pub fn open(db_path: &str) -> anyhow::Result<Self> {
+        let sql_storage = Self::open_unmigrated(db_path)?;
+        sql_storage.migrate()?;
+        Ok(sql_storage)
+    }
+
+    pub fn open_unmigrated(db_path: &str) -> anyhow::Result<Self> {
+        Ok(SQLiteStorage {
+            connection: Connection::open(db_path)?,
+        })
+    }
+
+    pub fn migrate(&self) -> anyhow::Result<Vec<&'static Migration>> {
+        migrations::migrate(&self.connection)
     }
 
     pub fn get_tasks(&self, extra_sql_clause: Option<&str>) -> anyhow::Result<Vec<Task>> {
-        let mut query = "SELECT ulid, body, modified_utc, ready_utc, due_utc, closed_utc, recurrence_duration, priority, user, metadata, tags FROM tasks_view".to_string();
-        if let Some(x) = extra_sql_clause {
-            query = format!("{query} {x}")
-        }
+        self.query_tasks(extra_sql_clause.unwrap_or_default(), [])
+    }
+
+    fn query_tasks<P: Params>(
+        &self,
+        extra_sql_clause: &str,
+        params: P,
+    ) -> anyhow::Result<Vec<Task>> {
+        let query = format!("SELECT ulid, body, modified_utc, ready_utc, due_utc, closed_utc, recurrence_duration, priority, user, metadata, tags, recurrence_rule, recurrence_mode, ready_lead, recurrence_until, recurrence_count, recurrence_occurrence FROM tasks_view {extra_sql_clause}");
         let mut stmt = self.connection.prepare(&query)?;
         let tasks: Vec<Task> = stmt
-            .query_map([], |row| {
+            .query_map(params, |row| {
                 Ok(Task {
                     ulid: row.get(0)?,
                     body: row.get(1)?,
@@ -300,25 +648,111 @@ impl SQLiteStorage {
                     ready_utc: row.get(3)?,
                     due_utc: row.get(4)?,
                     closed_utc: row.get(5)?,
-                    recurrence_duration: {
-                        let recur: Option<String> = row.get(6)?;
-                        recur.map(|x| x.parse().unwrap())
-                    },
-                    // filler value to stop weird increments
-                    priority_adjustment: None,
+                    recurrence_duration: parse_column(row, 6, parse_duration)?,
+                    recurrence_rule: parse_column(row, 11, str::parse)?,
+                    recurrence_mode: parse_column(row, 12, str::parse)?,
+                    ready_lead: parse_column(row, 13, parse_duration)?,
+                    recurrence_until: row.get(14)?,
+                    recurrence_count: row.get(15)?,
+                    recurrence_occurrence: row.get(16)?,
+                    priority_adjustment: row.get(7)?,
                     user: row.get(8)?,
                     metadata: row.get(9)?,
                     tags: {
                         let tags: Option<String> = row.get(10)?;
-                        tags.map(|x| x.split(',').map(|x| x.to_string()).collect::<Vec<String>>())
+                        tags.map(|x| {
+                            let mut tags: Vec<String> =
+                                x.split(',').map(|x| x.to_string()).collect();
+                            tags.sort();
+                            tags
+                        })
                     },
                 })
             })?
-            .map(|x| x.unwrap())
-            .collect();
+            .collect::<Result<_, _>>()?;
         Ok(tasks)
     }
 
+    fn insert_task(&self, task: &Task, modified_utc: Option<String>) -> anyhow::Result<()> {
+        let query = "INSERT INTO tasks (ulid, body, modified_utc, ready_utc, due_utc, closed_utc, recurrence_duration, recurrence_rule, recurrence_mode, ready_lead, recurrence_until, recurrence_count, recurrence_occurrence, priority_adjustment, user, metadata) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        let mut stmt = self.connection.prepare(query)?;
+        stmt.execute(params![
+            task.ulid,
+            task.body,
+            modified_utc,
+            task.ready_utc,
+            task.due_utc,
+            task.closed_utc,
+            task.recurrence_duration.map(|x| x.to_string()),
+            task.recurrence_rule.as_ref().map(|x| x.to_string()),
+            task.recurrence_mode.map(|x| x.to_string()),
+            task.ready_lead.map(|x| x.to_string()),
+            task.recurrence_until,
+            task.recurrence_count,
+            task.recurrence_occurrence,
+            task.priority_adjustment,
+            task.user,
+            task.metadata,
+        ])?;
+        self.insert_tags(task)
+    }
+
+    fn insert_tags(&self, task: &Task) -> anyhow::Result<()> {
+        let tags_query = "INSERT INTO task_to_tag (ulid, task_ulid, tag) VALUES (?, ?, ?) ON CONFLICT DO NOTHING";
+        let mut stmt = self.connection.prepare(tags_query)?;
+        if let Some(tags) = &task.tags {
+            for tag in tags {
+                stmt.execute(params![
+                    Ulid::new().to_string().to_lowercase(),
+                    task.ulid,
+                    tag
+                ])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn find_task(&self, ulid: &str) -> anyhow::Result<Option<Task>> {
+        Ok(self.query_tasks("WHERE ulid = ?", params![ulid])?.pop())
+    }
+
+    fn record_event(
+        &self,
+        kind: TaskEventKind,
+        before: Option<Task>,
+        after: Option<Task>,
+    ) -> anyhow::Result<()> {
+        let event = TaskEvent::new(kind, before, after);
+        self.connection.execute(
+            r#"INSERT INTO task_events (task_ulid, kind, before_task, after_task, recorded_utc)
+            VALUES (?, ?, ?, ?, ?)"#,
+            params![
+                event.ulid,
+                event.kind.as_str(),
+                event
+                    .before
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?,
+                event
+                    .after
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?,
+                event.recorded_utc,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove_task_rows(&self, ulid: &str) -> anyhow::Result<()> {
+        self.connection
+            .execute("DELETE FROM tasks WHERE ulid = ?", params![ulid])?;
+        self.connection
+            .execute("DELETE FROM task_to_tag WHERE task_ulid = ?", params![ulid])?;
+        Ok(())
+    }
+
     fn count_tasks(&self, where_clause: &str) -> usize {
         // TODO: fix tasks to use tasks_view since it has tags or rather use a join instead if possible
         let query = format!("SELECT count(*) FROM tasks_view where {where_clause}");
@@ -328,54 +762,124 @@ impl SQLiteStorage {
             .expect("Failed to run query");
         count
     }
+}
 
-    fn sync_deleted(&self, task_storage: &dyn TaskStorage, n_days: &usize) -> anyhow::Result<()> {
-        let self_deleted = self.deleted_ulids(n_days)?;
-        let other_deleted = task_storage.deleted_ulids(n_days)?;
+fn get_utc_now_db_str() -> String {
+    format_db_datetime(&Local::now().naive_utc().and_utc())
+}
 
-        for self_task_ulid in &self_deleted {
-            if other_deleted.contains(self_task_ulid) {
-                continue;
-            }
-            let other_exists = task_storage.search_using_ulid(&self_task_ulid)?;
-            if other_exists.len() > 0 {
-                task_storage.delete(&other_exists[0])?;
-            }
-        }
+/// Parses a text column, a value that doesn't parse fails the row instead of panicking
+fn parse_column<T>(
+    row: &Row,
+    index: usize,
+    parse: impl Fn(&str) -> anyhow::Result<T>,
+) -> rusqlite::Result<Option<T>> {
+    let value: Option<String> = row.get(index)?;
+    value
+        .map(|x| {
+            parse(&x)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, e.into()))
+        })
+        .transpose()
+}
 
-        for other_task_ulid in &other_deleted {
-            if self_deleted.contains(other_task_ulid) {
-                continue;
-            }
-            let self_exists = self.search_using_ulid(&other_task_ulid)?;
-            if self_exists.len() > 0 {
-                self.delete(&self_exists[0])?;
-            }
-        }
-        Ok(())
-    }
+fn parse_duration(value: &str) -> anyhow::Result<Duration> {
+    value
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid duration {value}: {e:?}"))
 }
 
-fn get_utc_now_db_str() -> String {
-    Local::now()
-        .naive_utc()
-        .and_utc()
-        .format("%Y-%m-%d %H:%M:%S")
-        .to_string()
+fn format_db_datetime(datetime: &DateTime<Utc>) -> String {
+    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn db_datetime(datetime: &DateTime<Utc>) -> Value {
+    Value::Text(format_db_datetime(datetime))
 }
 
-fn create_tasks_hashmap(tasks: Vec<Task>) -> HashMap<String, Task> {
-    let mut map = HashMap::new();
-    tasks.iter().for_each(|x| {
-        map.insert(x.ulid.to_string(), x.clone());
-    });
-    map
+fn push_range(
+    column: &str,
+    range: &DateRange,
+    conditions: &mut Vec<String>,
+    values: &mut Vec<Value>,
+) {
+    if let Some(from) = &range.from {
+        conditions.push(format!("DATETIME({column}) >= DATETIME(?)"));
+        values.push(db_datetime(from));
+    }
+    if let Some(to) = &range.to {
+        conditions.push(format!("DATETIME({column}) < DATETIME(?)"));
+        values.push(db_datetime(to));
+    }
+}
+
+/// Translates a filter into a parameterized clause for tasks_view
+fn filter_to_sql(filter: &TaskFilter) -> (String, Vec<Value>) {
+    let mut conditions = vec![];
+    let mut values = vec![];
+    for tag in &filter.tags {
+        conditions.push(
+            "EXISTS (SELECT 1 FROM task_to_tag WHERE task_to_tag.task_ulid = tasks_view.ulid AND task_to_tag.tag = ?)"
+                .to_string(),
+        );
+        values.push(Value::Text(tag.clone()));
+    }
+    match filter.state {
+        Some(TaskState::Open) => conditions.push("closed_utc IS NULL".to_string()),
+        Some(TaskState::Closed) => conditions.push("closed_utc IS NOT NULL".to_string()),
+        None => (),
+    }
+    if let Some(range) = &filter.due {
+        push_range("due_utc", range, &mut conditions, &mut values);
+    }
+    if let Some(range) = &filter.ready {
+        push_range("ready_utc", range, &mut conditions, &mut values);
+    }
+    if let Some(range) = &filter.closed {
+        push_range("closed_utc", range, &mut conditions, &mut values);
+    }
+    if let Some(since) = &filter.modified_since {
+        conditions
+            .push("(DATETIME(modified_utc) > DATETIME(?) OR modified_utc IS NULL)".to_string());
+        values.push(db_datetime(since));
+    }
+    if let Some(body) = &filter.body_contains {
+        let escaped = body
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        conditions.push("body LIKE ? ESCAPE '\\'".to_string());
+        values.push(Value::Text(format!("%{escaped}%")));
+    }
+
+    let mut clause = String::new();
+    if !conditions.is_empty() {
+        clause = format!("WHERE {}", conditions.join(" AND "));
+    }
+    if let Some(order) = filter.order_by {
+        let order_clause = match order {
+            TaskOrder::DueAsc => "due_utc ASC",
+            TaskOrder::DueDesc => "due_utc DESC",
+            TaskOrder::ModifiedDesc => "modified_utc DESC",
+            TaskOrder::ClosedDesc => "closed_utc DESC",
+        };
+        clause = format!("{clause} ORDER BY {order_clause}");
+    }
+    if let Some(limit) = filter.limit {
+        clause = format!("{clause} LIMIT ?");
+        values.push(Value::Integer(limit as i64));
+    }
+    (clause, values)
 }
 
 #[cfg(test)]
 mod tests {
 
+    use chrono::NaiveDate;
+
     use super::*;
+    use crate::storage::conformance;
+    use crate::storage::storage::HealthStatus;
 
     fn get_sqlite_storage() -> SQLiteStorage {
         let sqlite_storage = SQLiteStorage::new(":memory:");
@@ -400,6 +904,11 @@ mod tests {
         sqlite_storage
     }
 
+    #[test]
+    fn sqlite_storage_conforms() {
+        conformance::run_all(&|| Box::new(SQLiteStorage::new(":memory:")));
+    }
+
     #[test]
     fn tasks_table_exists() {
         let count: u8 = get_sqlite_storage()
@@ -442,6 +951,148 @@ mod tests {
         assert_eq!(tasks.len(), 1);
     }
 
+    #[test]
+    fn fts_rows_share_the_rowid_of_their_task() {
+        let sqlite_storage = get_sqlite_storage();
+        let mut task = sqlite_storage.search_using_ulid("8vag").unwrap()[0].clone();
+        task.body = "follow up with the plumber".to_string();
+        task.tags = Some(vec!["home".to_string()]);
+        sqlite_storage.update(&task).unwrap();
+        let deleted = sqlite_storage.search_using_ulid("6715").unwrap()[0].clone();
+        sqlite_storage.delete(&deleted).unwrap();
+        sqlite_storage.save(&Task::default()).unwrap();
+
+        let linked: (usize, usize, usize) = sqlite_storage
+            .connection
+            .query_row(
+                "SELECT (SELECT count(*) FROM tasks), (SELECT count(*) FROM tasks_fts),
+                (SELECT count(*) FROM tasks_fts JOIN tasks ON tasks_fts.rowid = tasks.rowid
+                WHERE tasks_fts.ulid = tasks.ulid)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(linked, (10, 10, 10));
+        let found = sqlite_storage.search_text("plumber home", 10).unwrap();
+        assert_eq!(found[0].ulid, "8vag");
+    }
+
+    #[test]
+    fn unparsable_recurrence_is_an_error() {
+        let sqlite_storage = get_sqlite_storage();
+        sqlite_storage
+            .connection
+            .execute(
+                "UPDATE tasks SET recurrence_rule = 'FREQ=HOURLY' WHERE ulid = '8vag'",
+                [],
+            )
+            .unwrap();
+        let error = sqlite_storage.search_using_ulid("8vag").unwrap_err();
+        assert!(
+            error.to_string().contains("Unsupported FREQ HOURLY"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn query_filters_by_tag_and_state() {
+        let sqlite_storage = get_sqlite_storage();
+        let filter = TaskFilter {
+            tags: vec!["work".to_string(), "meeting".to_string()],
+            state: Some(TaskState::Open),
+            ..Default::default()
+        };
+        let tasks = sqlite_storage.query(&filter).unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].ulid, "8vag");
+
+        let filter = TaskFilter {
+            state: Some(TaskState::Closed),
+            ..Default::default()
+        };
+        assert_eq!(sqlite_storage.query(&filter).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn query_filters_by_due_range_with_order_and_limit() {
+        let sqlite_storage = get_sqlite_storage();
+        let date = NaiveDate::from_ymd_opt(2023, 8, 6).unwrap();
+        let filter = TaskFilter {
+            due: Some(DateRange::from_dates(Some(date), Some(date))),
+            order_by: Some(TaskOrder::DueDesc),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let tasks = sqlite_storage.query(&filter).unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].ulid, "d6bx");
+    }
+
+    #[test]
+    fn query_body_is_not_a_pattern() {
+        let sqlite_storage = get_sqlite_storage();
+        let filter = TaskFilter {
+            body_contains: Some("ROTATE".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(sqlite_storage.query(&filter).unwrap().len(), 1);
+        let filter = TaskFilter {
+            body_contains: Some("%' OR 1=1 --".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(sqlite_storage.query(&filter).unwrap().len(), 0);
+        let filter = TaskFilter {
+            body_contains: Some("_".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(sqlite_storage.query(&filter).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn health_reports_orphaned_tags() {
+        let sqlite_storage = get_sqlite_storage();
+        let report = sqlite_storage.health().unwrap();
+        assert_eq!(report.status, HealthStatus::Ok);
+        assert_eq!(report.checks.len(), 4);
+
+        sqlite_storage
+            .connection
+            .execute(
+                "INSERT INTO task_to_tag (ulid, task_ulid, tag) VALUES ('zzzz', 'gone', 'work')",
+                (),
+            )
+            .unwrap();
+        let report = sqlite_storage.health().unwrap();
+        assert_eq!(report.status, HealthStatus::Degraded);
+        let orphans = report
+            .checks
+            .iter()
+            .find(|x| x.name == "orphaned_tags")
+            .unwrap();
+        assert_eq!(orphans.status, HealthStatus::Degraded);
+    }
+
+    #[test]
+    fn sync_state_is_saved_per_peer() {
+        let sqlite_storage = get_sqlite_storage();
+        assert_eq!(
+            sqlite_storage.sync_state("laptop").unwrap(),
+            SyncState::default()
+        );
+        let state = SyncState {
+            last_attempt_utc: "2024-01-04T10:00:00Z".parse().ok(),
+            last_success_utc: None,
+            last_error: Some("unreachable".to_string()),
+            local_cursor: 3,
+            remote_cursor: 7,
+        };
+        sqlite_storage.save_sync_state("laptop", &state).unwrap();
+        sqlite_storage
+            .save_sync_state("phone", &SyncState::default())
+            .unwrap();
+        assert_eq!(sqlite_storage.sync_state("laptop").unwrap(), state);
+    }
+
     #[test]
     fn task_deleted() {
         let sqlite_storage = get_sqlite_storage();
@@ -457,70 +1108,134 @@ mod tests {
         assert_eq!(tasks.len(), 0);
         let new_open_tasks = sqlite_storage.count_tasks(count_query);
         assert_eq!(open_tasks, new_open_tasks + 1);
-        let deleted_tasks = sqlite_storage.deleted_ulids(&1).unwrap();
-        assert_eq!(deleted_tasks.len(), 1);
-        assert!(deleted_tasks.contains("6715"));
+        let tombstones = sqlite_storage.tombstones().unwrap();
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(tombstones[0].ulid, "6715");
     }
 
     #[test]
-    fn task_updated() {
+    fn tombstone_beats_older_edits_only() {
         let sqlite_storage = get_sqlite_storage();
-        let mut tasks = sqlite_storage.search_using_ulid("6715").unwrap();
-        let expected_task = &mut tasks[0];
-        expected_task.body = "updated task".to_string();
-        sqlite_storage.update(expected_task).unwrap();
-        let tasks = sqlite_storage.search_using_ulid("6715").unwrap();
-        assert_eq!(tasks[0].body, "updated task".to_string());
+        // d6bx was modified on 2023-08-05
+        let older = Tombstone {
+            ulid: "d6bx".to_string(),
+            deleted_utc: "2023-08-01T00:00:00Z".parse().unwrap(),
+        };
+        sqlite_storage.apply_tombstone(&older).unwrap();
+        assert_eq!(sqlite_storage.search_using_ulid("d6bx").unwrap().len(), 1);
+        assert!(sqlite_storage.tombstones().unwrap().is_empty());
+
+        let newer = Tombstone {
+            deleted_utc: "2023-08-06T00:00:00Z".parse().unwrap(),
+            ..older
+        };
+        sqlite_storage.apply_tombstone(&newer).unwrap();
+        assert_eq!(sqlite_storage.search_using_ulid("d6bx").unwrap().len(), 0);
+        assert_eq!(sqlite_storage.tombstones().unwrap(), vec![newer.clone()]);
+
+        // tombstones for tasks we never had are kept so they can be passed on
+        let unknown = Tombstone {
+            ulid: "zzzz".to_string(),
+            ..newer
+        };
+        sqlite_storage.apply_tombstone(&unknown).unwrap();
+        assert_eq!(sqlite_storage.tombstones().unwrap().len(), 2);
+
+        let cursor = sqlite_storage.changes_since(0).unwrap().cursor;
+        let purged = sqlite_storage.purge_tombstones(cursor).unwrap();
+        assert_eq!(purged, 1, "d6bx is still in the trash");
+        assert_eq!(sqlite_storage.trash().unwrap()[0].task.ulid, "d6bx");
     }
 
     #[test]
-    fn test_sync() {
-        let storage1 = get_sqlite_storage();
-        let storage2 = get_sqlite_storage();
-        let count_query = r#"
-            (DATE(due_utc) <= DATE('now') AND DATE(closed_utc) IS NULL)
-            OR DATE(closed_utc) = DATE('now')
-        "#;
-        let original_tasks_count = storage1.count_tasks(count_query);
-        let mut tasks = storage1.search_using_ulid("6715").unwrap();
-        let expected_task = &mut tasks[0];
-        expected_task.body = "random updated task".to_string();
-        storage1.update(expected_task).unwrap();
-        let new_task1 = Task::default();
-        storage1.save(&new_task1).unwrap();
-        let task = storage1.search_using_ulid("3akq").unwrap();
-        storage1.delete(&task[0]).unwrap();
-
-        let new_task2 = Task::default();
-        storage2.save(&new_task2).unwrap();
-        let mut tasks = storage2.search_using_ulid("h2td").unwrap();
-        let task2 = &mut tasks[0];
-        task2.body = "random mess".to_string();
-        storage2.update(task2).unwrap();
-
-        storage1.sync(&storage2, 2).unwrap();
-        let tasks = storage2.search_using_ulid("6715").unwrap();
-        assert_eq!(tasks[0].body, "random updated task".to_string());
-        let tasks2 = storage1.search_using_ulid("h2td").unwrap();
-
-        assert_eq!(tasks2[0].body, "random mess");
-
+    fn import_keeps_modified_utc_and_clears_tombstone() {
+        let sqlite_storage = get_sqlite_storage();
+        let mut task = sqlite_storage.search_using_ulid("6715").unwrap()[0].clone();
+        sqlite_storage.delete(&task).unwrap();
+        task.modified_utc = "2024-01-04T10:00:00Z".parse().ok();
+        task.tags = Some(vec!["work".to_string()]);
+        sqlite_storage.import(&task).unwrap();
         assert_eq!(
-            storage2.search_using_ulid(&new_task1.ulid).unwrap().len(),
-            1
+            sqlite_storage.search_using_ulid("6715").unwrap(),
+            vec![task]
         );
+        assert!(sqlite_storage.tombstones().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_writes_are_rolled_back() {
+        let sqlite_storage = SQLiteStorage::new(":memory:");
+        sqlite_storage
+            .connection
+            .execute_batch(
+                "CREATE TRIGGER no_boom BEFORE INSERT ON task_to_tag WHEN NEW.tag = 'boom'
+                BEGIN SELECT RAISE(ABORT, 'boom'); END;",
+            )
+            .unwrap();
+        let task = Task {
+            tags: Some(vec!["fine".to_string(), "boom".to_string()]),
+            ..Default::default()
+        };
+        assert!(sqlite_storage.save(&task).is_err());
+        assert_eq!(sqlite_storage.count_tasks("1 = 1"), 0);
 
+        let saved = Task::default();
+        let result = sqlite_storage.transaction(&mut || {
+            sqlite_storage.save(&saved)?;
+            sqlite_storage.save(&task)
+        });
+        assert!(result.is_err());
+        assert_eq!(sqlite_storage.count_tasks("1 = 1"), 0);
+
+        sqlite_storage
+            .transaction(&mut || sqlite_storage.save(&saved))
+            .unwrap();
+        assert_eq!(sqlite_storage.count_tasks("1 = 1"), 1);
+    }
+
+    #[test]
+    fn changes_since_returns_later_writes() {
+        let sqlite_storage = get_sqlite_storage();
+        let all = sqlite_storage.changes_since(0).unwrap();
+        assert_eq!(all.tasks.len(), 10);
+        assert!(sqlite_storage
+            .changes_since(all.cursor)
+            .unwrap()
+            .tasks
+            .is_empty());
+
+        let mut task = sqlite_storage.search_using_ulid("6715").unwrap()[0].clone();
+        task.body = "updated task".to_string();
+        sqlite_storage.update(&task).unwrap();
+        let deleted = sqlite_storage.search_using_ulid("3akq").unwrap();
+        sqlite_storage.delete(&deleted[0]).unwrap();
+
+        let changes = sqlite_storage.changes_since(all.cursor).unwrap();
+        assert!(changes.cursor > all.cursor);
+        assert_eq!(changes.tasks.len(), 1);
+        assert_eq!(changes.tasks[0].body, "updated task");
+        assert_eq!(changes.tombstones.len(), 1);
+        assert_eq!(changes.tombstones[0].ulid, "3akq");
+
+        // importing an identical task isn't a change
+        sqlite_storage.import(&changes.tasks[0]).unwrap();
         assert_eq!(
-            storage1.search_using_ulid(&new_task2.ulid).unwrap().len(),
-            1
+            sqlite_storage.changes_since(changes.cursor).unwrap(),
+            ChangeSet {
+                cursor: changes.cursor,
+                ..Default::default()
+            }
         );
+    }
 
-        // deleted task doesn't exist anymore
-        let task = storage1.search_using_ulid("3akq").unwrap();
-        assert_eq!(task.len(), 0);
-        let task = storage2.search_using_ulid("3akq").unwrap();
-        assert_eq!(task.len(), 0);
-        assert_eq!(storage1.count_tasks(count_query), original_tasks_count - 1);
-        assert_eq!(storage2.count_tasks(count_query), original_tasks_count - 1);
+    #[test]
+    fn task_updated() {
+        let sqlite_storage = get_sqlite_storage();
+        let mut tasks = sqlite_storage.search_using_ulid("6715").unwrap();
+        let expected_task = &mut tasks[0];
+        expected_task.body = "updated task".to_string();
+        sqlite_storage.update(expected_task).unwrap();
+        let tasks = sqlite_storage.search_using_ulid("6715").unwrap();
+        assert_eq!(tasks[0].body, "updated task".to_string());
     }
 }
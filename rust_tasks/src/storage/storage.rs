@@ -1,8 +1,11 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 
 use anyhow::Result;
+use chrono::{DateTime, SubsecRound, Utc};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
+use super::filter::TaskFilter;
 use crate::tasks::summary::SummaryConfig;
 use crate::tasks::Task;
 
@@ -13,15 +16,326 @@ pub struct DaySummaryResult {
     pub open_tags_count: Option<HashMap<String, usize>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Degraded,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub name: String,
+    pub status: HealthStatus,
+    pub detail: String,
+}
+
+impl HealthCheck {
+    pub fn ok(name: &str, detail: impl Into<String>) -> Self {
+        HealthCheck {
+            name: name.to_string(),
+            status: HealthStatus::Ok,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn degraded(name: &str, detail: impl Into<String>) -> Self {
+        HealthCheck {
+            name: name.to_string(),
+            status: HealthStatus::Degraded,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Backend specific diagnostics, degraded if any check is degraded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    pub fn new(checks: Vec<HealthCheck>) -> Self {
+        let status = match checks.iter().any(|x| x.status == HealthStatus::Degraded) {
+            true => HealthStatus::Degraded,
+            false => HealthStatus::Ok,
+        };
+        HealthReport { status, checks }
+    }
+}
+
+/// What a storage remembers about syncing with a peer
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SyncState {
+    pub last_attempt_utc: Option<DateTime<Utc>>,
+    pub last_success_utc: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    /// Change cursors reached in the last successful sync
+    #[serde(default)]
+    pub local_cursor: u64,
+    #[serde(default)]
+    pub remote_cursor: u64,
+}
+
+/// Tasks and tombstones that changed after a cursor. `cursor` is the position to ask from next
+/// time.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ChangeSet {
+    pub tasks: Vec<Task>,
+    pub tombstones: Vec<Tombstone>,
+    pub cursor: u64,
+}
+
+/// Records that a task was deleted so that sync doesn't bring it back
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tombstone {
+    pub ulid: String,
+    pub deleted_utc: DateTime<Utc>,
+}
+
+/// A deleted task kept in full until the trash is purged, its tombstone stays until then
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrashedTask {
+    pub task: Task,
+    pub deleted_utc: DateTime<Utc>,
+}
+
+/// Why a ulid didn't pick out exactly one task
+#[derive(Error, Debug)]
+pub enum LookupError {
+    #[error("No task found with ulid: {0}")]
+    NotFound(String),
+    #[error("Expected 1 task but found {}:{}", .0.len(), list_tasks(.0))]
+    Ambiguous(Vec<Task>),
+    #[error(transparent)]
+    Storage(#[from] anyhow::Error),
+}
+
+fn list_tasks(tasks: &[Task]) -> String {
+    tasks
+        .iter()
+        .map(|x| format!("\n{}: {}", x.ulid, x.body))
+        .collect()
+}
+
+/// Whether `ulid` starts or ends with `fragment`, ignoring case
+pub fn ulid_matches(ulid: &str, fragment: &str) -> bool {
+    let ulid = ulid.to_lowercase();
+    let fragment = fragment.to_lowercase();
+    ulid.starts_with(&fragment) || ulid.ends_with(&fragment)
+}
+
+/// The task `fragment` picks out of `tasks`. A whole ulid wins over others it happens to be part
+/// of.
+pub fn resolve_ulid(
+    mut tasks: Vec<Task>,
+    fragment: &str,
+) -> std::result::Result<Task, LookupError> {
+    if let Some(index) = tasks
+        .iter()
+        .position(|x| x.ulid.eq_ignore_ascii_case(fragment))
+    {
+        return Ok(tasks.swap_remove(index));
+    }
+    match tasks.len() {
+        _ if fragment.is_empty() => Err(LookupError::NotFound(fragment.to_string())),
+        0 => Err(LookupError::NotFound(fragment.to_string())),
+        1 => Ok(tasks.remove(0)),
+        _ => Err(LookupError::Ambiguous(tasks)),
+    }
+}
+
+/// Writes sent to a storage together, applied in field order. Storages that can apply them
+/// atomically do.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct TaskBatch {
+    pub save: Vec<Task>,
+    pub update: Vec<Task>,
+    pub import: Vec<Task>,
+    pub delete: Vec<Task>,
+    pub tombstones: Vec<Tombstone>,
+}
+
+impl TaskBatch {
+    pub fn is_empty(&self) -> bool {
+        self.save.is_empty()
+            && self.update.is_empty()
+            && self.import.is_empty()
+            && self.delete.is_empty()
+            && self.tombstones.is_empty()
+    }
+}
+
+/// Both versions of a task whose edits conflicted during sync. Sync keeps the most recent edit
+/// until the conflict is resolved by hand.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncConflict {
+    pub ulid: String,
+    pub peer: String,
+    /// Names of the `Task` fields changed differently on both sides
+    pub fields: Vec<String>,
+    pub local: Task,
+    pub remote: Task,
+    pub detected_utc: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskEventKind {
+    Save,
+    Update,
+    /// An update that closed the task
+    Do,
+    Delete,
+    /// Written by sync or another import
+    Import,
+}
+
+impl TaskEventKind {
+    pub fn of_update(before: &Task, after: &Task) -> Self {
+        match (before.closed_utc, after.closed_utc) {
+            (None, Some(_)) => TaskEventKind::Do,
+            _ => TaskEventKind::Update,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskEventKind::Save => "save",
+            TaskEventKind::Update => "update",
+            TaskEventKind::Do => "do",
+            TaskEventKind::Delete => "delete",
+            TaskEventKind::Import => "import",
+        }
+    }
+}
+
+impl std::str::FromStr for TaskEventKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(serde_json::from_value(serde_json::Value::from(s))?)
+    }
+}
+
+/// A write to a task with the task as it was before and after, `None` when it didn't exist
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TaskEvent {
+    pub ulid: String,
+    pub kind: TaskEventKind,
+    pub before: Option<Task>,
+    pub after: Option<Task>,
+    pub recorded_utc: DateTime<Utc>,
+}
+
+impl TaskEvent {
+    pub fn new(kind: TaskEventKind, before: Option<Task>, after: Option<Task>) -> Self {
+        let ulid = before
+            .as_ref()
+            .or(after.as_ref())
+            .map(|x| x.ulid.clone())
+            .unwrap_or_default();
+        TaskEvent {
+            ulid,
+            kind,
+            before,
+            after,
+            recorded_utc: Utc::now().trunc_subsecs(0),
+        }
+    }
+}
+
+/// How many operations storages keep for `rust_tasks undo`
+pub const JOURNAL_LENGTH: usize = 100;
+
+/// The writes of one CLI command, journaled so `rust_tasks undo` can revert them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Operation {
+    pub command: String,
+    /// In the order they were written
+    pub events: Vec<TaskEvent>,
+    pub recorded_utc: DateTime<Utc>,
+}
+
+impl Operation {
+    pub fn new(command: &str, events: Vec<TaskEvent>) -> Self {
+        Operation {
+            command: command.to_string(),
+            events,
+            recorded_utc: Utc::now().trunc_subsecs(0),
+        }
+    }
+}
+
 pub trait TaskStorage {
     fn save(&self, task: &Task) -> Result<()>;
     fn delete(&self, task: &Task) -> Result<()>;
     fn update(&self, task: &Task) -> Result<()>;
+    /// Tasks whose ulid starts or ends with `ulid`, ignoring case
     fn search_using_ulid(&self, ulid: &str) -> Result<Vec<Task>>;
+    /// The one task whose ulid starts or ends with `ulid`
+    fn find_by_ulid(&self, ulid: &str) -> std::result::Result<Task, LookupError> {
+        resolve_ulid(self.search_using_ulid(ulid)?, ulid)
+    }
     fn next_tasks(&self, count: usize) -> Result<Vec<Task>>;
     fn summarize_day(&self, summary: &SummaryConfig) -> Result<DaySummaryResult>;
-    fn sync(&self, task_storage: &dyn TaskStorage, n_days: usize) -> Result<()>;
-    fn deleted_ulids(&self, n_days: &usize) -> Result<HashSet<String>>;
-    // FIXME! remove this method
-    fn unsafe_query(&self, clause: &str) -> Result<Vec<Task>>;
+    /// Inserts or replaces a task as is, keeping its modified_utc and dropping any tombstone.
+    /// Used when copying tasks between storages so it does nothing if the task is unchanged.
+    fn import(&self, task: &Task) -> Result<()>;
+    /// Applies the writes one by one, storages override this to save round trips or to make the
+    /// batch atomic
+    fn apply_batch(&self, batch: &TaskBatch) -> Result<()> {
+        batch.save.iter().try_for_each(|x| self.save(x))?;
+        batch.update.iter().try_for_each(|x| self.update(x))?;
+        batch.import.iter().try_for_each(|x| self.import(x))?;
+        batch.delete.iter().try_for_each(|x| self.delete(x))?;
+        batch
+            .tombstones
+            .iter()
+            .try_for_each(|x| self.apply_tombstone(x))
+    }
+    /// Runs `f` so that its writes are all kept or, if it fails, all rolled back. Storages that
+    /// can't roll back just run `f`.
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        f()
+    }
+    fn tombstones(&self) -> Result<Vec<Tombstone>>;
+    /// Records the tombstone and deletes the task unless it was modified after the deletion
+    fn apply_tombstone(&self, tombstone: &Tombstone) -> Result<()>;
+    /// Drops tombstones recorded up to the change `cursor`, returns how many were dropped.
+    /// Tombstones of tasks still in the trash are kept.
+    fn purge_tombstones(&self, cursor: u64) -> Result<usize>;
+    /// Tasks deleted locally or by sync, most recently deleted first. Importing a task takes it
+    /// out of the trash.
+    fn trash(&self) -> Result<Vec<TrashedTask>>;
+    /// Empties the trash of tasks deleted before `before`, returns how many were dropped
+    fn purge_trash(&self, before: &DateTime<Utc>) -> Result<usize>;
+    /// Tasks and tombstones written after `cursor`, which only ever increases. A cursor of 0
+    /// returns everything.
+    fn changes_since(&self, cursor: u64) -> Result<ChangeSet>;
+    fn query(&self, filter: &TaskFilter) -> Result<Vec<Task>>;
+    /// Up to `limit` tasks with every word of `query` in their body, tags or metadata, best match
+    /// first
+    fn search_text(&self, query: &str, limit: usize) -> Result<Vec<Task>>;
+    fn health(&self) -> Result<HealthReport>;
+    fn sync_state(&self, peer: &str) -> Result<SyncState>;
+    fn save_sync_state(&self, peer: &str, state: &SyncState) -> Result<()>;
+    /// Versions of tasks as they were after the last successful sync with a peer, the common
+    /// ancestor for merging
+    fn sync_bases(&self, peer: &str) -> Result<Vec<Task>>;
+    /// Inserts or replaces the base versions of these tasks
+    fn save_sync_bases(&self, peer: &str, tasks: &[Task]) -> Result<()>;
+    fn conflicts(&self) -> Result<Vec<SyncConflict>>;
+    /// Records a conflict, replacing any earlier one for the same task
+    fn save_conflict(&self, conflict: &SyncConflict) -> Result<()>;
+    fn remove_conflict(&self, ulid: &str) -> Result<()>;
+    /// Every recorded write to the task with this exact ulid, oldest first. Deleted tasks keep
+    /// their history.
+    fn history(&self, ulid: &str) -> Result<Vec<TaskEvent>>;
+    /// Appends to the operation journal, dropping the oldest beyond `JOURNAL_LENGTH`
+    fn push_operation(&self, operation: &Operation) -> Result<()>;
+    /// Removes and returns the most recent operation
+    fn pop_operation(&self) -> Result<Option<Operation>>;
 }
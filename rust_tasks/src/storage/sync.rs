@@ -0,0 +1,588 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::tasks::Task;
+
+use super::storage::{ChangeSet, SyncConflict, SyncState, TaskBatch, TaskStorage, Tombstone};
+
+/// What a sync wrote, or would write in a dry run
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SyncReport {
+    pub peer: String,
+    pub dry_run: bool,
+    /// Changes written to the local storage
+    pub local: SyncChanges,
+    /// Changes written to the peer
+    pub remote: SyncChanges,
+    /// Fields edited on both sides, the most recent edit was kept
+    pub conflicts: Vec<SyncConflict>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SyncChanges {
+    pub added: Vec<Task>,
+    pub updated: Vec<Task>,
+    /// Tombstones sent, the task may already be gone on this side
+    pub deleted: Vec<Tombstone>,
+}
+
+impl SyncChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.deleted.is_empty()
+    }
+
+    /// Writes the changes in a single batch
+    fn apply(&self, storage: &dyn TaskStorage) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        storage.apply_batch(&TaskBatch {
+            import: self.added.iter().chain(&self.updated).cloned().collect(),
+            tombstones: self.deleted.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Exchanges what changed on either storage since the cursors in `state`, and moves the cursors
+/// forward on success. Deletes beat older edits: tasks modified before a tombstone from the other
+/// side aren't sent, and applying a tombstone deletes the task unless it was modified after the
+/// deletion. Tasks changed on both sides are merged field by field against the version from the
+/// last sync with `peer`. Tasks are copied with `import` so their modified_utc survives any number
+/// of hops.
+///
+/// Everything is worked out before the first write, so a dry run only reads.
+pub fn sync(
+    local: &dyn TaskStorage,
+    remote: &dyn TaskStorage,
+    peer: &str,
+    state: &mut SyncState,
+    dry_run: bool,
+) -> Result<SyncReport> {
+    let local_changes = changes_since(local, state.local_cursor)?;
+    let remote_changes = changes_since(remote, state.remote_cursor)?;
+    let mut report = SyncReport {
+        peer: peer.to_string(),
+        dry_run,
+        ..Default::default()
+    };
+
+    for tombstone in &local_changes.tombstones {
+        if !remote_changes.tombstones.contains(tombstone) {
+            report.remote.deleted.push(tombstone.clone());
+        }
+    }
+    for tombstone in &remote_changes.tombstones {
+        if !local_changes.tombstones.contains(tombstone) {
+            report.local.deleted.push(tombstone.clone());
+        }
+    }
+
+    let local_map =
+        create_tasks_hashmap(surviving(local_changes.tasks, &remote_changes.tombstones));
+    let remote_map =
+        create_tasks_hashmap(surviving(remote_changes.tasks, &local_changes.tombstones));
+    let bases = create_tasks_hashmap(local.sync_bases(peer)?);
+    // our own writes from the last sync come back as changes, they match the base
+    let unchanged = |task: &Task| bases.get(&task.ulid).is_some_and(|x| x.same_as(task));
+    let mut new_bases = vec![];
+    for (k, local_task) in &local_map {
+        match remote_map.get(k) {
+            None if unchanged(local_task) => {}
+            // with a base the peer already has the task
+            None if bases.contains_key(k) => {
+                report.remote.updated.push(local_task.clone());
+                new_bases.push(local_task.clone());
+            }
+            None => {
+                report.remote.added.push(local_task.clone());
+                new_bases.push(local_task.clone());
+            }
+            Some(remote_task) => {
+                if remote_task.same_as(local_task) {
+                    new_bases.push(local_task.clone());
+                    continue;
+                }
+                let (merged, conflicting) = merge(bases.get(k), local_task, remote_task);
+                if !conflicting.is_empty() {
+                    report.conflicts.push(SyncConflict {
+                        ulid: k.clone(),
+                        peer: peer.to_string(),
+                        fields: conflicting.iter().map(|x| x.to_string()).collect(),
+                        local: local_task.clone(),
+                        remote: remote_task.clone(),
+                        detected_utc: Utc::now(),
+                    });
+                }
+                if !merged.same_as(local_task) {
+                    report.local.updated.push(merged.clone());
+                }
+                if !merged.same_as(remote_task) {
+                    report.remote.updated.push(merged.clone());
+                }
+                new_bases.push(merged);
+            }
+        }
+    }
+    for (k, remote_task) in &remote_map {
+        if local_map.contains_key(k) || unchanged(remote_task) {
+            continue;
+        }
+        match bases.contains_key(k) {
+            true => report.local.updated.push(remote_task.clone()),
+            false => report.local.added.push(remote_task.clone()),
+        }
+        new_bases.push(remote_task.clone());
+    }
+    if dry_run {
+        return Ok(report);
+    }
+
+    report.remote.apply(remote)?;
+    report.local.apply(local)?;
+    if !new_bases.is_empty() {
+        local.save_sync_bases(peer, &new_bases)?;
+    }
+    for conflict in &report.conflicts {
+        local.save_conflict(conflict)?;
+    }
+    state.local_cursor = local_changes.cursor;
+    state.remote_cursor = remote_changes.cursor;
+    Ok(report)
+}
+
+/// Tombstones up to a peer's local cursor have been read by that peer, so tombstones up to the
+/// lowest cursor are known to every peer. Tombstones a sync brings in come after the cursor and
+/// wait until every peer has synced again.
+pub fn acknowledged_cursor(states: &[SyncState]) -> Option<u64> {
+    states.iter().map(|x| x.local_cursor).min()
+}
+
+/// Three-way merge of a task edited on both sides. A field changed on one side only takes that
+/// change, a field changed differently on both sides goes to the most recently modified task and
+/// is reported in the returned field names. Tags merge as a set so additions and removals from
+/// both sides are kept. Without a base every differing field is a conflict.
+pub fn merge(base: Option<&Task>, local: &Task, remote: &Task) -> (Task, Vec<&'static str>) {
+    let local_wins = local.modified_utc >= remote.modified_utc;
+    let mut conflicts = vec![];
+    macro_rules! merge_field {
+        ($field:ident) => {{
+            let base = base.map(|x| &x.$field);
+            if local.$field == remote.$field || base == Some(&remote.$field) {
+                local.$field.clone()
+            } else if base == Some(&local.$field) {
+                remote.$field.clone()
+            } else {
+                conflicts.push(stringify!($field));
+                match local_wins {
+                    true => local.$field.clone(),
+                    false => remote.$field.clone(),
+                }
+            }
+        }};
+    }
+    let merged = Task {
+        ulid: local.ulid.clone(),
+        body: merge_field!(body),
+        modified_utc: local.modified_utc.max(remote.modified_utc),
+        ready_utc: merge_field!(ready_utc),
+        due_utc: merge_field!(due_utc),
+        closed_utc: merge_field!(closed_utc),
+        recurrence_duration: merge_field!(recurrence_duration),
+        recurrence_rule: merge_field!(recurrence_rule),
+        recurrence_mode: merge_field!(recurrence_mode),
+        ready_lead: merge_field!(ready_lead),
+        recurrence_until: merge_field!(recurrence_until),
+        recurrence_count: merge_field!(recurrence_count),
+        recurrence_occurrence: merge_field!(recurrence_occurrence),
+        priority_adjustment: merge_field!(priority_adjustment),
+        user: merge_field!(user),
+        metadata: merge_field!(metadata),
+        tags: merge_tags(
+            base.and_then(|x| x.tags.as_ref()),
+            local.tags.as_ref(),
+            remote.tags.as_ref(),
+        ),
+    };
+    (merged, conflicts)
+}
+
+/// Keeps tags both sides have, and tags one side added since the base
+fn merge_tags(
+    base: Option<&Vec<String>>,
+    local: Option<&Vec<String>>,
+    remote: Option<&Vec<String>>,
+) -> Option<Vec<String>> {
+    let as_set = |x: Option<&Vec<String>>| -> BTreeSet<String> {
+        x.map(|x| x.iter().cloned().collect()).unwrap_or_default()
+    };
+    let (base, local, remote) = (as_set(base), as_set(local), as_set(remote));
+    let tags: Vec<String> = local
+        .union(&remote)
+        .filter(|x| (local.contains(*x) && remote.contains(*x)) || !base.contains(*x))
+        .cloned()
+        .collect();
+    match tags.is_empty() {
+        true => None,
+        false => Some(tags),
+    }
+}
+
+/// A cursor ahead of the storage means it was replaced, so start over
+fn changes_since(storage: &dyn TaskStorage, cursor: u64) -> Result<ChangeSet> {
+    let changes = storage.changes_since(cursor)?;
+    if changes.cursor < cursor {
+        return storage.changes_since(0);
+    }
+    Ok(changes)
+}
+
+/// Drops tasks that lose to a tombstone from the other storage
+fn surviving(tasks: Vec<Task>, tombstones: &[Tombstone]) -> Vec<Task> {
+    tasks
+        .into_iter()
+        .filter(|task| {
+            !tombstones
+                .iter()
+                .any(|x| x.ulid == task.ulid && task.modified_utc <= Some(x.deleted_utc))
+        })
+        .collect()
+}
+
+fn create_tasks_hashmap(tasks: Vec<Task>) -> HashMap<String, Task> {
+    let mut map = HashMap::new();
+    tasks.iter().for_each(|x| {
+        map.insert(x.ulid.to_string(), x.clone());
+    });
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{
+        filter::{TaskFilter, TaskState},
+        sqlite_storage::SQLiteStorage,
+    };
+
+    use super::*;
+
+    fn get_sqlite_storage() -> SQLiteStorage {
+        let sqlite_storage = SQLiteStorage::new(":memory:");
+        let insert_query = r#"INSERT INTO tasks (ulid, body, due_utc, closed_utc, modified_utc) VALUES
+            ('8vag','follow up wit','2023-08-23 09:01:34',NULL,NULL),
+            ('6715','rotate passwo','2023-08-06 18:47:09',NULL,NULL),
+            ('3akq','cockroach cle','2023-08-06 18:47:09',NULL,NULL),
+            ('h2td','read/code on ','2023-08-07 18:50:05',NULL,NULL);
+        "#;
+        sqlite_storage.connection.execute(insert_query, ()).unwrap();
+        sqlite_storage
+    }
+
+    fn ulids(tasks: &[Task]) -> Vec<&str> {
+        tasks.iter().map(|x| x.ulid.as_str()).collect()
+    }
+
+    fn open_tasks_count(storage: &dyn TaskStorage) -> usize {
+        let filter = TaskFilter {
+            state: Some(TaskState::Open),
+            ..Default::default()
+        };
+        storage.query(&filter).unwrap().len()
+    }
+
+    #[test]
+    fn test_sync() {
+        let storage1 = get_sqlite_storage();
+        let storage2 = get_sqlite_storage();
+        let original_tasks_count = open_tasks_count(&storage1);
+        let mut tasks = storage1.search_using_ulid("6715").unwrap();
+        let expected_task = &mut tasks[0];
+        expected_task.body = "random updated task".to_string();
+        storage1.update(expected_task).unwrap();
+        let new_task1 = Task::default();
+        storage1.save(&new_task1).unwrap();
+        let task = storage1.search_using_ulid("3akq").unwrap();
+        storage1.delete(&task[0]).unwrap();
+
+        let new_task2 = Task::default();
+        storage2.save(&new_task2).unwrap();
+        let mut tasks = storage2.search_using_ulid("h2td").unwrap();
+        let task2 = &mut tasks[0];
+        task2.body = "random mess".to_string();
+        storage2.update(task2).unwrap();
+
+        let report = sync(
+            &storage1,
+            &storage2,
+            "test",
+            &mut SyncState::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(report.remote.added[0].ulid, new_task1.ulid);
+        assert_eq!(report.local.added[0].ulid, new_task2.ulid);
+        assert_eq!(report.remote.deleted.len(), 1);
+        let tasks = storage2.search_using_ulid("6715").unwrap();
+        assert_eq!(tasks[0].body, "random updated task".to_string());
+        let tasks2 = storage1.search_using_ulid("h2td").unwrap();
+
+        assert_eq!(tasks2[0].body, "random mess");
+
+        assert_eq!(
+            storage2.search_using_ulid(&new_task1.ulid).unwrap().len(),
+            1
+        );
+
+        assert_eq!(
+            storage1.search_using_ulid(&new_task2.ulid).unwrap().len(),
+            1
+        );
+
+        // deleted task doesn't exist anymore
+        let task = storage1.search_using_ulid("3akq").unwrap();
+        assert_eq!(task.len(), 0);
+        let task = storage2.search_using_ulid("3akq").unwrap();
+        assert_eq!(task.len(), 0);
+        assert_eq!(open_tasks_count(&storage1), original_tasks_count + 1);
+        assert_eq!(open_tasks_count(&storage2), original_tasks_count + 1);
+    }
+
+    #[test]
+    fn delete_beats_older_edits_across_peers() {
+        let laptop = get_sqlite_storage();
+        let server = get_sqlite_storage();
+        let phone = get_sqlite_storage();
+        let mut task = laptop.search_using_ulid("6715").unwrap()[0].clone();
+        task.body = "edited on the laptop".to_string();
+        laptop.update(&task).unwrap();
+        phone.delete(&task).unwrap();
+
+        // the edit reaches the server before the delete does
+        let mut laptop_state = SyncState::default();
+        sync(&laptop, &server, "server", &mut laptop_state, false).unwrap();
+        sync(&phone, &server, "test", &mut SyncState::default(), false).unwrap();
+        sync(&laptop, &server, "server", &mut laptop_state, false).unwrap();
+        for storage in [&laptop, &server, &phone] {
+            assert_eq!(storage.search_using_ulid("6715").unwrap().len(), 0);
+        }
+    }
+
+    #[test]
+    fn late_tombstones_reach_every_peer_before_being_purged() {
+        let hub = get_sqlite_storage();
+        let laptop = get_sqlite_storage();
+        let phone = get_sqlite_storage();
+        let mut laptop_state = SyncState::default();
+        let mut phone_state = SyncState::default();
+        let purge = |laptop_state: &SyncState, phone_state: &SyncState| {
+            hub.purge_trash(&Utc::now()).unwrap();
+            let states = [laptop_state.clone(), phone_state.clone()];
+            hub.purge_tombstones(acknowledged_cursor(&states).unwrap())
+                .unwrap()
+        };
+
+        // deleted long ago on the phone, which was offline until after the laptop synced
+        let tombstone = Tombstone {
+            ulid: "6715".to_string(),
+            deleted_utc: "2023-08-07T00:00:00Z".parse().unwrap(),
+        };
+        phone.apply_tombstone(&tombstone).unwrap();
+        sync(&hub, &laptop, "laptop", &mut laptop_state, false).unwrap();
+        sync(&hub, &phone, "phone", &mut phone_state, false).unwrap();
+        assert_eq!(
+            purge(&laptop_state, &phone_state),
+            0,
+            "laptop hasn't seen it"
+        );
+        assert_eq!(hub.tombstones().unwrap(), vec![tombstone]);
+
+        sync(&hub, &laptop, "laptop", &mut laptop_state, false).unwrap();
+        sync(&hub, &phone, "phone", &mut phone_state, false).unwrap();
+        assert_eq!(purge(&laptop_state, &phone_state), 1);
+
+        sync(&hub, &laptop, "laptop", &mut laptop_state, false).unwrap();
+        sync(&hub, &phone, "phone", &mut phone_state, false).unwrap();
+        for storage in [&hub, &laptop, &phone] {
+            assert_eq!(storage.search_using_ulid("6715").unwrap().len(), 0);
+        }
+    }
+
+    #[test]
+    fn sync_only_exchanges_changes_since_the_last_run() {
+        let storage1 = get_sqlite_storage();
+        let storage2 = SQLiteStorage::new(":memory:");
+        let mut state = SyncState::default();
+        sync(&storage1, &storage2, "test", &mut state, false).unwrap();
+        assert_eq!(open_tasks_count(&storage2), 4);
+
+        // a task older than any time window is still picked up
+        let old_task = Task {
+            modified_utc: "2020-01-01T00:00:00Z".parse().ok(),
+            ..Default::default()
+        };
+        storage2.import(&old_task).unwrap();
+        let first_cursors = state.clone();
+        sync(&storage1, &storage2, "test", &mut state, false).unwrap();
+        assert_eq!(storage1.search_using_ulid(&old_task.ulid).unwrap().len(), 1);
+        assert!(state.remote_cursor > first_cursors.remote_cursor);
+
+        // the import comes back once as a local change but nothing is exchanged for it
+        let report = sync(&storage1, &storage2, "test", &mut state, false).unwrap();
+        assert!(report.local.is_empty() && report.remote.is_empty());
+        let settled = state.clone();
+        sync(&storage1, &storage2, "test", &mut state, false).unwrap();
+        assert_eq!(state, settled);
+    }
+
+    #[test]
+    fn edits_on_one_side_are_reported_as_updates() {
+        let laptop = get_sqlite_storage();
+        let phone = SQLiteStorage::new(":memory:");
+        let mut state = SyncState::default();
+        let report = sync(&laptop, &phone, "phone", &mut state, false).unwrap();
+        assert_eq!(report.remote.added.len(), 4);
+
+        let mut task = laptop.search_using_ulid("6715").unwrap()[0].clone();
+        task.body = "rotate passwords".to_string();
+        laptop.update(&task).unwrap();
+        let report = sync(&laptop, &phone, "phone", &mut state, false).unwrap();
+        assert!(report.remote.added.is_empty());
+        assert_eq!(ulids(&report.remote.updated), vec!["6715"]);
+
+        let mut task = phone.search_using_ulid("8vag").unwrap()[0].clone();
+        task.body = "follow up with".to_string();
+        phone.update(&task).unwrap();
+        let report = sync(&laptop, &phone, "phone", &mut state, false).unwrap();
+        assert!(report.local.added.is_empty());
+        assert_eq!(ulids(&report.local.updated), vec!["8vag"]);
+    }
+
+    #[test]
+    fn edits_to_different_fields_are_both_kept() {
+        let laptop = get_sqlite_storage();
+        let phone = get_sqlite_storage();
+        let mut state = SyncState::default();
+        sync(&laptop, &phone, "phone", &mut state, false).unwrap();
+
+        let mut task = laptop.search_using_ulid("6715").unwrap()[0].clone();
+        task.tags = Some(vec!["work".to_string()]);
+        laptop.update(&task).unwrap();
+        let mut task = phone.search_using_ulid("6715").unwrap()[0].clone();
+        task.body = "rotate passwords".to_string();
+        task.tags = Some(vec!["security".to_string()]);
+        phone.update(&task).unwrap();
+
+        sync(&laptop, &phone, "phone", &mut state, false).unwrap();
+        for storage in [&laptop, &phone] {
+            let task = &storage.search_using_ulid("6715").unwrap()[0];
+            assert_eq!(task.body, "rotate passwords");
+            assert_eq!(
+                task.tags,
+                Some(vec!["security".to_string(), "work".to_string()])
+            );
+        }
+        assert!(laptop.conflicts().unwrap().is_empty());
+    }
+
+    #[test]
+    fn conflicting_edits_are_recorded() {
+        let laptop = get_sqlite_storage();
+        let phone = get_sqlite_storage();
+        let mut state = SyncState::default();
+        sync(&laptop, &phone, "phone", &mut state, false).unwrap();
+
+        let mut task = laptop.search_using_ulid("6715").unwrap()[0].clone();
+        task.body = "rotate passwords on the laptop".to_string();
+        laptop.update(&task).unwrap();
+        let mut task = phone.search_using_ulid("6715").unwrap()[0].clone();
+        task.body = "rotate passwords on the phone".to_string();
+        phone.update(&task).unwrap();
+
+        sync(&laptop, &phone, "phone", &mut state, false).unwrap();
+        let conflicts = laptop.conflicts().unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].peer, "phone");
+        assert_eq!(conflicts[0].fields, vec!["body"]);
+        assert_eq!(conflicts[0].local.body, "rotate passwords on the laptop");
+        assert_eq!(conflicts[0].remote.body, "rotate passwords on the phone");
+    }
+
+    #[test]
+    fn merge_breaks_ties_per_field() {
+        let base = Task {
+            body: "base".to_string(),
+            tags: Some(vec!["a".to_string(), "b".to_string()]),
+            modified_utc: "2024-01-01T00:00:00Z".parse().ok(),
+            ..Default::default()
+        };
+        let local = Task {
+            body: "local".to_string(),
+            tags: Some(vec!["b".to_string(), "c".to_string()]),
+            modified_utc: "2024-01-02T00:00:00Z".parse().ok(),
+            ..base.clone()
+        };
+        let remote = Task {
+            body: "remote".to_string(),
+            metadata: Some("remote".to_string()),
+            tags: Some(vec!["a".to_string(), "b".to_string(), "d".to_string()]),
+            modified_utc: "2024-01-03T00:00:00Z".parse().ok(),
+            ..base.clone()
+        };
+        let (merged, conflicts) = merge(Some(&base), &local, &remote);
+        assert_eq!(conflicts, vec!["body"]);
+        assert_eq!(merged.body, "remote");
+        assert_eq!(merged.metadata, Some("remote".to_string()));
+        assert_eq!(
+            merged.tags,
+            Some(vec!["b".to_string(), "c".to_string(), "d".to_string()])
+        );
+        assert_eq!(merged.modified_utc, remote.modified_utc);
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() {
+        let storage1 = get_sqlite_storage();
+        let storage2 = SQLiteStorage::new(":memory:");
+        let mut state = SyncState::default();
+        let report = sync(&storage1, &storage2, "test", &mut state, true).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.remote.added.len(), 4);
+        assert!(report.local.is_empty());
+        assert_eq!(open_tasks_count(&storage2), 0);
+        assert_eq!(state, SyncState::default());
+    }
+
+    #[test]
+    fn sync_is_symmetric() {
+        let storage1 = get_sqlite_storage();
+        let storage2 = get_sqlite_storage();
+        let new_task = Task::default();
+        storage2.save(&new_task).unwrap();
+        let task = storage2.search_using_ulid("8vag").unwrap();
+        storage2.delete(&task[0]).unwrap();
+
+        // storage2 is the remote in one direction and the local in the other
+        sync(
+            &storage1,
+            &storage2,
+            "test",
+            &mut SyncState::default(),
+            false,
+        )
+        .unwrap();
+        sync(
+            &storage2,
+            &storage1,
+            "test",
+            &mut SyncState::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(storage1.search_using_ulid(&new_task.ulid).unwrap().len(), 1);
+        assert_eq!(storage1.search_using_ulid("8vag").unwrap().len(), 0);
+        assert_eq!(open_tasks_count(&storage1), open_tasks_count(&storage2));
+    }
+}
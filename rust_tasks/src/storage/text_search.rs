@@ -0,0 +1,155 @@
+use std::cmp::Ordering;
+
+use crate::tasks::Task;
+
+/// Results returned when no limit is given
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// How much a match counts in each field, used as the bm25 weights in SQLite too
+pub const BODY_WEIGHT: f64 = 10.0;
+pub const TAGS_WEIGHT: f64 = 5.0;
+pub const METADATA_WEIGHT: f64 = 1.0;
+
+/// Lowercase words of `text`, split on anything that isn't alphanumeric like FTS5's default
+/// tokenizer
+pub fn tokens(text: &str) -> Vec<String> {
+    text.split(|x: char| !x.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_lowercase())
+        .collect()
+}
+
+/// `query` as an FTS5 MATCH expression where every word must appear. Words are quoted so FTS5
+/// operators in the query are searched for like any other word. None if there are no words.
+pub fn fts5_query(query: &str) -> Option<String> {
+    let words = tokens(query);
+    (!words.is_empty()).then(|| {
+        words
+            .iter()
+            .map(|x| format!("\"{x}\""))
+            .collect::<Vec<_>>()
+            .join(" ")
+    })
+}
+
+/// Tasks with every word of `query` in their body, tags or metadata, best match first. Ranks
+/// tasks for storages without an index.
+pub fn rank(tasks: impl IntoIterator<Item = Task>, query: &str, limit: usize) -> Vec<Task> {
+    let words = tokens(query);
+    if words.is_empty() {
+        return vec![];
+    }
+    let mut scored: Vec<(f64, Task)> = tasks
+        .into_iter()
+        .filter_map(|task| {
+            let fields = [
+                (tokens(&task.body), BODY_WEIGHT),
+                (
+                    tokens(&task.tags.as_deref().unwrap_or_default().join(" ")),
+                    TAGS_WEIGHT,
+                ),
+                (
+                    tokens(task.metadata.as_deref().unwrap_or_default()),
+                    METADATA_WEIGHT,
+                ),
+            ];
+            let mut score = 0.0;
+            for word in &words {
+                let found: f64 = fields
+                    .iter()
+                    .map(|(tokens, weight)| {
+                        weight * tokens.iter().filter(|x| *x == word).count() as f64
+                    })
+                    .sum();
+                if found == 0.0 {
+                    return None;
+                }
+                score += found;
+            }
+            Some((score, task))
+        })
+        .collect();
+    scored.sort_by(|(a, x), (b, y)| {
+        b.partial_cmp(a)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| y.ulid.cmp(&x.ulid))
+    });
+    scored.into_iter().take(limit).map(|(_, x)| x).collect()
+}
+
+/// `text` split into pieces with whether each is a word of `query`, to highlight matches
+pub fn highlight<'a>(text: &'a str, query: &str) -> Vec<(&'a str, bool)> {
+    let words = tokens(query);
+    let mut pieces = vec![];
+    let mut start = 0;
+    let mut word_start = None;
+    for (index, character) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (
+            character.is_alphanumeric() && index < text.len(),
+            word_start,
+        ) {
+            (true, None) => word_start = Some(index),
+            (false, Some(from)) => {
+                if words.contains(&text[from..index].to_lowercase()) {
+                    if start < from {
+                        pieces.push((&text[start..from], false));
+                    }
+                    pieces.push((&text[from..index], true));
+                    start = index;
+                }
+                word_start = None;
+            }
+            _ => {}
+        }
+    }
+    if start < text.len() {
+        pieces.push((&text[start..], false));
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operators_are_quoted() {
+        assert_eq!(
+            fts5_query("Plants OR NOT"),
+            Some(r#""plants" "or" "not""#.into())
+        );
+        assert_eq!(fts5_query(" -* "), None);
+    }
+
+    #[test]
+    fn body_matches_rank_first() {
+        let tagged = Task {
+            body: "water".to_string(),
+            tags: Some(vec!["plants".to_string()]),
+            ..Default::default()
+        };
+        let body = Task {
+            body: "water the plants".to_string(),
+            ..Default::default()
+        };
+        let other = Task {
+            body: "water the lawn".to_string(),
+            ..Default::default()
+        };
+        let ranked = rank([tagged.clone(), other, body.clone()], "Plants water", 10);
+        assert_eq!(ranked, vec![body, tagged]);
+    }
+
+    #[test]
+    fn matching_words_are_highlighted() {
+        assert_eq!(
+            highlight("Water the plants, water!", "water"),
+            vec![
+                ("Water", true),
+                (" the plants, ", false),
+                ("water", true),
+                ("!", false)
+            ]
+        );
+    }
+}
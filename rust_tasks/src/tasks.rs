@@ -1,11 +1,11 @@
 use std::env::var;
 use std::fs;
 use std::io::Write;
-use std::ops::Add;
 use std::process::Command;
 
 use anyhow::{bail, Result};
 use chrono::{DateTime, NaiveDate, Utc};
+use clap::ValueEnum;
 use iso8601_duration::Duration;
 use serde::{Deserialize, Serialize};
 use summary::SummaryConfig;
@@ -13,14 +13,36 @@ use tempfile::Builder;
 use termcolor::{Color, ColorSpec, StandardStream, WriteColor};
 use ulid::Ulid;
 
-use self::display_utils::show_tasks_table;
+use self::display_utils::{show_search_results, show_tasks_table};
+use self::recurrence::{RRule, RecurrenceMode};
 
-use crate::storage::storage::TaskStorage;
+use crate::config::{Backend, Config};
+use crate::storage;
+use crate::storage::filter::{DateRange, TaskFilter, TaskOrder, TaskState};
+use crate::storage::migrations;
+use crate::storage::sqlite_storage::SQLiteStorage;
+use crate::storage::storage::{HealthStatus, TaskEvent, TaskEventKind, TaskStorage};
+use crate::storage::sync::SyncReport;
 
 pub mod add_utils;
+pub mod conflict_utils;
 pub mod display_utils;
 pub mod edit_utils;
+pub mod history_utils;
+pub mod priority;
+pub mod recurrence;
 pub mod summary;
+pub mod trash_utils;
+pub mod undo_utils;
+
+/// Shown when editing a task, the recurrence fields are left out of the yaml until they are set
+const RECURRENCE_FIELDS: &str =
+    "# recurrence_rule: FREQ=WEEKLY;BYDAY=MO,FR (an RRULE, used over recurrence_duration)
+# recurrence_mode: from-due | from-completion | catch-up
+# ready_lead: P3D (ready this long before due, sets ready_utc)
+# recurrence_until: 2024-12-31T23:59:59Z (no occurrence is due after it)
+# recurrence_count: 10 (occurrences in total, recurrence_occurrence is this one's number)
+";
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Task {
@@ -31,6 +53,24 @@ pub struct Task {
     pub due_utc: Option<DateTime<Utc>>,
     pub closed_utc: Option<DateTime<Utc>>,
     pub recurrence_duration: Option<Duration>,
+    /// An RFC 5545 RRULE, takes over from `recurrence_duration` when both are set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence_rule: Option<RRule>,
+    /// What the next occurrence counts from, from the due date when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence_mode: Option<RecurrenceMode>,
+    /// How long before due the task is ready, `ready_utc` follows it when due moves
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ready_lead: Option<Duration>,
+    /// The last due date the recurrence can have
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence_until: Option<DateTime<Utc>>,
+    /// How many occurrences the recurrence has in total
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence_count: Option<u32>,
+    /// Which occurrence this task is, the first when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence_occurrence: Option<u32>,
     pub priority_adjustment: Option<f64>,
     pub user: Option<String>,
     pub metadata: Option<String>,
@@ -49,6 +89,12 @@ impl Default for Task {
             ready_utc: None,
             closed_utc: None,
             recurrence_duration: None,
+            recurrence_rule: None,
+            recurrence_mode: None,
+            ready_lead: None,
+            recurrence_until: None,
+            recurrence_count: None,
+            recurrence_occurrence: None,
             priority_adjustment: None,
             metadata: None,
             tags: None,
@@ -57,26 +103,38 @@ impl Default for Task {
 }
 
 impl Task {
-    fn next_task(&self) -> Option<Task> {
-        match &self.recurrence_duration {
-            None => None,
-            Some(duration) => {
-                let chrono_duration = duration.to_chrono_at_datetime(self.due_utc.unwrap());
-                let new_due_date = self.due_utc.map(|x| x.add(chrono_duration));
-                // FIXME! Temporary fix that assumes all my ready dates happen on the same date as due_utc
-                let new_ready_date = self.ready_utc.map(|x| x.add(chrono_duration));
-                let new_task = Task {
-                    ulid: Ulid::new().to_string().to_lowercase(),
-                    due_utc: new_due_date,
-                    ready_utc: new_ready_date,
-                    ..self.clone()
-                };
-                Some(new_task)
+    /// Equality that ignores the order of tags, which storages don't preserve
+    pub fn same_as(&self, other: &Task) -> bool {
+        let sorted = |x: &Task| {
+            let mut task = x.clone();
+            if let Some(tags) = task.tags.as_mut() {
+                tags.sort();
             }
-        }
+            task
+        };
+        sorted(self) == sorted(other)
+    }
+
+    fn next_task(&self) -> Option<Task> {
+        self.next_task_at(Utc::now())
     }
 
-    pub fn undo_task(&mut self, storage: &impl TaskStorage) -> Result<()> {
+    /// The next occurrence of a recurring task, `now` being when it was done if it's still open
+    fn next_task_at(&self, now: DateTime<Utc>) -> Option<Task> {
+        let new_due_date = self.next_due_at(now)?;
+        let new_ready_date = self.ready_for(new_due_date);
+        let new_task = Task {
+            ulid: Ulid::new().to_string().to_lowercase(),
+            due_utc: Some(new_due_date),
+            ready_utc: new_ready_date,
+            closed_utc: None,
+            recurrence_occurrence: Some(self.occurrence() + 1),
+            ..self.clone()
+        };
+        Some(new_task)
+    }
+
+    pub fn undo_task(&mut self, storage: &dyn TaskStorage) -> Result<()> {
         match self.closed_utc {
             Some(_) => {
                 self.closed_utc = None;
@@ -94,12 +152,27 @@ impl Task {
                 Ok(())
             }
             None => {
-                if let Some(x) = self.next_task() {
-                    storage.save(&x)?;
-                }
-                self.closed_utc = Some(Utc::now());
-                storage.update(self)?;
-                Ok(())
+                let before = self.clone();
+                let now = Utc::now();
+                self.closed_utc = Some(now);
+                let next_task = self.next_task_at(now);
+                let task = &*self;
+                let mut events = vec![TaskEvent::new(
+                    TaskEventKind::Do,
+                    Some(before),
+                    Some(task.clone()),
+                )];
+                events.extend(
+                    next_task
+                        .iter()
+                        .map(|x| TaskEvent::new(TaskEventKind::Save, None, Some(x.clone()))),
+                );
+                undo_utils::journaled(storage, "do", events, &mut || {
+                    if let Some(x) = &next_task {
+                        storage.save(x)?;
+                    }
+                    storage.update(task)
+                })
             }
         }
     }
@@ -121,10 +194,19 @@ impl Task {
     }
 
     fn edit_with_editor(&mut self) -> Result<()> {
+        self.edit_with_editor_and_comment(RECURRENCE_FIELDS)?;
+        if let (Some(_), Some(due)) = (&self.ready_lead, self.due_utc) {
+            self.ready_utc = self.ready_for(due);
+        }
+        Ok(())
+    }
+
+    /// Edits the task with `comment` shown above the yaml, it should be made of `#` lines
+    fn edit_with_editor_and_comment(&mut self, comment: &str) -> Result<()> {
         let yml = self.to_yaml();
 
         let mut tempfile = Builder::new().suffix(".yml").tempfile()?;
-        write!(tempfile, "{}", yml)?;
+        write!(tempfile, "{}{}", comment, yml)?;
 
         let editor = var("EDITOR").unwrap_or("vim".to_string());
         Command::new(editor).arg(tempfile.path()).status()?;
@@ -144,11 +226,20 @@ pub fn experiment() -> Result<()> {
     todo!()
 }
 
-pub fn query(storage: &dyn TaskStorage, clause: &str) -> Result<()> {
-    let tasks = storage.unsafe_query(clause)?;
+pub fn query(storage: &dyn TaskStorage, filter: &TaskFilter) -> Result<()> {
+    let tasks = storage.query(filter)?;
     show_tasks_table(&tasks)
 }
 
+pub fn search(storage: &dyn TaskStorage, query: &str, limit: usize) -> Result<()> {
+    let tasks = storage.search_text(query, limit)?;
+    if tasks.is_empty() {
+        println!("No tasks match: {query}");
+        return Ok(());
+    }
+    show_search_results(&tasks, query)
+}
+
 pub fn quick_clean(storage: &dyn TaskStorage, date: &str) -> Result<()> {
     let date_to_clean = NaiveDate::parse_from_str(date, "%Y-%m-%d")
         .unwrap_or_else(|_| panic!("Expected date like `2024-10-23` but found {}", date));
@@ -159,48 +250,47 @@ pub fn quick_clean(storage: &dyn TaskStorage, date: &str) -> Result<()> {
         bail!("Expected date before today but got {}", date_to_clean);
     }
 
-    let clause =
-        format!("WHERE DATE(due_utc) = '{date}' AND DATE(closed_utc) IS NULL ORDER BY due_utc ASC");
+    let filter = TaskFilter {
+        due: Some(DateRange::from_dates(
+            Some(date_to_clean),
+            Some(date_to_clean),
+        )),
+        state: Some(TaskState::Open),
+        order_by: Some(TaskOrder::DueAsc),
+        ..Default::default()
+    };
 
-    let mut tasks = storage.unsafe_query(&clause)?;
+    let mut tasks = storage.query(&filter)?;
+    let before = tasks.clone();
 
     for task in tasks.iter_mut() {
-        match &task.recurrence_duration {
+        match task.next_task() {
             None => {
                 let new_due = task.due_utc.map(|x| today.with_time(x.time()).unwrap());
-                let new_ready = task.ready_utc.map(|x| today.with_time(x.time()).unwrap());
+                task.ready_utc = new_due.and_then(|x| task.ready_for(x));
                 task.due_utc = new_due;
-                task.ready_utc = new_ready;
-                task.update_to_db(storage)?;
             }
-            Some(_) => {
-                let potential_next_task = task.next_task().unwrap();
+            Some(potential_next_task) => {
                 task.due_utc = potential_next_task.due_utc;
                 task.ready_utc = potential_next_task.ready_utc;
-                task.update_to_db(storage)?;
             }
         }
     }
-    Ok(())
+    let events = before
+        .into_iter()
+        .zip(&tasks)
+        .map(|(before, after)| {
+            TaskEvent::new(TaskEventKind::Update, Some(before), Some(after.clone()))
+        })
+        .collect();
+    // move every task or none of them
+    undo_utils::journaled(storage, "quick-clean", events, &mut || {
+        tasks.iter().try_for_each(|x| x.update_to_db(storage))
+    })
 }
 
-pub fn do_task(task_storage: &dyn TaskStorage, ulid_suffix: &str) -> Result<()> {
-    let mut tasks = task_storage.search_using_ulid(ulid_suffix)?;
-    if tasks.len() > 1 {
-        bail!(
-            "Expected 1 task but found {}\n{}",
-            tasks.len(),
-            tasks.iter().fold("".to_string(), |acc, x| format!(
-                "{}\n{}: {}",
-                acc, x.ulid, x.body
-            ))
-        );
-    }
-    if tasks.is_empty() {
-        bail!("No tasks found with ulid: {}", ulid_suffix);
-    }
-
-    let task = &mut tasks[0];
+pub fn do_task(task_storage: &dyn TaskStorage, ulid: &str) -> Result<()> {
+    let task = &mut task_storage.find_by_ulid(ulid)?;
     task.do_task(task_storage)?;
     let mut stdout = StandardStream::stdout(termcolor::ColorChoice::Always);
     write!(&mut stdout, "Done: {} ", task.ulid)?;
@@ -216,6 +306,195 @@ pub fn list_next_tasks(storage: &dyn TaskStorage, number: usize) -> Result<()> {
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Syncs with every target in order, a failing target doesn't stop the rest. Afterwards drops
+/// tombstones that every configured target has acknowledged. A dry run only reports what each
+/// target would exchange.
+pub fn sync(
+    storage: &dyn TaskStorage,
+    config: &Config,
+    target_names: &[String],
+    dry_run: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let mut failed = vec![];
+    let mut reports = vec![];
+    for target in config.get_sync_targets(target_names)? {
+        let name = target.name();
+        match sync_target(storage, target, dry_run) {
+            Ok(report) if format == OutputFormat::Text => show_sync_report(&report),
+            Ok(report) => reports.push(report),
+            Err(e) => {
+                eprintln!("Failed to sync with {name}: {e:#}");
+                failed.push(name);
+            }
+        }
+    }
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    }
+    if !dry_run {
+        purge_acknowledged_tombstones(storage, config, format)?;
+    }
+
+    if !failed.is_empty() {
+        bail!("Failed to sync with: {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+fn purge_acknowledged_tombstones(
+    storage: &dyn TaskStorage,
+    config: &Config,
+    format: OutputFormat,
+) -> Result<()> {
+    let mut states = vec![];
+    for target in config.get_sync_targets(&[])? {
+        states.push(storage.sync_state(&target.name())?);
+    }
+    if let Some(acknowledged) = storage::sync::acknowledged_cursor(&states) {
+        let purged = storage.purge_tombstones(acknowledged)?;
+        if purged > 0 && format == OutputFormat::Text {
+            println!("Purged {purged} tombstones acknowledged by every target");
+        }
+    }
+    Ok(())
+}
+
+fn sync_target(storage: &dyn TaskStorage, target: &Backend, dry_run: bool) -> Result<SyncReport> {
+    let name = target.name();
+    let mut state = storage.sync_state(&name)?;
+    if dry_run {
+        let peer = target.get_storage_engine()?;
+        return storage::sync::sync(storage, peer.as_ref(), &name, &mut state, true);
+    }
+    state.last_attempt_utc = Some(Utc::now());
+    let result = target
+        .get_storage_engine()
+        .and_then(|peer| storage::sync::sync(storage, peer.as_ref(), &name, &mut state, false));
+    match &result {
+        Ok(_) => {
+            state.last_success_utc = state.last_attempt_utc;
+            state.last_error = None;
+        }
+        Err(e) => state.last_error = Some(format!("{e:#}")),
+    }
+    storage.save_sync_state(&name, &state)?;
+    result
+}
+
+fn show_sync_report(report: &SyncReport) {
+    let verb = match report.dry_run {
+        true => "Would sync",
+        false => "Synced",
+    };
+    println!("{verb} with {}", report.peer);
+    for (side, changes) in [("self", &report.local), (&report.peer[..], &report.remote)] {
+        if changes.is_empty() {
+            continue;
+        }
+        println!(
+            "  {side}: {} added, {} updated, {} deleted",
+            changes.added.len(),
+            changes.updated.len(),
+            changes.deleted.len()
+        );
+        for task in &changes.added {
+            println!("    + {} {}", task.ulid, task.body);
+        }
+        for task in &changes.updated {
+            println!("    ~ {} {}", task.ulid, task.body);
+        }
+        for tombstone in &changes.deleted {
+            println!("    - {}", tombstone.ulid);
+        }
+    }
+    for conflict in &report.conflicts {
+        println!(
+            "  conflict: {} {} ({}), the most recent edit won. See `rust_tasks conflicts list`",
+            conflict.ulid,
+            conflict.local.body,
+            conflict.fields.join(", ")
+        );
+    }
+}
+
+pub fn show_sync_status(
+    storage: &dyn TaskStorage,
+    config: &Config,
+    target_names: &[String],
+) -> Result<()> {
+    let format_time = |x: Option<DateTime<Utc>>| {
+        x.map_or("never".to_string(), |x| {
+            x.format("%Y-%m-%d %H:%M:%S").to_string()
+        })
+    };
+    for target in config.get_sync_targets(target_names)? {
+        let name = target.name();
+        let state = storage.sync_state(&name)?;
+        println!(
+            "{name}: last attempt {}, last success {}",
+            format_time(state.last_attempt_utc),
+            format_time(state.last_success_utc)
+        );
+        if let Some(error) = state.last_error {
+            println!("  error: {error}");
+        }
+    }
+    Ok(())
+}
+
+pub fn show_health(storage: &dyn TaskStorage) -> Result<()> {
+    let report = storage.health()?;
+    let mut stdout = StandardStream::stdout(termcolor::ColorChoice::Always);
+    for check in &report.checks {
+        let color = match check.status {
+            HealthStatus::Ok => Color::Green,
+            HealthStatus::Degraded => Color::Red,
+        };
+        stdout.set_color(ColorSpec::new().set_fg(Some(color)))?;
+        write!(&mut stdout, "{:10}", format!("{:?}", check.status))?;
+        stdout.reset()?;
+        writeln!(&mut stdout, "{:18}{}", check.name, check.detail)?;
+    }
+    if report.status == HealthStatus::Degraded {
+        bail!("Storage is degraded");
+    }
+    Ok(())
+}
+
+pub fn show_db_status(storage: &SQLiteStorage) -> Result<()> {
+    println!(
+        "Schema version: {}",
+        migrations::schema_version(&storage.connection)?
+    );
+    println!("Latest version: {}", migrations::latest_version());
+    let pending = migrations::pending(&storage.connection)?;
+    if pending.is_empty() {
+        println!("Up to date");
+    }
+    for migration in pending {
+        println!("Pending: {} {}", migration.version, migration.description);
+    }
+    Ok(())
+}
+
+pub fn migrate_db(storage: &SQLiteStorage) -> Result<()> {
+    let applied = storage.migrate()?;
+    if applied.is_empty() {
+        println!("Up to date");
+    }
+    for migration in applied {
+        println!("Applied: {} {}", migration.version, migration.description);
+    }
+    Ok(())
+}
+
 pub fn get_summary_stats(storage: &dyn TaskStorage, summary_config: &SummaryConfig) -> Result<()> {
     let summary_result = storage.summarize_day(summary_config)?;
     summary_config.get_summary_stats(summary_result)?;
@@ -224,6 +503,7 @@ pub fn get_summary_stats(storage: &dyn TaskStorage, summary_config: &SummaryConf
 
 #[cfg(test)]
 mod tests {
+    use crate::storage::memory_storage::MemoryStorage;
     use crate::storage::sqlite_storage;
 
     use super::*;
@@ -233,7 +513,7 @@ mod tests {
         // FIXME! Refactor this to return SQLiteStorage
         let conn = Connection::open_in_memory().unwrap();
         let sqlite_storage = sqlite_storage::SQLiteStorage { connection: conn };
-        sqlite_storage.create_tasks_table().unwrap();
+        sqlite_storage.migrate().unwrap();
         let insert_query = r#"INSERT INTO tasks (ulid, body, due_utc, closed_utc, modified_utc) VALUES
             ('8vag','follow up wit','2023-08-23 09:01:34',NULL,NULL),
             ('7nx0','deep dive int','2023-08-06 18:46:41',NULL,NULL),
@@ -279,10 +559,68 @@ mod tests {
     }
 
     #[test]
-    fn task_saved_to_db() {
-        let task_storage = sqlite_storage::SQLiteStorage {
-            connection: get_connection(),
+    fn next_task_follows_the_rrule() {
+        let task = Task {
+            due_utc: "2024-03-08T17:00:00Z".parse().ok(),
+            ready_utc: "2024-03-08T16:00:00Z".parse().ok(),
+            recurrence_duration: "P1D".parse().ok(),
+            recurrence_rule: "FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR".parse().ok(),
+            ..Default::default()
         };
+        let new_task = task.next_task().unwrap();
+        assert_eq!(new_task.due_utc, "2024-03-11T17:00:00Z".parse().ok());
+        assert_eq!(new_task.ready_utc, "2024-03-11T16:00:00Z".parse().ok());
+        assert_eq!(new_task.recurrence_rule, task.recurrence_rule);
+    }
+
+    #[test]
+    fn next_task_keeps_the_ready_lead() {
+        // ready 3 days before, across the end of February
+        let task = Task {
+            due_utc: "2024-02-02T09:00:00Z".parse().ok(),
+            ready_utc: "2024-01-30T09:00:00Z".parse().ok(),
+            ready_lead: "P3D".parse().ok(),
+            recurrence_rule: "FREQ=MONTHLY".parse().ok(),
+            ..Default::default()
+        };
+        let new_task = task.next_task().unwrap();
+        assert_eq!(new_task.due_utc, "2024-03-02T09:00:00Z".parse().ok());
+        assert_eq!(new_task.ready_utc, "2024-02-28T09:00:00Z".parse().ok());
+
+        // a lead of a month counts on the calendar
+        let task = Task {
+            due_utc: "2024-03-31T09:00:00Z".parse().ok(),
+            ready_lead: "P1M".parse().ok(),
+            recurrence_duration: "P1D".parse().ok(),
+            ..Default::default()
+        };
+        let new_task = task.next_task().unwrap();
+        assert_eq!(new_task.ready_utc, "2024-03-01T09:00:00Z".parse().ok());
+    }
+
+    #[test]
+    fn quick_clean_keeps_the_ready_lead() {
+        let storage = MemoryStorage::new();
+        let task = Task {
+            due_utc: "2024-02-02T18:00:00Z".parse().ok(),
+            ready_utc: "2024-02-01T06:00:00Z".parse().ok(),
+            ..Default::default()
+        };
+        storage.save(&task).unwrap();
+        quick_clean(&storage, "2024-02-02").unwrap();
+        let moved = storage
+            .search_using_ulid(&task.ulid)
+            .unwrap()
+            .pop()
+            .unwrap();
+        let due = moved.due_utc.unwrap();
+        assert_eq!(due.date_naive(), Utc::now().date_naive());
+        assert_eq!(due - moved.ready_utc.unwrap(), chrono::Duration::hours(36));
+    }
+
+    #[test]
+    fn task_saved_to_db() {
+        let task_storage = MemoryStorage::new();
         let task = Task::default();
         task.save_to_db(&task_storage).unwrap();
 
@@ -298,13 +636,12 @@ mod tests {
 
     #[test]
     fn task_saved_to_db_with_tags() {
-        let conn = get_connection();
         let task = Task {
             tags: Some(vec!["meeting".to_string(), "work".to_string()]),
             ..Default::default()
         };
 
-        let task_storage = sqlite_storage::SQLiteStorage { connection: conn };
+        let task_storage = MemoryStorage::new();
         task.save_to_db(&task_storage).unwrap();
         let saved_tasks = task_storage.search_using_ulid(&task.ulid).unwrap();
         assert_eq!(saved_tasks.len(), 1);
@@ -318,15 +655,23 @@ mod tests {
 
     #[test]
     fn task_do_task() {
-        let task_storage = sqlite_storage::SQLiteStorage {
-            connection: get_connection(),
+        let task_storage = MemoryStorage::new();
+        let mut task = Task {
+            due_utc: "2023-12-04T10:00:00Z".parse().ok(),
+            recurrence_duration: "P1D".parse().ok(),
+            ..Default::default()
         };
-        let mut tasks = task_storage.search_using_ulid("8vag").unwrap();
-        let task = &mut tasks[0];
-        assert_eq!(task.closed_utc, None);
+        task_storage.save(&task).unwrap();
         task.do_task(&task_storage).unwrap();
-        let task = &task_storage.search_using_ulid("8vag").unwrap()[0];
-        assert_ne!(task.closed_utc, None);
+        let saved = &task_storage.search_using_ulid(&task.ulid).unwrap()[0];
+        assert_ne!(saved.closed_utc, None);
+        let filter = TaskFilter {
+            state: Some(TaskState::Open),
+            ..Default::default()
+        };
+        let next = task_storage.query(&filter).unwrap();
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].due_utc, "2023-12-05T10:00:00Z".parse().ok());
     }
 
     #[test]
@@ -1,7 +1,11 @@
 use anyhow::Result;
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use iso8601_duration::Duration;
 
+use crate::storage::storage::{TaskEvent, TaskEventKind};
+
+use super::recurrence::{RRule, RecurrenceMode};
+use super::undo_utils::journaled;
 use super::{Task, TaskStorage};
 
 #[derive(Debug, PartialEq)]
@@ -10,21 +14,39 @@ struct AddContext {
     due: Option<DateTime<Utc>>,
     tags: Option<Vec<String>>,
     recur: Option<Duration>,
+    rule: Option<RRule>,
+    mode: Option<RecurrenceMode>,
+    ready: Option<Duration>,
+    until: Option<DateTime<Utc>>,
+    count: Option<u32>,
     priority: Option<f64>,
 }
 
 pub fn add_task(task_storage: &dyn TaskStorage, input: &str) -> Result<()> {
     let context = get_context(input.to_string())?;
-    let task = Task {
+    let mut task = Task {
         body: context.body,
         due_utc: context.due,
         tags: context.tags,
         recurrence_duration: context.recur,
+        recurrence_rule: context.rule,
+        recurrence_mode: context.mode,
+        ready_lead: context.ready,
+        recurrence_until: context.until,
+        recurrence_count: context.count,
         priority_adjustment: context.priority,
         ..Default::default()
     };
+    task.ready_utc = task.due_utc.and_then(|x| task.ready_for(x));
 
-    task.save_to_db(task_storage)?;
+    let events = vec![TaskEvent::new(
+        TaskEventKind::Save,
+        None,
+        Some(task.clone()),
+    )];
+    journaled(task_storage, "add", events, &mut || {
+        task.save_to_db(task_storage)
+    })?;
     println!("Saved task: {}", task.ulid);
     Ok(())
 }
@@ -35,6 +57,11 @@ fn get_context(input: String) -> Result<AddContext> {
     let mut due = None;
     let mut tags: Vec<String> = vec![];
     let mut recur = None;
+    let mut rule = None;
+    let mut mode = None;
+    let mut ready = None;
+    let mut until = None;
+    let mut count = None;
     let mut priority = None;
     for word in input.split(' ').rev() {
         if !special_identifiers {
@@ -51,14 +78,53 @@ fn get_context(input: String) -> Result<AddContext> {
                 .and_utc();
             due = Some(due_chrono);
         } else if word.starts_with("recur:") {
-            if recur.is_some() {
+            if recur.is_some() || rule.is_some() {
                 panic!("Invalid input string has multiple recurs");
             }
             let recur_string = word.replace("recur:", "");
-            recur = Some(
-                recur_string
+            // an iso8601 duration like P1W or an RRULE like FREQ=WEEKLY;BYDAY=MO,FR
+            if recur_string.contains('=') {
+                rule = Some(
+                    recur_string
+                        .parse()
+                        .unwrap_or_else(|e| panic!("Recur rule should be a valid RRULE: {e}")),
+                );
+            } else {
+                recur = Some(
+                    recur_string
+                        .parse()
+                        .expect("Recur string should be a valid iso8601 string"),
+                );
+            }
+        } else if word.starts_with("mode:") {
+            let mode_string = word.replace("mode:", "");
+            mode = Some(mode_string.parse().unwrap_or_else(|e| panic!("{e}")));
+        } else if word.starts_with("ready:") {
+            let ready_string = word.replace("ready:", "");
+            ready = Some(
+                ready_string
                     .parse()
-                    .expect("Recur string should be a valid iso8601 string"),
+                    .expect("Ready string should be a valid iso8601 string e.g. P3D"),
+            );
+        } else if word.starts_with("until:") {
+            let until_string = word.replace("until:", "");
+            // a date alone includes the whole day
+            let until_chrono = NaiveDateTime::parse_from_str(&until_string, "%Y-%m-%dT%H:%M")
+                .or_else(|_| {
+                    NaiveDate::parse_from_str(&until_string, "%Y-%m-%d")
+                        .map(|x| x.and_hms_opt(23, 59, 59).unwrap())
+                })
+                .expect("Until should have the format %Y-%m-%d or %Y-%m-%dT%H:%M e.g. 2023-12-31")
+                .and_utc();
+            until = Some(until_chrono);
+        } else if word.starts_with("count:") {
+            let count_string = word.replace("count:", "");
+            count = Some(
+                count_string
+                    .parse()
+                    .ok()
+                    .filter(|x| *x > 0)
+                    .expect("Count should be the number of occurrences e.g. count:10"),
             );
         } else if word.starts_with("tag:") || word.starts_with('+') {
             let tag = word.replace("tag:", "").replace('+', "");
@@ -71,14 +137,25 @@ fn get_context(input: String) -> Result<AddContext> {
         }
     }
 
-    if recur.is_some() && due.is_none() {
+    if (recur.is_some() || rule.is_some()) && due.is_none() {
         panic!("The due date has to exist in a task recurs i.e. add due:XXXX to the command");
     }
+    if ready.is_some() && due.is_none() {
+        panic!("A ready lead time counts back from the due date i.e. add due:XXXX to the command");
+    }
+    if (mode.is_some() || until.is_some() || count.is_some()) && recur.is_none() && rule.is_none() {
+        panic!("mode:, until: and count: need a recurrence i.e. add recur:XXXX to the command");
+    }
 
     Ok(AddContext {
         body: body.trim().to_string(),
         due,
         recur,
+        rule,
+        mode,
+        ready,
+        until,
+        count,
         tags: (!tags.is_empty()).then_some(tags),
         priority,
     })
@@ -99,6 +176,11 @@ mod tests {
                 body: "task 1".to_string(),
                 due: None,
                 recur: None,
+                rule: None,
+                mode: None,
+                ready: None,
+                until: None,
+                count: None,
                 tags: None,
                 priority: None,
             }
@@ -114,6 +196,11 @@ mod tests {
                 body: "task 1".to_string(),
                 due: Some(Utc.with_ymd_and_hms(2023, 10, 11, 12, 0, 0).unwrap()),
                 recur: None,
+                rule: None,
+                mode: None,
+                ready: None,
+                until: None,
+                count: None,
                 tags: None,
                 priority: None,
             }
@@ -129,6 +216,11 @@ mod tests {
                 body: "task 1".to_string(),
                 due: Some(Utc.with_ymd_and_hms(2023, 10, 11, 12, 0, 0).unwrap()),
                 recur: Some("P1W".parse().unwrap()),
+                rule: None,
+                mode: None,
+                ready: None,
+                until: None,
+                count: None,
                 tags: Some(vec!["meeting".to_string(), "work".to_string()]),
                 priority: Some(10.0),
             }
@@ -141,12 +233,111 @@ mod tests {
                 body: "task 1".to_string(),
                 due: Some(Utc.with_ymd_and_hms(2023, 10, 11, 12, 0, 0).unwrap()),
                 recur: Some("P1W".parse().unwrap()),
+                rule: None,
+                mode: None,
+                ready: None,
+                until: None,
+                count: None,
                 tags: Some(vec!["meeting".to_string(), "work".to_string()]),
                 priority: Some(3.0),
             }
         );
     }
 
+    #[test]
+    fn test_get_context_with_rrule() {
+        let input =
+            "standup due:2023-10-11T09:30 recur:RRULE:FREQ=WEEKLY;BYDAY=MO,WE,FR".to_string();
+        assert_eq!(
+            get_context(input).unwrap(),
+            AddContext {
+                body: "standup".to_string(),
+                due: Some(Utc.with_ymd_and_hms(2023, 10, 11, 9, 30, 0).unwrap()),
+                recur: None,
+                rule: Some("FREQ=WEEKLY;BYDAY=MO,WE,FR".parse().unwrap()),
+                mode: None,
+                ready: None,
+                until: None,
+                count: None,
+                tags: None,
+                priority: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_get_context_with_recurrence_mode() {
+        let input = "water plants due:2023-10-11T09:00 recur:P3D mode:from-completion".to_string();
+        let context = get_context(input).unwrap();
+        assert_eq!(context.body, "water plants");
+        assert_eq!(context.recur, Some("P3D".parse().unwrap()));
+        assert_eq!(context.mode, Some(RecurrenceMode::FromCompletion));
+    }
+
+    #[test]
+    fn test_add_task_sets_ready_from_the_lead() {
+        let storage = crate::storage::memory_storage::MemoryStorage::new();
+        add_task(
+            &storage,
+            "pay rent due:2024-03-01T09:00 ready:P3D recur:P1M",
+        )
+        .unwrap();
+        let task = storage.search_using_ulid("").unwrap().pop().unwrap();
+        assert_eq!(task.ready_lead, Some("P3D".parse().unwrap()));
+        assert_eq!(
+            task.ready_utc,
+            Some(Utc.with_ymd_and_hms(2024, 2, 27, 9, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_get_context_with_recurrence_bounds() {
+        let input = "standup due:2023-10-11T09:30 recur:P1D until:2023-10-31 count:10".to_string();
+        let context = get_context(input).unwrap();
+        assert_eq!(context.body, "standup");
+        assert_eq!(
+            context.until,
+            Some(Utc.with_ymd_and_hms(2023, 10, 31, 23, 59, 59).unwrap())
+        );
+        assert_eq!(context.count, Some(10));
+
+        let input = "standup due:2023-10-11T09:30 recur:P1D until:2023-10-31T09:30".to_string();
+        assert_eq!(
+            get_context(input).unwrap().until,
+            Some(Utc.with_ymd_and_hms(2023, 10, 31, 9, 30, 0).unwrap())
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_context_fails_with_zero_count() {
+        let _ = get_context("task 1 due:2023-10-20T10:00 recur:P1D count:0".to_string());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_context_fails_with_count_but_no_recur() {
+        let _ = get_context("task 1 due:2023-10-20T10:00 count:3".to_string());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_context_fails_with_ready_but_no_due() {
+        let _ = get_context("task 1 ready:P1D".to_string());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_context_fails_with_mode_but_no_recur() {
+        let _ = get_context("task 1 due:2023-10-20T10:00 mode:catch-up".to_string());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_context_fails_with_invalid_rrule() {
+        let _ = get_context("task 1 due:2023-10-20T10:00 recur:FREQ=HOURLY".to_string());
+    }
+
     #[test]
     #[should_panic]
     fn test_get_context_fails_with_invalid_due_date() {
@@ -0,0 +1,150 @@
+use std::io::Write;
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde_json::Value;
+use termcolor::{Color, ColorSpec, StandardStream, WriteColor};
+
+use crate::storage::storage::{ulid_matches, SyncConflict, TaskStorage};
+
+use super::Task;
+
+/// Which version of the conflicting fields to keep
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Take {
+    Local,
+    Remote,
+    /// Write the fields by hand in $EDITOR
+    Edit,
+}
+
+pub fn list_conflicts(storage: &dyn TaskStorage) -> Result<()> {
+    let conflicts = storage.conflicts()?;
+    if conflicts.is_empty() {
+        println!("No sync conflicts");
+        return Ok(());
+    }
+    let mut stdout = StandardStream::stdout(termcolor::ColorChoice::Always);
+    for conflict in conflicts {
+        write!(&mut stdout, "{} ", conflict.ulid)?;
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+        write!(&mut stdout, "{}", conflict.local.body)?;
+        stdout.reset()?;
+        writeln!(
+            &mut stdout,
+            " (with {} at {})",
+            conflict.peer,
+            conflict.detected_utc.format("%Y-%m-%d %H:%M:%S")
+        )?;
+        for (field, local, remote) in field_values(&conflict)? {
+            writeln!(&mut stdout, "  {field}")?;
+            writeln!(&mut stdout, "    local:  {local}")?;
+            writeln!(&mut stdout, "    remote: {remote}")?;
+        }
+    }
+    Ok(())
+}
+
+pub fn resolve_conflict(storage: &dyn TaskStorage, ulid_suffix: &str, take: Take) -> Result<()> {
+    let conflicts: Vec<SyncConflict> = storage
+        .conflicts()?
+        .into_iter()
+        .filter(|x| ulid_matches(&x.ulid, ulid_suffix))
+        .collect();
+    let conflict = match conflicts.as_slice() {
+        [conflict] => conflict,
+        [] => bail!("No sync conflict found with ulid: {ulid_suffix}"),
+        _ => bail!(
+            "Expected 1 sync conflict but found {}",
+            conflicts
+                .iter()
+                .fold("".to_string(), |acc, x| format!("{}\n{}", acc, x.ulid))
+        ),
+    };
+
+    let mut tasks = storage.search_using_ulid(&conflict.ulid)?;
+    let Some(task) = tasks.pop() else {
+        // deleted since, nothing left to resolve
+        storage.remove_conflict(&conflict.ulid)?;
+        println!("Task {} was deleted, dropped its conflict", conflict.ulid);
+        return Ok(());
+    };
+    let task = match take {
+        Take::Local => with_fields(&task, &conflict.local, &conflict.fields)?,
+        Take::Remote => with_fields(&task, &conflict.remote, &conflict.fields)?,
+        Take::Edit => {
+            let mut task = task;
+            let mut comment = "# Conflicting values from the last sync\n".to_string();
+            for (field, local, remote) in field_values(conflict)? {
+                comment += &format!("# {field}\n#   local:  {local}\n#   remote: {remote}\n");
+            }
+            task.edit_with_editor_and_comment(&comment)?;
+            task
+        }
+    };
+    // a normal update so the resolution wins the next sync
+    storage.update(&task)?;
+    storage.remove_conflict(&conflict.ulid)?;
+    println!("Resolved: {} {}", task.ulid, task.body);
+    Ok(())
+}
+
+/// `task` with `fields` copied from `source`
+fn with_fields(task: &Task, source: &Task, fields: &[String]) -> Result<Task> {
+    let mut task = serde_json::to_value(task)?;
+    let source = serde_json::to_value(source)?;
+    for field in fields {
+        task[field] = source[field].clone();
+    }
+    Ok(serde_json::from_value(task)?)
+}
+
+fn field_values(conflict: &SyncConflict) -> Result<Vec<(String, Value, Value)>> {
+    let local = serde_json::to_value(&conflict.local)?;
+    let remote = serde_json::to_value(&conflict.remote)?;
+    Ok(conflict
+        .fields
+        .iter()
+        .map(|x| (x.clone(), local[x].clone(), remote[x].clone()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::storage::sqlite_storage::SQLiteStorage;
+
+    use super::*;
+
+    #[test]
+    fn take_copies_only_the_conflicting_fields() {
+        let storage = SQLiteStorage::new(":memory:");
+        let task = Task {
+            body: "merged".to_string(),
+            metadata: Some("from the remote".to_string()),
+            ..Default::default()
+        };
+        storage.save(&task).unwrap();
+        storage
+            .save_conflict(&SyncConflict {
+                ulid: task.ulid.clone(),
+                peer: "phone".to_string(),
+                fields: vec!["body".to_string()],
+                local: Task {
+                    body: "local".to_string(),
+                    metadata: None,
+                    ..task.clone()
+                },
+                remote: task.clone(),
+                detected_utc: Utc::now(),
+            })
+            .unwrap();
+
+        resolve_conflict(&storage, &task.ulid, Take::Local).unwrap();
+        let resolved = &storage.search_using_ulid(&task.ulid).unwrap()[0];
+        assert_eq!(resolved.body, "local");
+        assert_eq!(resolved.metadata, Some("from the remote".to_string()));
+        assert!(storage.conflicts().unwrap().is_empty());
+    }
+}
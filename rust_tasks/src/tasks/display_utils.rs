@@ -3,6 +3,8 @@ use chrono::Local;
 use std::io::{ErrorKind, Write};
 use termcolor::{Color, ColorSpec, StandardStream, WriteColor};
 
+use crate::storage::text_search;
+
 use super::Task;
 
 pub fn show_tasks_table(tasks: &[Task]) -> Result<()> {
@@ -27,6 +29,51 @@ pub fn show_tasks_table(tasks: &[Task]) -> Result<()> {
     Ok(())
 }
 
+/// Tasks in the order found with the words of `query` highlighted in the body and tags
+pub fn show_search_results(tasks: &[Task], query: &str) -> Result<()> {
+    let mut stdout = StandardStream::stdout(termcolor::ColorChoice::Always);
+    let ulid_length = ulid_output_length(tasks.len());
+    for task in tasks {
+        let due_utc = task.due_utc.map_or("".to_string(), |x| {
+            x.format("%Y-%m-%d %H:%M:%S").to_string()
+        });
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+        write!(stdout, "{:7}", &task.ulid[task.ulid.len() - ulid_length..])?;
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+        write!(stdout, "{:23}", due_utc)?;
+        stdout.reset()?;
+        write_highlighted(&mut stdout, &task.body, query, None)?;
+        if let Some(tags) = &task.tags {
+            write!(stdout, " ")?;
+            write_highlighted(&mut stdout, &tags.join(","), query, Some(Color::Blue))?;
+        }
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+fn write_highlighted(
+    stdout: &mut StandardStream,
+    text: &str,
+    query: &str,
+    color: Option<Color>,
+) -> Result<()> {
+    for (piece, matched) in text_search::highlight(text, query) {
+        match matched {
+            true => stdout.set_color(
+                ColorSpec::new()
+                    .set_fg(Some(Color::Red))
+                    .set_bold(true)
+                    .set_underline(true),
+            )?,
+            false => stdout.set_color(ColorSpec::new().set_fg(color))?,
+        }
+        write!(stdout, "{piece}")?;
+    }
+    stdout.reset()?;
+    Ok(())
+}
+
 fn show_task_table(
     task: &Task,
     stdout: &mut StandardStream,
@@ -60,6 +107,10 @@ fn show_task_table(
 
     stdout.set_color(ColorSpec::new().set_fg(Some(body_color)))?;
     write!(stdout, "{}", task.body)?;
+    if let Some(progress) = task.recurrence_progress() {
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Magenta)))?;
+        write!(stdout, " ({progress})")?;
+    }
     stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
     let tags_str = task.tags.clone().map_or("".to_string(), |x| x.join(","));
     writeln!(stdout, " {}", tags_str)?;
@@ -1,28 +1,22 @@
 use std::io::Write;
 
-use crate::storage::storage::TaskStorage;
+use crate::storage::storage::{TaskEvent, TaskEventKind, TaskStorage};
+
+use super::undo_utils::journaled;
 
 use anyhow::Result;
 use termcolor::{Color, ColorSpec, StandardStream, WriteColor};
 
-pub fn edit_task(storage: &dyn TaskStorage, ulid_suffix: &str) -> Result<()> {
-    let mut tasks = storage.search_using_ulid(ulid_suffix)?;
-    if tasks.len() > 1 {
-        panic!(
-            "Expected 1 task but found {}\n{}",
-            tasks.len(),
-            tasks.iter().fold("".to_string(), |acc, x| format!(
-                "{}\n{}: {}",
-                acc, x.ulid, x.body
-            ))
-        );
-    } else if tasks.is_empty() {
-        panic!("No tasks found with ulid: {}", ulid_suffix);
-    }
-
-    let task = &mut tasks[0];
+pub fn edit_task(storage: &dyn TaskStorage, ulid: &str) -> Result<()> {
+    let task = &mut storage.find_by_ulid(ulid)?;
+    let before = task.clone();
     task.edit_with_editor()?;
-    storage.update(task)?;
+    let events = vec![TaskEvent::new(
+        TaskEventKind::Update,
+        Some(before),
+        Some(task.clone()),
+    )];
+    journaled(storage, "edit", events, &mut || storage.update(task))?;
 
     let mut stdout = StandardStream::stdout(termcolor::ColorChoice::Always);
     write!(&mut stdout, "Done: {} ", task.ulid)?;
@@ -32,24 +26,40 @@ pub fn edit_task(storage: &dyn TaskStorage, ulid_suffix: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn delete_task(storage: &dyn TaskStorage, ulid_suffix: &str) -> Result<()> {
-    let mut tasks = storage.search_using_ulid(ulid_suffix)?;
-    if tasks.len() > 1 {
-        panic!(
-            "Expected 1 task but found {}\n{}",
-            tasks.len(),
-            tasks.iter().fold("".to_string(), |acc, x| format!(
-                "{}\n{}: {}",
-                acc, x.ulid, x.body
-            ))
-        );
-    }
-    if tasks.is_empty() {
-        panic!("No tasks found with ulid: {}", ulid_suffix);
-    }
-
-    let task = &mut tasks[0];
-    storage.delete(task)?;
+pub fn delete_task(storage: &dyn TaskStorage, ulid: &str) -> Result<()> {
+    let task = &storage.find_by_ulid(ulid)?;
+    let events = vec![TaskEvent::new(
+        TaskEventKind::Delete,
+        Some(task.clone()),
+        None,
+    )];
+    journaled(storage, "delete", events, &mut || storage.delete(task))?;
     println!("Deleted: '{}' {}", task.body, task.ulid);
     Ok(())
 }
+
+/// Adds `amount` to the task's priority adjustment, negative amounts lower it
+pub fn bump_task(storage: &dyn TaskStorage, ulid: &str, amount: f64) -> Result<()> {
+    let task = &mut storage.find_by_ulid(ulid)?;
+    let before = task.clone();
+    task.priority_adjustment = Some(task.priority_adjustment.unwrap_or_default() + amount);
+    let events = vec![TaskEvent::new(
+        TaskEventKind::Update,
+        Some(before),
+        Some(task.clone()),
+    )];
+    journaled(storage, "bump", events, &mut || storage.update(task))?;
+
+    let mut stdout = StandardStream::stdout(termcolor::ColorChoice::Always);
+    write!(&mut stdout, "Bumped: {} ", task.ulid)?;
+    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+    write!(&mut stdout, "{}", task.body)?;
+    stdout.reset()?;
+    writeln!(
+        &mut stdout,
+        " priority {:.1} (adjusted by {})",
+        task.priority(),
+        task.priority_adjustment.unwrap_or_default()
+    )?;
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+use termcolor::{Color, ColorSpec, StandardStream, WriteColor};
+
+use crate::storage::storage::{resolve_ulid, ulid_matches, TaskEventKind, TaskStorage};
+
+use super::Task;
+
+pub fn show_history(storage: &dyn TaskStorage, ulid: &str) -> Result<()> {
+    let ulid = find_ulid(storage, ulid)?;
+    let events = storage.history(&ulid)?;
+    if events.is_empty() {
+        println!("No history for {ulid}");
+        return Ok(());
+    }
+    let mut stdout = StandardStream::stdout(termcolor::ColorChoice::Always);
+    for event in events {
+        let color = match event.kind {
+            TaskEventKind::Save => Color::Green,
+            TaskEventKind::Do => Color::Cyan,
+            TaskEventKind::Delete => Color::Red,
+            TaskEventKind::Update | TaskEventKind::Import => Color::Yellow,
+        };
+        write!(
+            &mut stdout,
+            "{} ",
+            event.recorded_utc.format("%Y-%m-%d %H:%M:%S")
+        )?;
+        stdout.set_color(ColorSpec::new().set_fg(Some(color)))?;
+        write!(&mut stdout, "{:8}", event.kind.as_str())?;
+        stdout.reset()?;
+        match (&event.before, &event.after) {
+            (Some(before), Some(after)) => {
+                writeln!(&mut stdout, "{}", after.body)?;
+                for (field, before, after) in changed_fields(before, after)? {
+                    writeln!(&mut stdout, "  {field}: {before} -> {after}")?;
+                }
+            }
+            (Some(task), None) | (None, Some(task)) => writeln!(&mut stdout, "{}", task.body)?,
+            (None, None) => writeln!(&mut stdout)?,
+        }
+    }
+    Ok(())
+}
+
+/// The ulid starting or ending with `fragment` among open, trashed and deleted tasks
+fn find_ulid(storage: &dyn TaskStorage, fragment: &str) -> Result<String> {
+    let mut tasks = storage.search_using_ulid(fragment)?;
+    tasks.extend(
+        storage
+            .trash()?
+            .into_iter()
+            .map(|x| x.task)
+            .filter(|x| ulid_matches(&x.ulid, fragment)),
+    );
+    // tasks purged from the trash only leave their tombstone
+    for tombstone in storage.tombstones()? {
+        if ulid_matches(&tombstone.ulid, fragment)
+            && !tasks.iter().any(|x| x.ulid == tombstone.ulid)
+        {
+            tasks.push(Task {
+                ulid: tombstone.ulid,
+                ..Default::default()
+            });
+        }
+    }
+    Ok(resolve_ulid(tasks, fragment)?.ulid)
+}
+
+/// Fields that differ between the two versions, except the modification time. Fields that are
+/// None on one side are missing from its JSON and show as null.
+fn changed_fields(before: &Task, after: &Task) -> Result<Vec<(String, Value, Value)>> {
+    let (Value::Object(before), Value::Object(after)) =
+        (serde_json::to_value(before)?, serde_json::to_value(after)?)
+    else {
+        bail!("Task didn't serialize to an object");
+    };
+    let fields: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    Ok(fields
+        .into_iter()
+        .filter(|field| *field != "modified_utc")
+        .map(|field| {
+            let value = |task: &Map<String, Value>| task.get(field).cloned().unwrap_or_default();
+            (field.clone(), value(&before), value(&after))
+        })
+        .filter(|(_, before, after)| before != after)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::memory_storage::MemoryStorage;
+    use crate::storage::storage::LookupError;
+
+    use super::*;
+
+    #[test]
+    fn deleted_tasks_are_found_like_open_ones() {
+        let storage = MemoryStorage::new();
+        let open = Task::default();
+        let deleted = Task::default();
+        storage.save(&open).unwrap();
+        storage.save(&deleted).unwrap();
+        storage.delete(&deleted).unwrap();
+        assert_eq!(find_ulid(&storage, &deleted.ulid).unwrap(), deleted.ulid);
+        assert_eq!(find_ulid(&storage, &open.ulid).unwrap(), open.ulid);
+        let error = find_ulid(&storage, "").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(LookupError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn only_changed_fields_are_shown() {
+        let before = Task {
+            body: "draft".to_string(),
+            ..Default::default()
+        };
+        let after = Task {
+            body: "final".to_string(),
+            modified_utc: Some(chrono::Utc::now()),
+            ..before.clone()
+        };
+        let changed = changed_fields(&before, &after).unwrap();
+        assert_eq!(
+            changed,
+            vec![(
+                "body".to_string(),
+                Value::from("draft"),
+                Value::from("final")
+            )]
+        );
+    }
+
+    #[test]
+    fn fields_set_for_the_first_time_are_shown() {
+        let before = Task::default();
+        let after = Task {
+            recurrence_rule: "FREQ=WEEKLY;BYDAY=MO".parse().ok(),
+            ..before.clone()
+        };
+        let changed = changed_fields(&before, &after).unwrap();
+        assert_eq!(
+            changed,
+            vec![(
+                "recurrence_rule".to_string(),
+                Value::Null,
+                Value::from("FREQ=WEEKLY;BYDAY=MO")
+            )]
+        );
+        let changed = changed_fields(&after, &before).unwrap();
+        assert_eq!(changed[0].2, Value::Null);
+    }
+}
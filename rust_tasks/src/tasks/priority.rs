@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+use ulid::Ulid;
+
+use super::Task;
+
+/// Added for each tag, tagged tasks tend to be the ones that are planned
+const TAG_WEIGHT: f64 = 0.5;
+/// Added per month since the task was created, up to MAX_AGE_SCORE
+const AGE_WEIGHT: f64 = 1.0;
+const MAX_AGE_SCORE: f64 = 5.0;
+
+impl Task {
+    /// How urgent the task is, higher first. Like TaskLite it's a score from how close the task
+    /// is to being due, how old it is and its tags, plus `priority_adjustment`.
+    pub fn priority(&self) -> f64 {
+        self.priority_at(Utc::now())
+    }
+
+    pub fn priority_at(&self, now: DateTime<Utc>) -> f64 {
+        due_score(self.due_utc, now)
+            + self.age_score(now)
+            + TAG_WEIGHT * self.tags.as_ref().map_or(0, |x| x.len()) as f64
+            + self.priority_adjustment.unwrap_or_default()
+    }
+
+    /// Created time from the ulid, tasks with made up ulids count as new
+    fn age_score(&self, now: DateTime<Utc>) -> f64 {
+        let Ok(ulid) = Ulid::from_string(&self.ulid) else {
+            return 0.0;
+        };
+        let created: DateTime<Utc> = ulid.datetime().into();
+        let months = (now - created).num_days().max(0) as f64 / 30.0;
+        (AGE_WEIGHT * months).min(MAX_AGE_SCORE)
+    }
+}
+
+fn due_score(due_utc: Option<DateTime<Utc>>, now: DateTime<Utc>) -> f64 {
+    let Some(due_utc) = due_utc else {
+        return 0.0;
+    };
+    match (due_utc - now).num_hours() {
+        x if x < 0 => 12.0,
+        x if x < 24 => 9.0,
+        x if x < 24 * 7 => 6.0,
+        x if x < 24 * 30 => 3.0,
+        _ => 0.0,
+    }
+}
+
+/// The first `count` tasks by priority, ties going to the earliest due
+pub fn by_priority(tasks: Vec<Task>, count: usize) -> Vec<Task> {
+    let now = Utc::now();
+    let mut tasks: Vec<(f64, Task)> = tasks.into_iter().map(|x| (x.priority_at(now), x)).collect();
+    tasks.sort_by(|(a, x), (b, y)| {
+        b.total_cmp(a)
+            .then_with(|| x.due_utc.cmp(&y.due_utc))
+            .then_with(|| x.ulid.cmp(&y.ulid))
+    });
+    tasks.into_iter().take(count).map(|(_, x)| x).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn due_age_tags_and_adjustment_add_up() {
+        let now: DateTime<Utc> = "2024-03-01T09:00:00Z".parse().unwrap();
+        let created = now - Duration::days(60);
+        let task = Task {
+            ulid: Ulid::from_datetime(created.into()).to_string(),
+            due_utc: Some(now + Duration::hours(3)),
+            tags: Some(vec!["home".to_string(), "chores".to_string()]),
+            priority_adjustment: Some(-1.5),
+            ..Default::default()
+        };
+        assert_eq!(task.priority_at(now), 9.0 + 2.0 + 1.0 - 1.5);
+
+        let overdue = Task {
+            due_utc: Some(now - Duration::hours(1)),
+            ..Default::default()
+        };
+        let undated = Task {
+            ulid: "7nx0".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(overdue.priority_at(now), 12.0);
+        assert_eq!(undated.priority_at(now), 0.0);
+    }
+}
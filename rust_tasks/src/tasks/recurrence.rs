@@ -0,0 +1,618 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Datelike, Months, NaiveDate, TimeDelta, Utc, Weekday};
+use iso8601_duration::Duration;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::Task;
+
+/// How far ahead to look for the next occurrence, in periods of the rule's interval
+const MAX_PERIODS: u32 = 8;
+/// Largest INTERVAL accepted, keeps the search horizon of `next_after` bounded
+const MAX_INTERVAL: u32 = 1000;
+/// Most calendar steps a catch-up takes, over 800 years of monthly steps
+const MAX_CATCH_UP_STEPS: u32 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// What the next occurrence of a recurring task counts from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecurrenceMode {
+    /// The previous due date, so occurrences stay on schedule however late they are done
+    #[default]
+    FromDue,
+    /// When the task was done, so doing it late pushes the next one back
+    FromCompletion,
+    /// The first occurrence after now, skipping the ones that were missed
+    CatchUp,
+}
+
+impl FromStr for RecurrenceMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "from-due" => Ok(RecurrenceMode::FromDue),
+            "from-completion" => Ok(RecurrenceMode::FromCompletion),
+            "catch-up" => Ok(RecurrenceMode::CatchUp),
+            x => bail!("Expected from-due, from-completion or catch-up but found {x}"),
+        }
+    }
+}
+
+impl fmt::Display for RecurrenceMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            RecurrenceMode::FromDue => "from-due",
+            RecurrenceMode::FromCompletion => "from-completion",
+            RecurrenceMode::CatchUp => "catch-up",
+        };
+        write!(f, "{mode}")
+    }
+}
+
+impl Task {
+    pub fn recurs(&self) -> bool {
+        self.recurrence_rule.is_some() || self.recurrence_duration.is_some()
+    }
+
+    /// The due date of the next occurrence, from the rule if there is one and counted as
+    /// `recurrence_mode` says. `now` stands in for when an open task was done.
+    pub(crate) fn next_due_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let due = self.due_utc?;
+        if self
+            .recurrence_count
+            .is_some_and(|x| self.occurrence() >= x)
+        {
+            return None;
+        }
+        let from = match self.recurrence_mode.unwrap_or_default() {
+            RecurrenceMode::FromDue => due,
+            // the day it was done at the usual time, doing it in the evening doesn't move it
+            RecurrenceMode::FromCompletion => self
+                .closed_utc
+                .unwrap_or(now)
+                .date_naive()
+                .and_time(due.time())
+                .and_utc(),
+            RecurrenceMode::CatchUp => now,
+        };
+        let step = |x: DateTime<Utc>| {
+            self.recurrence_duration
+                .as_ref()
+                .map(|duration| x + duration.to_chrono_at_datetime(x))
+        };
+        let next = match (
+            &self.recurrence_rule,
+            self.recurrence_mode.unwrap_or_default(),
+        ) {
+            (Some(rule), _) => rule.next_after(due, from.max(due)),
+            (None, RecurrenceMode::CatchUp) => {
+                let duration = self.recurrence_duration.as_ref()?;
+                let mut next = step(due)?;
+                if duration.year == 0.0 && duration.month == 0.0 && next <= now {
+                    // a fixed length jumps over the elapsed periods, a zero one never catches up
+                    let period = (next - due).num_milliseconds();
+                    let periods = (now - next).num_milliseconds().checked_div(period)?;
+                    next += TimeDelta::try_milliseconds(period.checked_mul(periods)?)?;
+                }
+                for _ in 0..MAX_CATCH_UP_STEPS {
+                    if next > now {
+                        break;
+                    }
+                    next = step(next).filter(|x| *x > next)?;
+                }
+                Some(next).filter(|x| *x > now)
+            }
+            (None, _) => step(from),
+        };
+        next.filter(|x| self.recurrence_until.is_none_or(|until| *x <= until))
+    }
+
+    /// Which occurrence of its recurrence the task is, counting from 1
+    pub fn occurrence(&self) -> u32 {
+        self.recurrence_occurrence.unwrap_or(1)
+    }
+
+    /// How far through a bounded recurrence the task is e.g. `3 of 10`
+    pub fn recurrence_progress(&self) -> Option<String> {
+        if !self.recurs() {
+            return None;
+        }
+        match (self.recurrence_count, self.recurrence_until) {
+            (Some(count), _) => Some(format!("{} of {count}", self.occurrence())),
+            (None, Some(until)) => Some(format!("until {}", until.format("%Y-%m-%d"))),
+            (None, None) => None,
+        }
+    }
+
+    /// When the task is ready if it's due at `due`, keeping how long before due it was ready
+    pub(crate) fn ready_for(&self, due: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match (&self.ready_lead, self.ready_utc, self.due_utc) {
+            (Some(lead), _, _) => Some(before(due, lead)),
+            (None, Some(ready), Some(old_due)) => Some(due - (old_due - ready)),
+            (None, ready, _) => ready,
+        }
+    }
+}
+
+/// `lead` before `due`, whole years and months count on the calendar so P1M before the 1st of
+/// April is the 1st of March
+fn before(due: DateTime<Utc>, lead: &Duration) -> DateTime<Utc> {
+    let months = Months::new((lead.year * 12.0 + lead.month) as u32);
+    let seconds = lead.day as f64 * 86400.0
+        + lead.hour as f64 * 3600.0
+        + lead.minute as f64 * 60.0
+        + lead.second as f64;
+    due.checked_sub_months(months).unwrap_or(due)
+        - TimeDelta::milliseconds((seconds * 1000.0) as i64)
+}
+
+/// A BYDAY entry, optionally the nth weekday of the month counting from the end when negative
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByDay {
+    pub nth: Option<i32>,
+    pub weekday: Weekday,
+}
+
+/// The part of an RFC 5545 RRULE that tasks use: FREQ, INTERVAL, BYDAY, BYMONTHDAY and BYMONTH.
+/// Occurrences keep the time of day of the due date they start from, in UTC, and BYDAY ordinals
+/// count within the month.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RRule {
+    pub frequency: Frequency,
+    pub interval: u32,
+    pub by_day: Vec<ByDay>,
+    pub by_month_day: Vec<i32>,
+    pub by_month: Vec<u32>,
+}
+
+impl RRule {
+    /// The first occurrence after `after` of the rule starting at `start`, which is an occurrence
+    /// itself. None if there isn't one within a few periods.
+    pub fn next_after(&self, start: DateTime<Utc>, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let time = start.time();
+        let mut day = start.date_naive().max(after.date_naive());
+        let horizon = match self.frequency {
+            Frequency::Daily => 1,
+            Frequency::Weekly => 7,
+            Frequency::Monthly => 31,
+            Frequency::Yearly => 366,
+        } * self.interval
+            * MAX_PERIODS;
+        for _ in 0..horizon {
+            let candidate = day.and_time(time).and_utc();
+            if candidate > after && self.matches(start.date_naive(), day) {
+                return Some(candidate);
+            }
+            day = day.succ_opt()?;
+        }
+        None
+    }
+
+    fn matches(&self, start: NaiveDate, day: NaiveDate) -> bool {
+        if !self.by_month.is_empty() && !self.by_month.contains(&day.month()) {
+            return false;
+        }
+        let interval = self.interval as i64;
+        match self.frequency {
+            Frequency::Daily => {
+                (day - start).num_days() % interval == 0
+                    && self.month_day_matches(day)
+                    && self.weekday_matches(day)
+            }
+            Frequency::Weekly => {
+                let monday =
+                    |x: NaiveDate| x - chrono::Days::new(x.weekday().num_days_from_monday().into());
+                let weeks = (monday(day) - monday(start)).num_days() / 7;
+                let weekday = match self.by_day.is_empty() {
+                    true => day.weekday() == start.weekday(),
+                    false => self.weekday_matches(day),
+                };
+                weeks % interval == 0 && weekday && self.month_day_matches(day)
+            }
+            Frequency::Monthly => {
+                let months = (day.year() - start.year()) as i64 * 12 + day.month() as i64
+                    - start.month() as i64;
+                months % interval == 0 && self.day_in_month_matches(start, day)
+            }
+            Frequency::Yearly => {
+                let month = !self.by_month.is_empty() || day.month() == start.month();
+                (day.year() - start.year()) as i64 % interval == 0
+                    && month
+                    && self.day_in_month_matches(start, day)
+            }
+        }
+    }
+
+    /// BYMONTHDAY and BYDAY narrow each other, without either the start's day of the month repeats
+    fn day_in_month_matches(&self, start: NaiveDate, day: NaiveDate) -> bool {
+        if self.by_month_day.is_empty() && self.by_day.is_empty() {
+            return day.day() == start.day();
+        }
+        self.month_day_matches(day) && self.weekday_matches(day)
+    }
+
+    fn month_day_matches(&self, day: NaiveDate) -> bool {
+        let last = days_in_month(day) as i32;
+        self.by_month_day.is_empty()
+            || self.by_month_day.iter().any(|x| match *x > 0 {
+                true => *x == day.day() as i32,
+                false => last + 1 + x == day.day() as i32,
+            })
+    }
+
+    fn weekday_matches(&self, day: NaiveDate) -> bool {
+        let last = days_in_month(day) as i32;
+        let from_start = (day.day() as i32 - 1) / 7 + 1;
+        let from_end = -((last - day.day() as i32) / 7 + 1);
+        self.by_day.is_empty()
+            || self.by_day.iter().any(|x| {
+                x.weekday == day.weekday()
+                    && x.nth.is_none_or(|nth| nth == from_start || nth == from_end)
+            })
+    }
+}
+
+fn days_in_month(day: NaiveDate) -> u32 {
+    let (year, month) = match day.month() {
+        12 => (day.year() + 1, 1),
+        x => (day.year(), x + 1),
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|x| x.pred_opt())
+        .map_or(31, |x| x.day())
+}
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("MO", Weekday::Mon),
+    ("TU", Weekday::Tue),
+    ("WE", Weekday::Wed),
+    ("TH", Weekday::Thu),
+    ("FR", Weekday::Fri),
+    ("SA", Weekday::Sat),
+    ("SU", Weekday::Sun),
+];
+
+impl FromStr for ByDay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.len().saturating_sub(2);
+        let (nth, day) = s.split_at_checked(split).unwrap_or((s, ""));
+        let Some((_, weekday)) = WEEKDAYS.iter().find(|(x, _)| *x == day) else {
+            bail!("Expected a weekday like MO or -1FR in BYDAY but found {s}");
+        };
+        let nth = match nth {
+            "" => None,
+            x => Some(
+                x.parse()
+                    .ok()
+                    .filter(|x: &i32| *x != 0 && x.abs() <= 5)
+                    .with_context(|| {
+                        format!("Expected 1 to 5 or -1 to -5 before the weekday in {s}")
+                    })?,
+            ),
+        };
+        Ok(ByDay {
+            nth,
+            weekday: *weekday,
+        })
+    }
+}
+
+impl fmt::Display for ByDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (day, _) = WEEKDAYS
+            .iter()
+            .find(|(_, x)| *x == self.weekday)
+            .expect("every weekday has a code");
+        match self.nth {
+            Some(nth) => write!(f, "{nth}{day}"),
+            None => write!(f, "{day}"),
+        }
+    }
+}
+
+fn parse_list<T: FromStr>(
+    key: &str,
+    value: &str,
+    valid: impl Fn(&T) -> bool,
+) -> anyhow::Result<Vec<T>> {
+    value
+        .split(',')
+        .map(|x| {
+            x.parse()
+                .ok()
+                .filter(&valid)
+                .with_context(|| format!("Invalid {key} value {x}"))
+        })
+        .collect()
+}
+
+impl FromStr for RRule {
+    type Err = anyhow::Error;
+
+    /// Parses rules like `FREQ=WEEKLY;BYDAY=MO,WE,FR`, with or without the `RRULE:` prefix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.trim().to_uppercase();
+        let rule = upper.strip_prefix("RRULE:").unwrap_or(&upper);
+        let mut frequency = None;
+        let mut parsed = RRule {
+            frequency: Frequency::Daily,
+            interval: 1,
+            by_day: vec![],
+            by_month_day: vec![],
+            by_month: vec![],
+        };
+        for part in rule.split(';').filter(|x| !x.is_empty()) {
+            let Some((key, value)) = part.split_once('=') else {
+                bail!("Expected KEY=VALUE in the RRULE but found {part}");
+            };
+            match key {
+                "FREQ" => {
+                    frequency = Some(match value {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        x => {
+                            bail!("Unsupported FREQ {x}, expected DAILY, WEEKLY, MONTHLY or YEARLY")
+                        }
+                    })
+                }
+                "INTERVAL" => {
+                    parsed.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|x| (1..=MAX_INTERVAL).contains(x))
+                        .with_context(|| {
+                            format!("Invalid INTERVAL value {value}, expected 1 to {MAX_INTERVAL}")
+                        })?
+                }
+                "BYDAY" => {
+                    parsed.by_day = value.split(',').map(str::parse).collect::<Result<_, _>>()?
+                }
+                "BYMONTHDAY" => {
+                    parsed.by_month_day =
+                        parse_list(key, value, |x: &i32| *x != 0 && x.abs() <= 31)?
+                }
+                "BYMONTH" => parsed.by_month = parse_list(key, value, |x| (1..=12).contains(x))?,
+                x => bail!("Unsupported RRULE part {x}"),
+            }
+        }
+        let Some(frequency) = frequency else {
+            bail!("The RRULE {s} has no FREQ");
+        };
+        if matches!(frequency, Frequency::Daily | Frequency::Weekly)
+            && parsed.by_day.iter().any(|x| x.nth.is_some())
+        {
+            bail!("BYDAY ordinals like 1MO need FREQ=MONTHLY or FREQ=YEARLY");
+        }
+        Ok(RRule {
+            frequency,
+            ..parsed
+        })
+    }
+}
+
+impl fmt::Display for RRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frequency = match self.frequency {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+            Frequency::Yearly => "YEARLY",
+        };
+        write!(f, "FREQ={frequency}")?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        let join = |x: Vec<String>| x.join(",");
+        if !self.by_month.is_empty() {
+            write!(
+                f,
+                ";BYMONTH={}",
+                join(self.by_month.iter().map(|x| x.to_string()).collect())
+            )?;
+        }
+        if !self.by_month_day.is_empty() {
+            let days = self.by_month_day.iter().map(|x| x.to_string()).collect();
+            write!(f, ";BYMONTHDAY={}", join(days))?;
+        }
+        if !self.by_day.is_empty() {
+            write!(
+                f,
+                ";BYDAY={}",
+                join(self.by_day.iter().map(|x| x.to_string()).collect())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for RRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for RRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let rule = String::deserialize(deserializer)?;
+        rule.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(datetime: &str) -> DateTime<Utc> {
+        datetime.parse().unwrap()
+    }
+
+    fn occurrences(rule: &str, start: &str, count: usize) -> Vec<String> {
+        let rule: RRule = rule.parse().unwrap();
+        let start = utc(start);
+        let mut due = start;
+        (0..count)
+            .map(|_| {
+                due = rule.next_after(start, due).unwrap();
+                due.format("%a %Y-%m-%d %H:%M").to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn weekdays_skip_the_weekend() {
+        assert_eq!(
+            occurrences(
+                "FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR",
+                "2024-03-07T09:00:00Z",
+                3
+            ),
+            vec![
+                "Fri 2024-03-08 09:00",
+                "Mon 2024-03-11 09:00",
+                "Tue 2024-03-12 09:00"
+            ]
+        );
+    }
+
+    #[test]
+    fn nth_weekday_of_the_month() {
+        assert_eq!(
+            occurrences("RRULE:FREQ=MONTHLY;BYDAY=1MO", "2024-01-01T08:30:00Z", 2),
+            vec!["Mon 2024-02-05 08:30", "Mon 2024-03-04 08:30"]
+        );
+        assert_eq!(
+            occurrences("FREQ=MONTHLY;BYDAY=-1FR", "2024-01-26T08:30:00Z", 1),
+            vec!["Fri 2024-02-23 08:30"]
+        );
+    }
+
+    #[test]
+    fn intervals_and_missing_days_are_skipped() {
+        assert_eq!(
+            occurrences(
+                "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE",
+                "2024-03-04T09:00:00Z",
+                3
+            ),
+            vec![
+                "Wed 2024-03-06 09:00",
+                "Mon 2024-03-18 09:00",
+                "Wed 2024-03-20 09:00"
+            ]
+        );
+        assert_eq!(
+            occurrences("FREQ=MONTHLY", "2024-01-31T09:00:00Z", 2),
+            vec!["Sun 2024-03-31 09:00", "Fri 2024-05-31 09:00"]
+        );
+        assert_eq!(
+            occurrences("FREQ=MONTHLY;BYMONTHDAY=-1", "2024-01-31T09:00:00Z", 2),
+            vec!["Thu 2024-02-29 09:00", "Sun 2024-03-31 09:00"]
+        );
+    }
+
+    #[test]
+    fn modes_count_from_due_completion_or_now() {
+        let mut task = Task {
+            due_utc: Some(utc("2024-03-01T09:00:00Z")),
+            closed_utc: Some(utc("2024-03-04T21:30:00Z")),
+            recurrence_duration: "P3D".parse().ok(),
+            ..Default::default()
+        };
+        let now = utc("2024-03-10T12:00:00Z");
+        let next_due = |task: &Task| task.next_due_at(now).unwrap().to_rfc3339();
+        assert_eq!(next_due(&task), "2024-03-04T09:00:00+00:00");
+        task.recurrence_mode = Some(RecurrenceMode::FromCompletion);
+        assert_eq!(next_due(&task), "2024-03-07T09:00:00+00:00");
+        task.recurrence_mode = Some(RecurrenceMode::CatchUp);
+        assert_eq!(next_due(&task), "2024-03-13T09:00:00+00:00");
+
+        task.recurrence_rule = "FREQ=WEEKLY;BYDAY=MO,FR".parse().ok();
+        assert_eq!(next_due(&task), "2024-03-11T09:00:00+00:00");
+        task.recurrence_mode = Some(RecurrenceMode::FromCompletion);
+        assert_eq!(next_due(&task), "2024-03-08T09:00:00+00:00");
+        task.recurrence_mode = None;
+        assert_eq!(next_due(&task), "2024-03-04T09:00:00+00:00");
+    }
+
+    #[test]
+    fn catch_up_jumps_over_short_periods() {
+        let task = Task {
+            due_utc: Some(utc("2004-03-01T09:00:00Z")),
+            recurrence_duration: "PT1S".parse().ok(),
+            recurrence_mode: Some(RecurrenceMode::CatchUp),
+            ..Default::default()
+        };
+        let now = utc("2024-03-10T12:00:00.5Z");
+        assert_eq!(task.next_due_at(now), Some(utc("2024-03-10T12:00:01Z")));
+        let now = utc("2024-03-10T12:00:00Z");
+        assert_eq!(task.next_due_at(now), Some(utc("2024-03-10T12:00:01Z")));
+
+        let zero = Task {
+            recurrence_duration: "PT0S".parse().ok(),
+            ..task.clone()
+        };
+        assert_eq!(zero.next_due_at(now), None);
+        let monthly = Task {
+            recurrence_duration: "P1M".parse().ok(),
+            ..task
+        };
+        assert_eq!(monthly.next_due_at(now), Some(utc("2024-04-01T09:00:00Z")));
+    }
+
+    #[test]
+    fn count_and_until_end_the_recurrence() {
+        let mut task = Task {
+            due_utc: Some(utc("2024-03-01T09:00:00Z")),
+            recurrence_duration: "P1W".parse().ok(),
+            recurrence_count: Some(3),
+            recurrence_occurrence: Some(2),
+            ..Default::default()
+        };
+        let now = utc("2024-03-01T10:00:00Z");
+        assert_eq!(task.recurrence_progress(), Some("2 of 3".to_string()));
+        assert!(task.next_due_at(now).is_some());
+        task.recurrence_occurrence = Some(3);
+        assert_eq!(task.next_due_at(now), None);
+
+        task.recurrence_count = None;
+        task.recurrence_until = Some(utc("2024-03-08T09:00:00Z"));
+        assert_eq!(
+            task.recurrence_progress(),
+            Some("until 2024-03-08".to_string())
+        );
+        assert_eq!(task.next_due_at(now), Some(utc("2024-03-08T09:00:00Z")));
+        task.due_utc = Some(utc("2024-03-08T09:00:00Z"));
+        assert_eq!(task.next_due_at(now), None);
+    }
+
+    #[test]
+    fn rules_round_trip_and_reject_unsupported_parts() {
+        let rule: RRule = "freq=monthly;interval=2;byday=1mo,-1fr".parse().unwrap();
+        assert_eq!(rule.to_string(), "FREQ=MONTHLY;INTERVAL=2;BYDAY=1MO,-1FR");
+        assert!("FREQ=HOURLY".parse::<RRule>().is_err());
+        assert!("FREQ=DAILY;BYSETPOS=1".parse::<RRule>().is_err());
+        assert!("FREQ=WEEKLY;BYDAY=1MO".parse::<RRule>().is_err());
+        assert!("BYDAY=MO".parse::<RRule>().is_err());
+        assert!("FREQ=YEARLY;INTERVAL=4294967295".parse::<RRule>().is_err());
+        assert!("FREQ=YEARLY;INTERVAL=1000".parse::<RRule>().is_ok());
+        assert_eq!(
+            "catch-up".parse::<RecurrenceMode>().unwrap().to_string(),
+            "catch-up"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use chrono::{SubsecRound, Utc};
+use termcolor::{Color, ColorSpec, StandardStream, WriteColor};
+
+use crate::storage::storage::{resolve_ulid, ulid_matches, TaskEvent, TaskEventKind, TaskStorage};
+
+use super::{undo_utils::journaled, Task};
+
+pub fn list_trash(storage: &dyn TaskStorage) -> Result<()> {
+    let trash = storage.trash()?;
+    if trash.is_empty() {
+        println!("The trash is empty");
+        return Ok(());
+    }
+    let mut stdout = StandardStream::stdout(termcolor::ColorChoice::Always);
+    for trashed in trash {
+        let task = trashed.task;
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+        write!(&mut stdout, "{} ", task.ulid)?;
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
+        write!(
+            &mut stdout,
+            "{} ",
+            trashed.deleted_utc.format("%Y-%m-%d %H:%M:%S")
+        )?;
+        stdout.reset()?;
+        write!(&mut stdout, "{}", task.body)?;
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+        writeln!(&mut stdout, " {}", task.tags.unwrap_or_default().join(","))?;
+        stdout.reset()?;
+    }
+    Ok(())
+}
+
+/// Brings a task back from the trash as it was when deleted
+pub fn restore_task(storage: &dyn TaskStorage, ulid: &str) -> Result<()> {
+    let task = find_trashed(storage, ulid)?;
+    let restored = Task {
+        // newer than the tombstone so sync restores it elsewhere too
+        modified_utc: Some(Utc::now().trunc_subsecs(0)),
+        ..task
+    };
+    let event = TaskEvent::new(TaskEventKind::Import, None, Some(restored.clone()));
+    journaled(storage, "trash restore", vec![event], &mut || {
+        storage.import(&restored)
+    })?;
+    println!("Restored: {} {}", restored.ulid, restored.body);
+    Ok(())
+}
+
+/// Empties the trash of tasks deleted longer ago than `older_than`, an iso8601 duration e.g. P30D
+pub fn purge_trash(storage: &dyn TaskStorage, older_than: &str) -> Result<()> {
+    let duration = older_than
+        .parse::<iso8601_duration::Duration>()
+        .map_err(|x| anyhow::anyhow!("{:?}", x))
+        .with_context(|| format!("{older_than} isn't an iso8601 duration e.g. P30D"))?;
+    let now = Utc::now();
+    let before = now - duration.to_chrono_at_datetime(now);
+    let purged = storage.purge_trash(&before)?;
+    println!("Purged {purged} tasks from the trash");
+    Ok(())
+}
+
+fn find_trashed(storage: &dyn TaskStorage, ulid: &str) -> Result<Task> {
+    let trash = storage
+        .trash()?
+        .into_iter()
+        .map(|x| x.task)
+        .filter(|x| ulid_matches(&x.ulid, ulid))
+        .collect();
+    Ok(resolve_ulid(trash, ulid)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::memory_storage::MemoryStorage;
+    use crate::tasks::add_utils::add_task;
+    use crate::tasks::undo_utils::undo;
+
+    use super::*;
+
+    #[test]
+    fn restore_brings_back_the_deleted_task() {
+        let storage = MemoryStorage::new();
+        add_task(&storage, "water the plants +home").unwrap();
+        let task = storage.search_using_ulid("").unwrap().pop().unwrap();
+        crate::tasks::edit_utils::delete_task(&storage, &task.ulid).unwrap();
+
+        restore_task(&storage, &task.ulid[20..].to_lowercase()).unwrap();
+        let restored = storage
+            .search_using_ulid(&task.ulid)
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!((&restored.body, &restored.tags), (&task.body, &task.tags));
+        assert!(storage.trash().unwrap().is_empty());
+        assert!(restore_task(&storage, &task.ulid).is_err());
+
+        undo(&storage).unwrap();
+        assert_eq!(storage.trash().unwrap()[0].task.ulid, task.ulid);
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use chrono::{SubsecRound, Utc};
+
+use crate::storage::storage::{Operation, TaskEvent, TaskEventKind, TaskStorage};
+
+use super::Task;
+
+/// Runs `write` and journals `events` in the same transaction so `rust_tasks undo` can revert
+/// them. The `after` of each event is replaced by the task as stored.
+pub(crate) fn journaled(
+    storage: &dyn TaskStorage,
+    command: &str,
+    events: Vec<TaskEvent>,
+    write: &mut dyn FnMut() -> Result<()>,
+) -> Result<()> {
+    storage.transaction(&mut || {
+        write()?;
+        let mut events = events.clone();
+        for event in events.iter_mut().filter(|x| x.after.is_some()) {
+            event.after = find(storage, &event.ulid)?;
+        }
+        storage.push_operation(&Operation::new(command, events))
+    })
+}
+
+/// Reverts the most recent journaled command, refusing if its tasks changed since. Every task is
+/// checked before anything is written, so storages that can't roll back keep the journal and
+/// their tasks as they were.
+pub fn undo(storage: &dyn TaskStorage) -> Result<()> {
+    let mut undone = None;
+    storage.transaction(&mut || {
+        let Some(operation) = storage.pop_operation()? else {
+            bail!("Nothing to undo");
+        };
+        if let Err(e) = check_unchanged(storage, &operation) {
+            storage.push_operation(&operation)?;
+            return Err(e);
+        }
+        for event in operation.events.iter().rev() {
+            revert(storage, &operation.command, event)?;
+        }
+        undone = Some(operation);
+        Ok(())
+    })?;
+    if let Some(operation) = undone {
+        println!("Undid: {}", operation.command);
+        for event in &operation.events {
+            if let Some(task) = event.before.as_ref().or(event.after.as_ref()) {
+                println!("  {} {}", task.ulid, task.body);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fails unless each task is still as the operation's last event on it left it
+fn check_unchanged(storage: &dyn TaskStorage, operation: &Operation) -> Result<()> {
+    let mut checked = HashSet::new();
+    for event in operation.events.iter().rev() {
+        if checked.insert(&event.ulid) {
+            let current = find(storage, &event.ulid)?;
+            ensure_unchanged(current.as_ref(), &operation.command, event)?;
+        }
+    }
+    Ok(())
+}
+
+fn ensure_unchanged(current: Option<&Task>, command: &str, event: &TaskEvent) -> Result<()> {
+    let unchanged = match (current, &event.after) {
+        (Some(current), Some(after)) => current.same_as(after),
+        (None, None) => true,
+        _ => false,
+    };
+    if !unchanged {
+        bail!(
+            "Task {} changed after `{command}`, edit it by hand instead",
+            event.ulid
+        );
+    }
+    Ok(())
+}
+
+fn revert(storage: &dyn TaskStorage, command: &str, event: &TaskEvent) -> Result<()> {
+    let current = find(storage, &event.ulid)?;
+    ensure_unchanged(current.as_ref(), command, event)?;
+    match (event.kind, &event.before, current) {
+        (TaskEventKind::Do, _, Some(mut current)) => current.undo_task(storage),
+        (_, None, Some(current)) => storage.delete(&current),
+        (_, Some(before), Some(_)) => storage.update(before),
+        (_, Some(before), None) => storage.import(&Task {
+            // newer than the tombstone so sync restores it elsewhere too
+            modified_utc: Some(Utc::now().trunc_subsecs(0)),
+            ..before.clone()
+        }),
+        (_, None, None) => Ok(()),
+    }
+}
+
+fn find(storage: &dyn TaskStorage, ulid: &str) -> Result<Option<Task>> {
+    Ok(storage
+        .search_using_ulid(ulid)?
+        .into_iter()
+        .find(|x| x.ulid == ulid))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::memory_storage::MemoryStorage;
+    use crate::tasks::add_utils::add_task;
+
+    use super::*;
+
+    #[test]
+    fn undo_reverts_add_do_and_delete() {
+        let storage = MemoryStorage::new();
+        add_task(&storage, "water the plants recur:P1D due:2024-03-01T09:00").unwrap();
+        let task = storage.search_using_ulid("").unwrap().pop().unwrap();
+        crate::tasks::do_task(&storage, &task.ulid).unwrap();
+        crate::tasks::edit_utils::delete_task(&storage, &task.ulid).unwrap();
+        assert_eq!(storage.search_using_ulid("").unwrap().len(), 1);
+
+        undo(&storage).unwrap();
+        let restored = storage
+            .search_using_ulid(&task.ulid)
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert!(restored.closed_utc.is_some());
+        assert!(storage.tombstones().unwrap().is_empty());
+
+        undo(&storage).unwrap();
+        let tasks = storage.search_using_ulid("").unwrap();
+        assert_eq!(tasks.len(), 1, "the next recurrence is removed");
+        assert_eq!(tasks[0].ulid, task.ulid);
+        assert!(tasks[0].closed_utc.is_none());
+
+        undo(&storage).unwrap();
+        assert!(storage.search_using_ulid("").unwrap().is_empty());
+        assert!(undo(&storage).is_err());
+    }
+
+    #[test]
+    fn undo_refuses_tasks_changed_since() {
+        let storage = MemoryStorage::new();
+        add_task(&storage, "water the plants").unwrap();
+        let mut task = storage.search_using_ulid("").unwrap().pop().unwrap();
+        task.body = "edited elsewhere".to_string();
+        storage.update(&task).unwrap();
+
+        assert!(undo(&storage).is_err());
+        assert!(
+            storage.pop_operation().unwrap().is_some(),
+            "still journaled"
+        );
+    }
+}
@@ -28,7 +28,8 @@ impl Config {
             anyhow::bail!("{} doesnt start with file://", self.db_uri)
         }
         let file_path = self.db_uri.strip_prefix("file://").unwrap();
-        Ok(SQLiteStorage::new(file_path))
+        // Fails when the database has a newer schema than we know about
+        SQLiteStorage::open(file_path)
     }
 }
 
@@ -11,12 +11,21 @@ use axum::{
     extract::{Path, Query, State},
     http::StatusCode,
     response::{IntoResponse, Response},
-    routing::{get, patch},
+    routing::{delete, get, patch, post},
     Json, Router,
 };
+use chrono::{DateTime, Utc};
+use rust_tasks::{
+    storage::filter::TaskFilter,
+    storage::storage::{
+        HealthCheck, HealthStatus, Operation, SyncConflict, SyncState, TaskBatch, TaskStorage,
+        Tombstone,
+    },
+    storage::text_search,
+    tasks::summary::SummaryConfig,
+};
 use rust_tasks::{storage::sqlite_storage, tasks::Task};
-use rust_tasks::{storage::storage::TaskStorage, tasks::summary::SummaryConfig};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json::{json, Value};
 use tokio::signal;
 use tower_http::{timeout::TimeoutLayer, trace::TraceLayer};
@@ -25,24 +34,6 @@ struct AppState {
     sql_storage: sqlite_storage::SQLiteStorage,
 }
 
-// #[derive(Debug, Serialize, Deserialize)]
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum TaskStates {
-    Done,
-    Open,
-}
-
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-struct PatchTask {
-    state: TaskStates,
-}
-
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-struct GenericBody {
-    body: String,
-}
-
 // Error handline
 // Copied from https://github.com//tokio-rs/axum/blob/e3bb7083c886247f4e6931e149ef6067e6b82e1b/examples/anyhow-error-response/src/main.rs#L35
 
@@ -76,12 +67,30 @@ fn app(shared_state: Arc<Mutex<AppState>>) -> Router {
         .route("/", get(root))
         .route("/health", get(get_health))
         .route("/tasks/", get(get_tasks).post(save_task))
-        .route("/tasks/:ulid", patch(patch_task).delete(delete_task))
+        .route(
+            "/tasks/:ulid",
+            patch(patch_task).put(import_task).delete(delete_task),
+        )
+        .route("/tasks/:ulid/history", get(get_history))
         .route("/tasks/search", get(search_tasks))
+        .route("/tasks/changes", get(get_changes))
+        .route("/tasks/batch", post(apply_batch))
         .route("/tasks/next/:count", get(get_next_tasks))
-        .route("/tasks/unsafe_query/", get(get_unsafe_query_tasks))
+        .route("/tasks/query/", get(get_query_tasks))
         .route("/tasks/summarize_day/", get(get_day_summary))
-        .route("/tasks/deleted_ulids/:n_days", get(get_deleted_ulids))
+        .route(
+            "/tombstones/",
+            get(get_tombstones)
+                .post(apply_tombstone)
+                .delete(purge_tombstones),
+        )
+        .route("/trash/", get(get_trash).delete(purge_trash))
+        .route("/sync_state/", get(get_sync_state).put(put_sync_state))
+        .route("/sync_bases/", get(get_sync_bases).put(put_sync_bases))
+        .route("/sync_conflicts/", get(get_conflicts).post(post_conflict))
+        .route("/sync_conflicts/:ulid", delete(delete_conflict))
+        .route("/operations/", post(post_operation))
+        .route("/operations/last", delete(pop_operation))
         .layer((
             TraceLayer::new_for_http(),
             // Graceful shutdown will wait for outstanding requests to complete. Add a timeout so
@@ -144,11 +153,17 @@ async fn root() -> String {
     "TODO: intent to add some web html end point here".to_string()
 }
 
-async fn get_health() -> Result<Json<serde_json::Value>, AppError> {
-    let mut result = HashMap::new();
-    result.insert("running", "ok");
-    result.insert("TODO", "add more statuses");
-    Ok(Json(json!(result)))
+async fn get_health(State(state): State<Arc<Mutex<AppState>>>) -> Result<Response, AppError> {
+    let task_storage = state.lock().unwrap();
+    let mut report = task_storage.sql_storage.health()?;
+    report
+        .checks
+        .push(HealthCheck::ok("version", env!("CARGO_PKG_VERSION")));
+    let status = match report.status {
+        HealthStatus::Ok => StatusCode::OK,
+        HealthStatus::Degraded => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok((status, Json(json!(report))).into_response())
 }
 
 async fn get_tasks(
@@ -189,57 +204,78 @@ async fn patch_task(
     Ok(Json(json!("Successfully updated task")))
 }
 
+async fn import_task(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(ulid): Path<String>,
+    Json(task): Json<Task>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    if ulid != task.ulid {
+        let err = anyhow!("The uilds don't match");
+        return Err(AppError(err));
+    }
+    task_storage.sql_storage.import(&task)?;
+    Ok(Json(json!("Successfully imported task")))
+}
+
 async fn delete_task(
     State(state): State<Arc<Mutex<AppState>>>,
     Path(ulid): Path<String>,
 ) -> Result<Json<Value>, AppError> {
     let task_storage = state.lock().unwrap();
     let sql_storage = &task_storage.sql_storage;
-    let tasks = sql_storage.search_using_ulid(&ulid)?;
-
-    if tasks.len() != 1 {
-        let msg = format!("Expected to get one task but found {}", tasks.len());
-        let err = anyhow!(msg);
-        return Err(AppError(err));
-    }
-    sql_storage.delete(&tasks[0])?;
+    let task = sql_storage.find_by_ulid(&ulid)?;
+    sql_storage.delete(&task)?;
     Ok(Json(json!("Successfully deleted")))
 }
 
+async fn get_history(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(ulid): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    let events = task_storage.sql_storage.history(&ulid)?;
+    Ok(Json(json!(events)))
+}
+
 async fn search_tasks(
     State(state): State<Arc<Mutex<AppState>>>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<Value>, AppError> {
     let task_storage = state.lock().unwrap();
     let sql_storage = &task_storage.sql_storage;
-    match params.get("ulid") {
-        None => {
-            let err = anyhow!(format!("Expected ulid in params"));
-            Err(AppError(err))
-        }
-        Some(ulid) => {
+    match (params.get("ulid"), params.get("q")) {
+        (Some(ulid), _) => {
             let tasks = sql_storage.search_using_ulid(ulid)?;
             Ok(Json(json!(tasks)))
         }
+        (None, Some(query)) => {
+            let limit = match params.get("limit") {
+                Some(x) => x.parse()?,
+                None => text_search::DEFAULT_LIMIT,
+            };
+            let tasks = sql_storage.search_text(query, limit)?;
+            Ok(Json(json!(tasks)))
+        }
+        (None, None) => {
+            let err = anyhow!(format!("Expected ulid or q in params"));
+            Err(AppError(err))
+        }
     }
 }
 
-async fn get_unsafe_query_tasks(
+async fn get_query_tasks(
     State(state): State<Arc<Mutex<AppState>>>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<Value>, AppError> {
     let task_storage = state.lock().unwrap();
     let sql_storage = &task_storage.sql_storage;
-    match params.get("clause") {
-        None => {
-            let err = anyhow!(format!("Expected clause in params"));
-            Err(AppError(err))
-        }
-        Some(clause) => {
-            let tasks = sql_storage.unsafe_query(clause)?;
-            Ok(Json(json!(tasks)))
-        }
-    }
+    let filter: TaskFilter = match params.get("filter") {
+        None => TaskFilter::default(),
+        Some(filter) => serde_json::from_str(filter)?,
+    };
+    let tasks = sql_storage.query(&filter)?;
+    Ok(Json(json!(tasks)))
 }
 
 async fn get_day_summary(
@@ -256,19 +292,175 @@ async fn get_day_summary(
     Ok(Json(json!(day_summary)))
 }
 
-async fn get_deleted_ulids(
+async fn get_tombstones(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    let tombstones = task_storage.sql_storage.tombstones()?;
+    Ok(Json(json!(tombstones)))
+}
+
+async fn apply_tombstone(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(tombstone): Json<Tombstone>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    task_storage.sql_storage.apply_tombstone(&tombstone)?;
+    Ok(Json(json!("Successfully applied tombstone")))
+}
+
+#[derive(Deserialize)]
+struct ChangesParams {
+    since: u64,
+}
+
+async fn get_changes(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<ChangesParams>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    let changes = task_storage.sql_storage.changes_since(params.since)?;
+    Ok(Json(json!(changes)))
+}
+
+async fn apply_batch(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(batch): Json<TaskBatch>,
+) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    task_storage.sql_storage.apply_batch(&batch)?;
+    Ok(Json(json!("Successfully applied batch")))
+}
+
+#[derive(Deserialize)]
+struct PurgeTombstonesParams {
+    cursor: u64,
+}
+
+async fn purge_tombstones(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<PurgeTombstonesParams>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    let purged = task_storage.sql_storage.purge_tombstones(params.cursor)?;
+    Ok(Json(json!(purged)))
+}
+
+async fn get_trash(
     State(state): State<Arc<Mutex<AppState>>>,
-    Path(n_days): Path<usize>,
 ) -> Result<Json<serde_json::Value>, AppError> {
     let task_storage = state.lock().unwrap();
-    let ulids = task_storage.sql_storage.deleted_ulids(&n_days)?;
-    Ok(Json(json!(ulids)))
+    let trash = task_storage.sql_storage.trash()?;
+    Ok(Json(json!(trash)))
+}
+
+#[derive(Deserialize)]
+struct PurgeParams {
+    before: DateTime<Utc>,
+}
+
+async fn purge_trash(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<PurgeParams>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    let purged = task_storage.sql_storage.purge_trash(&params.before)?;
+    Ok(Json(json!(purged)))
+}
+
+fn peer_param(params: &HashMap<String, String>) -> Result<&String, AppError> {
+    params
+        .get("peer")
+        .ok_or_else(|| AppError(anyhow!("Expected peer in params")))
+}
+
+async fn get_sync_state(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    let sync_state = task_storage.sql_storage.sync_state(peer_param(&params)?)?;
+    Ok(Json(json!(sync_state)))
+}
+
+async fn put_sync_state(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(sync_state): Json<SyncState>,
+) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    task_storage
+        .sql_storage
+        .save_sync_state(peer_param(&params)?, &sync_state)?;
+    Ok(Json(json!("Successfully saved sync state")))
+}
+
+async fn get_sync_bases(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    let bases = task_storage.sql_storage.sync_bases(peer_param(&params)?)?;
+    Ok(Json(json!(bases)))
+}
+
+async fn put_sync_bases(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(tasks): Json<Vec<Task>>,
+) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    task_storage
+        .sql_storage
+        .save_sync_bases(peer_param(&params)?, &tasks)?;
+    Ok(Json(json!("Successfully saved sync bases")))
+}
+
+async fn get_conflicts(State(state): State<Arc<Mutex<AppState>>>) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    let conflicts = task_storage.sql_storage.conflicts()?;
+    Ok(Json(json!(conflicts)))
+}
+
+async fn post_conflict(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(conflict): Json<SyncConflict>,
+) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    task_storage.sql_storage.save_conflict(&conflict)?;
+    Ok(Json(json!("Successfully saved conflict")))
+}
+
+async fn delete_conflict(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(ulid): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    task_storage.sql_storage.remove_conflict(&ulid)?;
+    Ok(Json(json!("Successfully removed conflict")))
+}
+
+async fn post_operation(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Json(operation): Json<Operation>,
+) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    task_storage.sql_storage.push_operation(&operation)?;
+    Ok(Json(json!("Successfully saved operation")))
+}
+
+async fn pop_operation(State(state): State<Arc<Mutex<AppState>>>) -> Result<Json<Value>, AppError> {
+    let task_storage = state.lock().unwrap();
+    let operation = task_storage.sql_storage.pop_operation()?;
+    Ok(Json(json!(operation)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use axum::{body::Body, extract::Request, http, Router};
+    use rust_tasks::storage::{api_storage::APIStorage, conformance};
+    use rust_tasks::tasks::{add_utils, undo_utils};
     use sqlite_storage::SQLiteStorage;
 
     use http_body_util::BodyExt; // for `collect`
@@ -292,6 +484,51 @@ mod tests {
         app(shared_state)
     }
 
+    /// Serves a fresh in-memory storage on a free port from its own thread, returns the uri
+    fn spawn_server() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async {
+                listener.set_nonblocking(true).unwrap();
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let shared_state = Arc::new(Mutex::new(AppState {
+                    sql_storage: SQLiteStorage::new(":memory:"),
+                }));
+                axum::serve(listener, app(shared_state)).await.unwrap();
+            });
+        });
+        uri
+    }
+
+    #[test]
+    fn api_storage_conforms() {
+        conformance::run_all(&|| {
+            Box::new(APIStorage {
+                uri: spawn_server(),
+            })
+        });
+    }
+
+    #[test]
+    fn refused_undo_keeps_the_journal_without_transactions() {
+        let storage = APIStorage {
+            uri: spawn_server(),
+        };
+        add_utils::add_task(&storage, "water the plants").unwrap();
+        let mut task = storage.search_using_ulid("").unwrap().pop().unwrap();
+        task.body = "edited elsewhere".to_string();
+        storage.update(&task).unwrap();
+
+        assert!(undo_utils::undo(&storage).is_err());
+        assert_eq!(storage.search_using_ulid("").unwrap(), vec![task]);
+        assert!(
+            storage.pop_operation().unwrap().is_some(),
+            "still journaled"
+        );
+    }
+
     #[tokio::test]
     async fn test_get_next_tasks() {
         let app = test_app();
@@ -308,10 +545,32 @@ mod tests {
 
         let body = response.into_body().collect().await.unwrap().to_bytes();
         let body: Value = serde_json::from_slice(&body).unwrap();
-        let expected = json!([{"body":"deep dive int","closed_utc":null,"due_utc":"2023-08-06T18:46:41Z","metadata":null,"modified_utc":null,"priority_adjustment":null,"ready_utc":null,"recurrence_duration":null,"tags":null,"ulid":"7nx0","user":null}]);
+        // both are overdue, the tags rank 8vag first
+        let expected = json!([{"body":"follow up wit","closed_utc":null,"due_utc":"2023-08-23T09:01:34Z","metadata":null,"modified_utc":null,"priority_adjustment":null,"ready_utc":null,"recurrence_duration":null,"tags":["meeting","work"],"ulid":"8vag","user":null}]);
         assert_eq!(body, expected);
     }
 
+    #[tokio::test]
+    async fn test_search_text() {
+        let app = test_app();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/tasks/search?q=DIVE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let tasks = body.as_array().unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0]["body"], json!("deep dive int"));
+    }
+
     #[tokio::test]
     async fn test_deleted_tasks() {
         let app = test_app();
@@ -335,7 +594,7 @@ mod tests {
             .oneshot(
                 Request::builder()
                     .method(http::Method::GET)
-                    .uri("/tasks/deleted_ulids/1")
+                    .uri("/tombstones/")
                     .body(Body::empty())
                     .unwrap(),
             )
@@ -350,6 +609,239 @@ mod tests {
                 .to_bytes(),
         )
         .unwrap();
-        assert_eq!(resp_body, json!(["8vag"]));
+        let tombstones = resp_body.as_array().unwrap();
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(tombstones[0]["ulid"], json!("8vag"));
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let app = test_app();
+        app.clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::DELETE)
+                    .uri("/tasks/8vag")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/tasks/8vag/history")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let events = body.as_array().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["kind"], json!("delete"));
+        assert_eq!(events[0]["before"]["body"], json!("follow up wit"));
+        assert_eq!(events[0]["after"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_trash() {
+        let app = test_app();
+        app.clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::DELETE)
+                    .uri("/tasks/8vag")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/trash/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let trash = body.as_array().unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0]["task"]["body"], json!("follow up wit"));
+    }
+
+    #[tokio::test]
+    async fn test_changes() {
+        let app = test_app();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/tasks/changes?since=0")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["tasks"].as_array().unwrap().len(), 2);
+        assert!(body["cursor"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let app = test_app();
+        let post_batch = |batch: Value| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/tasks/batch")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(batch.to_string()))
+                .unwrap()
+        };
+        let task = Task {
+            body: "from a batch".to_string(),
+            ..Default::default()
+        };
+        let response = app
+            .clone()
+            .oneshot(post_batch(json!({
+                "import": [task],
+                "tombstones": [{"ulid": "7nx0", "deleted_utc": "2024-01-04T10:00:00Z"}]
+            })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // deleting a missing task fails the whole batch
+        let other_task = Task::default();
+        let response = app
+            .clone()
+            .oneshot(post_batch(json!({
+                "save": [other_task],
+                "delete": [Task { ulid: "missing".to_string(), ..Default::default() }]
+            })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/tasks/changes?since=0")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let ulids: Vec<&str> = body["tasks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["ulid"].as_str().unwrap())
+            .collect();
+        assert_eq!(ulids.len(), 2);
+        assert!(ulids.contains(&task.ulid.as_str()));
+        assert!(!ulids.contains(&"7nx0"));
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let app = test_app();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], json!("ok"));
+        let checks = body["checks"].as_array().unwrap();
+        assert!(checks.iter().any(|x| x["name"] == json!("version")));
+    }
+
+    #[tokio::test]
+    async fn test_sync_state() {
+        let app = test_app();
+        let sync_state = json!({
+            "last_attempt_utc": "2024-01-04T10:00:00Z",
+            "last_success_utc": "2024-01-04T10:00:00Z",
+            "last_error": null,
+            "local_cursor": 3,
+            "remote_cursor": 7
+        });
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::PUT)
+                    .uri("/sync_state/?peer=laptop")
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(sync_state.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/sync_state/?peer=laptop")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, sync_state);
+    }
+
+    #[tokio::test]
+    async fn test_query_tasks() {
+        let app = test_app();
+        let filter = json!({"tags": ["work"]}).to_string();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri(format!(
+                        "/tasks/query/?filter={}",
+                        filter
+                            .replace('{', "%7B")
+                            .replace('}', "%7D")
+                            .replace('"', "%22")
+                    ))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["ulid"], json!("8vag"));
     }
 }